@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Result;
+use std::net::IpAddr;
+use std::path::Path;
+
+use log::{error, info, warn};
+
+#[cfg(feature = "geoip")]
+use super::GeoIp;
+
+/**
+    Inclusive range of IP addresses, IPv4 addresses are stored as IPv4-mapped IPv6 addresses so both can be compared
+*/
+type IpRange = (u128, u128);
+
+/**
+    Decides which clients are allowed to connect based on their source IP
+*/
+pub struct AccessList {
+    /**
+        Only clients matching these ranges are allowed to connect, everyone is allowed when [None]
+    */
+    allow: Option<Vec<IpRange>>,
+    /**
+        Clients matching these ranges are never allowed to connect
+    */
+    deny: Vec<IpRange>,
+    /**
+        Countries of clients, used to reject clients of denied countries and to pick pools of clients by their country
+    */
+    #[cfg(feature = "geoip")]
+    geoip: Option<GeoIp>,
+}
+
+impl AccessList {
+    /**
+        Loads allowed and denied ranges from given files, both files are optional
+    */
+    pub fn new(allowfile: &str, denyfile: &str) -> Self {
+        let allow = if Path::exists(Path::new(allowfile)) {
+            let ranges = AccessList::load_ranges(allowfile);
+            if ranges.is_empty() {
+                warn!("[Parser] Allow file '{}' has no valid ranges, no clients will be allowed", allowfile);
+            }
+            Some(ranges)
+        } else {
+            None
+        };
+
+        let deny = if Path::exists(Path::new(denyfile)) {
+            AccessList::load_ranges(denyfile)
+        } else {
+            vec![]
+        };
+
+        AccessList {
+            allow,
+            deny,
+            #[cfg(feature = "geoip")]
+            geoip: None,
+        }
+    }
+
+    /**
+        Access list that allows every client
+    */
+    pub fn allow_all() -> Self {
+        AccessList {
+            allow: None,
+            deny: vec![],
+            #[cfg(feature = "geoip")]
+            geoip: None,
+        }
+    }
+
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /**
+        Returns pool of the client's country, [None] if there is no country database or the country has no pool
+    */
+    #[cfg(feature = "geoip")]
+    pub fn get_country_pool(&self, ip: IpAddr) -> Option<&str> {
+        self.geoip.as_ref().and_then(|g| g.get_pool(ip))
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        #[cfg(feature = "geoip")]
+        if self.geoip.as_ref().is_some_and(|g| g.is_denied(ip)) {
+            return false;
+        }
+
+        let ip = to_u128(ip);
+
+        if contains(&self.deny, ip) {
+            return false;
+        }
+
+        match &self.allow {
+            Some(allow) => contains(allow, ip),
+            None => true,
+        }
+    }
+
+    fn load_ranges(file: &str) -> Vec<IpRange> {
+        match AccessList::parse_ranges(file) {
+            Ok(r) => r,
+            Err(err) => {
+                error!("[Parser] Failed to parse access file '{}' -> {}", file, err);
+                vec![]
+            }
+        }
+    }
+
+    fn parse_ranges(file: &str) -> Result<Vec<IpRange>> {
+        let mut ranges: Vec<IpRange> = vec![];
+
+        let f = File::open(file)?;
+        let bufreader = BufReader::new(f);
+
+        for line in bufreader.lines() {
+            let l = line?;
+            let l = l.trim();
+            if l.is_empty() || l.starts_with('#') {
+                continue;
+            }
+
+            match parse_cidr(l) {
+                Some(r) => ranges.push(r),
+                None => warn!("[Parser] Invalid IP range: '{}'", l),
+            }
+        }
+
+        info!("[Parser] Registered {} valid IP ranges from '{}'", ranges.len(), file);
+        Ok(merge_ranges(ranges))
+    }
+}
+
+/**
+    Parses a CIDR range (e.g. "10.0.0.0/8" or "fd00::/8"), a plain IP address is a range of a single address
+*/
+pub fn parse_cidr(value: &str) -> Option<IpRange> {
+    let (ip, prefix) = match value.split_once('/') {
+        Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, Some(prefix.parse::<u32>().ok()?)),
+        None => (value.parse::<IpAddr>().ok()?, None),
+    };
+
+    // IPv4 prefixes are relative to the last 32 bits of the mapped address
+    let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max_prefix);
+    if prefix > max_prefix {
+        return None;
+    }
+
+    let host_bits = max_prefix - prefix;
+    let mask = if host_bits == 128 { u128::MAX } else { (1u128 << host_bits) - 1 };
+
+    let start = to_u128(ip) & !mask;
+    Some((start, start | mask))
+}
+
+/**
+    Sorts ranges and merges overlapping ones, so a match can be found with a binary search
+*/
+fn merge_ranges(mut ranges: Vec<IpRange>) -> Vec<IpRange> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<IpRange> = vec![];
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+fn contains(ranges: &[IpRange], ip: u128) -> bool {
+    // find the last range starting at or before the IP
+    let index = ranges.partition_point(|r| r.0 <= ip);
+    index > 0 && ranges[index - 1].1 >= ip
+}
+
+pub fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::balancer::testing::{assert_closed, assert_echoed, echo_backend, hosts, temp_file, TestBalancer};
+    use crate::balancer::{Algorithm, LoadBalancer};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_cidr("10.0.0.0/8"), Some((to_u128(ip("10.0.0.0")), to_u128(ip("10.255.255.255")))));
+        assert_eq!(parse_cidr("10.1.2.3"), Some((to_u128(ip("10.1.2.3")), to_u128(ip("10.1.2.3")))));
+        assert_eq!(
+            parse_cidr("fd00::/8"),
+            Some((to_u128(ip("fd00::")), to_u128(ip("fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"))))
+        );
+        assert_eq!(parse_cidr("::/0"), Some((0, u128::MAX)));
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("10.0.0/8"), None);
+        assert_eq!(parse_cidr("10.0.0.0/x"), None);
+    }
+
+    #[test]
+    fn denies_and_allows_ranges() {
+        let allow = temp_file("access-allow", "# office\n10.0.0.0/8\n\ninvalid\n192.168.1.1\n");
+        let deny = temp_file("access-deny", "10.1.0.0/16\n10.2.3.4/32\n");
+        let list = AccessList::new(&allow, &deny);
+
+        assert!(list.is_allowed(ip("10.0.0.1")));
+        assert!(list.is_allowed(ip("192.168.1.1")));
+        assert!(!list.is_allowed(ip("192.168.1.2")));
+        assert!(!list.is_allowed(ip("10.1.200.1")));
+        assert!(!list.is_allowed(ip("10.2.3.4")));
+        assert!(list.is_allowed(ip("10.2.3.5")));
+        // IPv4-mapped clients of dual-stack listeners match IPv4 ranges
+        assert!(!list.is_allowed(ip("::ffff:10.2.3.4")));
+
+        // without an allow file every client that is not denied is allowed
+        let list = AccessList::new("", &deny);
+        assert!(list.is_allowed(ip("192.168.1.2")));
+        assert!(!list.is_allowed(ip("10.2.3.4")));
+    }
+
+    #[test]
+    fn drops_denied_clients() {
+        let host = echo_backend();
+        let deny = temp_file("access-deny-clients", "127.0.0.2/32\n");
+        let balancer = LoadBalancer::builder().threads(2).access_list(AccessList::new("", &deny));
+        let balancer = TestBalancer::start(balancer.build(hosts(&[&host]), Algorithm::RoundRobin.factory(1)));
+
+        let denied = balancer.connect_from(ip("127.0.0.2"));
+        assert_closed(&denied);
+
+        let allowed = balancer.connect_from(ip("127.0.0.3"));
+        assert_echoed(&allowed, b"hello");
+        assert_eq!(balancer.balancer.stats().denied, 1);
+    }
+}
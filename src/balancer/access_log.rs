@@ -0,0 +1,99 @@
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::io::Result;
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use log::{error, info};
+
+/**
+    Writes access log records to a file (or stdout) on its own thread, so worker threads never wait for the writes.
+    Records are buffered and written in batches, the output is flushed whenever no more records are waiting
+*/
+#[derive(Clone)]
+pub struct AccessLogWriter {
+    sender: Sender<String>,
+}
+
+impl AccessLogWriter {
+    /**
+        Opens the file for appending (it's created if missing), "-" writes to stdout instead.
+        The writing thread stops once all writers are dropped
+    */
+    pub fn spawn(path: &str) -> Result<Self> {
+        let output: Box<dyn Write + Send> = if path == "-" {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+
+        let (sender, receiver) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            let mut output = BufWriter::new(output);
+
+            // failures are only reported once until writing succeeds again, so a full disk doesn't flood the log
+            let mut failing = false;
+            while let Ok(record) = receiver.recv() {
+                let mut result = writeln!(output, "{}", record);
+                while let Ok(record) = receiver.try_recv() {
+                    result = result.and_then(|_| writeln!(output, "{}", record));
+                }
+
+                match result.and_then(|_| output.flush()) {
+                    Ok(_) if failing => {
+                        info!("[Access] Writing access log works again");
+                        failing = false;
+                    }
+                    Ok(_) => {}
+                    Err(e) if !failing => {
+                        error!("[Access] Failed to write access log! {}", e);
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+        });
+
+        Ok(AccessLogWriter { sender })
+    }
+
+    pub fn write(&self, record: String) {
+        // writing thread only stops when all writers are dropped, so this can't fail
+        self.sender.send(record).unwrap_or(());
+    }
+}
+
+/**
+    Returns current time in RFC 3339 format (UTC with milliseconds, e.g. "2021-05-01T12:30:45.123Z")
+*/
+pub fn timestamp() -> String {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, time) = (secs / 86400, secs % 86400);
+
+    // civil date from days since 1970-01-01 (https://howardhinnant.github.io/date_algorithms.html)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        since_epoch.subsec_millis()
+    )
+}
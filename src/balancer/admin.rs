@@ -0,0 +1,317 @@
+use std::fmt::Write as FmtWrite;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, LevelFilter};
+
+use super::http;
+use super::Endpoint;
+use super::HostManager;
+use super::SharedHostManager;
+use super::SharedPools;
+use super::StatsHandle;
+
+// this is used as the timeout to receive the request from an API client
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// maximum size of the request we are willing to read
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/**
+    Response of a single API request: status line (without the HTTP version), content type and body
+*/
+struct Response(&'static str, &'static str, String);
+
+impl Response {
+    fn json(body: String) -> Self {
+        Response("200 OK", "application/json", body)
+    }
+
+    fn text(status: &'static str, message: String) -> Self {
+        Response(status, "text/plain", message + "\n")
+    }
+}
+
+/**
+    Minimal HTTP server for controlling the balancer at runtime. It only listens on localhost, as it can change the hosts:
+
+    - [GET /backends] lists hosts of every pool with their state and stats
+    - [POST /backends?host=<addr>&pool=<name>] adds host to the pool (default pool when [pool] is missing)
+    - [DELETE /backends?host=<addr>&pool=<name>] removes host from the pool, its connections are handled like on reload
+    - [POST /backends/drain?host=<addr>] stops picking host for new clients, [POST /backends/resume?host=<addr>] undoes it
+    - [GET /threads] lists connected and pending clients of every worker thread
+    - [GET /log-level] returns the log level, [POST /log-level?level=<level>] changes it
+*/
+pub struct AdminApi {
+    host_manager: SharedHostManager,
+    pools: SharedPools,
+    stats: StatsHandle,
+}
+
+impl AdminApi {
+    pub fn new(host_manager: SharedHostManager, pools: SharedPools, stats: StatsHandle) -> Self {
+        AdminApi { host_manager, pools, stats }
+    }
+
+    pub fn spawn(self, port: u16, stopped: Arc<RwLock<bool>>) -> Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+
+        info!("[API] Serving API on port {} (localhost only)", port);
+
+        thread::spawn(move || loop {
+            // keep checking if balancer has been stopped
+            if *stopped.read().unwrap() {
+                break;
+            }
+
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.handle_client(stream) {
+                        error!("[API] Failed to handle API request! {}", e);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+                Err(e) => error!("[API] Failed to accept socket! {}", e),
+            }
+        });
+
+        Ok(())
+    }
+
+    fn handle_client(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let (method, target) = http::read_request_line(&mut stream, MAX_REQUEST_SIZE)?;
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let params = Params::parse(query);
+
+        let Response(status, content_type, body) = match (method.as_str(), path) {
+            ("GET", "/backends") => Response::json(self.render_backends()),
+            ("POST", "/backends") => self.change_hosts(&params, true),
+            ("DELETE", "/backends") => self.change_hosts(&params, false),
+            ("POST", "/backends/drain") => self.set_draining(&params, true),
+            ("POST", "/backends/resume") => self.set_draining(&params, false),
+            ("GET", "/threads") => Response::json(self.render_threads()),
+            ("GET", "/log-level") => Response::text("200 OK", log::max_level().to_string().to_lowercase()),
+            ("POST", "/log-level") => AdminApi::set_log_level(&params),
+            (_, "/backends" | "/backends/drain" | "/backends/resume" | "/threads" | "/log-level") => {
+                Response::text("405 Method Not Allowed", format!("Method {} is not allowed for {}", method, path))
+            }
+            _ => Response::text("404 Not Found", format!("Unknown path {}", path)),
+        };
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes())
+    }
+
+    fn render_backends(&self) -> String {
+        let pools = self.pools.read().unwrap();
+        let stats = self.stats.stats.snapshot(&pools);
+
+        let mut backends = vec![];
+        for (name, algorithm) in pools.all_named() {
+            let algorithm = algorithm.read().unwrap();
+            for host in algorithm.get_hosts() {
+                let cooldown = algorithm.get_cooldown_remaining(&host).map(|r| r.as_millis()).unwrap_or(0);
+                let mut backend = format!(
+                    "{{\"pool\":{},\"host\":{},\"cooldown_ms\":{},\"draining\":{}",
+                    name.as_deref().map(json_string).unwrap_or_else(|| "null".to_string()),
+                    json_string(&host.to_string()),
+                    cooldown,
+                    pools.is_draining(&host)
+                );
+                if let Some(s) = stats.iter().find(|s| s.host == host) {
+                    write!(
+                        backend,
+                        ",\"active_connections\":{},\"connections\":{},\"successes\":{},\"errors\":{},\"bytes_to_host\":{},\"bytes_from_host\":{}",
+                        s.active_connections, s.connections, s.successes, s.errors, s.throughput.bytes_to_targets, s.throughput.bytes_from_targets
+                    )
+                    .unwrap();
+                }
+                backend.push('}');
+                backends.push(backend);
+            }
+        }
+
+        format!("[{}]\n", backends.join(","))
+    }
+
+    fn render_threads(&self) -> String {
+        let threads: Vec<String> = self
+            .stats
+            .snapshot()
+            .threads
+            .iter()
+            .enumerate()
+            .map(|(thread, t)| format!("{{\"thread\":{},\"clients\":{},\"pending\":{}}}", thread, t.clients, t.pending))
+            .collect();
+
+        format!("[{}]\n", threads.join(","))
+    }
+
+    /**
+        Adds (or removes) host of a pool. Hosts are swapped the same way as when the host file is reloaded,
+        so every balancing algorithm supports it and connections to removed hosts are drained (or closed)
+    */
+    fn change_hosts(&self, params: &Params, add: bool) -> Response {
+        let host = match params.get_host() {
+            Ok(h) => h,
+            Err(response) => return response,
+        };
+        let pool = params.get("pool").map(|p| p.to_lowercase());
+        let pool_name = pool.as_deref().unwrap_or("default");
+
+        // lock is held while updating the pools, so the DNS resolver can't overwrite the new hosts with old ones
+        let mut current = self.host_manager.write().unwrap();
+        let mut updated = current.clone();
+        let hosts = match updated.get_pool_mut(pool.as_deref()) {
+            Some(h) => h,
+            None => return Response::text("404 Not Found", format!("Unknown pool '{}'", pool_name)),
+        };
+
+        if add && !hosts.add_host(host.clone()) {
+            return Response::text("409 Conflict", format!("Host {} is already in pool '{}'", host, pool_name));
+        }
+        if !add {
+            // reloading the host file doesn't allow empty pools either
+            if hosts.hosts.len() == 1 && hosts.hosts[0] == host {
+                return Response::text("409 Conflict", format!("Host {} is the last host of pool '{}'", host, pool_name));
+            }
+            if !hosts.remove_host(&host) {
+                return Response::text("404 Not Found", format!("Host {} is not in pool '{}'", host, pool_name));
+            }
+        }
+
+        *current = updated.clone();
+        self.pools.write().unwrap().update_hosts(updated);
+
+        if add {
+            info!("[API] Added host {} to pool '{}'", host, pool_name);
+            Response::text("200 OK", format!("Added host {} to pool '{}'", host, pool_name))
+        } else {
+            info!("[API] Removed host {} from pool '{}'", host, pool_name);
+            Response::text("200 OK", format!("Removed host {} from pool '{}'", host, pool_name))
+        }
+    }
+
+    fn set_draining(&self, params: &Params, draining: bool) -> Response {
+        let host = match params.get_host() {
+            Ok(h) => h,
+            Err(response) => return response,
+        };
+
+        let mut pools = self.pools.write().unwrap();
+        // checked first, so the runtime draining state is left as it was
+        if !draining && pools.is_draining_by_host_file(&host) {
+            return Response::text("409 Conflict", format!("Host {} is marked as draining in the host file", host));
+        }
+        if !pools.set_draining(&host, draining) {
+            return Response::text("404 Not Found", format!("Host {} is not in any pool", host));
+        }
+
+        if draining {
+            info!("[API] Draining host {}, it's not picked for new clients anymore", host);
+            Response::text("200 OK", format!("Draining host {}", host))
+        } else {
+            info!("[API] Host {} stopped draining", host);
+            Response::text("200 OK", format!("Host {} stopped draining", host))
+        }
+    }
+
+    fn set_log_level(params: &Params) -> Response {
+        let level = match params.get("level").map(LevelFilter::from_str) {
+            Some(Ok(l)) => l,
+            _ => {
+                return Response::text(
+                    "400 Bad Request",
+                    "Expected parameter 'level' with one of: off, error, warn, info, debug, trace".to_string(),
+                )
+            }
+        };
+
+        // logged before changing it, so turning logging off is still visible
+        info!("[API] Changing log level to {}", level.to_string().to_lowercase());
+        log::set_max_level(level);
+        Response::text("200 OK", level.to_string().to_lowercase())
+    }
+}
+
+/**
+    Parameters from the query string of the request target (e.g. "host=127.0.0.1%3A5000&pool=api")
+*/
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn parse(query: &str) -> Self {
+        let params = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let (name, value) = p.split_once('=').unwrap_or((p, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
+        Params(params)
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /**
+        Returns host given by the [host] parameter, parsed the same way as hosts in the host file
+    */
+    fn get_host(&self) -> std::result::Result<Endpoint, Response> {
+        let host = match self.get("host") {
+            Some(h) if !h.is_empty() => h,
+            _ => return Err(Response::text("400 Bad Request", "Missing parameter 'host'".to_string())),
+        };
+
+        Endpoint::parse_unix(host)
+            .or_else(|| HostManager::resolve(host).map(Endpoint::Tcp))
+            .ok_or_else(|| Response::text("400 Bad Request", format!("Invalid host '{}'", host)))
+    }
+}
+
+/**
+    Decodes "%XX" escapes, invalid escapes are kept as they are
+*/
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match escaped {
+            Some(b) if bytes[i] == b'%' => {
+                decoded.push(b);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn json_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,200 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::ip_hash::fnv1a;
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::IpHash;
+use super::OutlierDetection;
+
+/**
+    Maps every client IP (port is ignored) to a host using a hash ring, so changing the host list only moves clients of the
+    added or removed host (unlike [IpHash], where most clients move).
+
+    Every host gets [virtual_nodes] points on the ring (multiplied by its weight) and client is mapped to the host of the
+    first point after the hash of its IP. If that host is on cooldown, the ring is walked further to the next host.
+*/
+pub struct ConsistentHash {
+    host_manager: HostManager,
+    virtual_nodes: usize,
+    /**
+        Points on the ring as (hash, host index), sorted by hash
+    */
+    ring: Vec<(u64, usize)>,
+    cooldowns: Cooldowns,
+}
+
+impl ConsistentHash {
+    /**
+        Creates the ring with given number of points for every host of weight 1, more points spread clients more evenly
+    */
+    pub fn new(host_manager: HostManager, virtual_nodes: usize) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        let virtual_nodes = virtual_nodes.max(1);
+        ConsistentHash {
+            ring: ConsistentHash::build_ring(&host_manager, virtual_nodes),
+            host_manager,
+            virtual_nodes,
+            cooldowns,
+        }
+    }
+
+    fn build_ring(host_manager: &HostManager, virtual_nodes: usize) -> Vec<(u64, usize)> {
+        let mut ring = vec![];
+        for (i, host) in host_manager.hosts.iter().enumerate() {
+            // points only depend on the host address, so they stay in place when other hosts change
+            for node in 0..virtual_nodes * host_manager.weights[i] as usize {
+                let point = format!("{}#{}", host, node);
+                ring.push((ConsistentHash::mix(fnv1a(point.as_bytes())), i));
+            }
+        }
+
+        ring.sort_unstable();
+        ring
+    }
+
+    /**
+        Spreads bits of the hash (similar inputs like "host#1" and "host#2" have similar FNV hashes)
+    */
+    fn mix(mut hash: u64) -> u64 {
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+}
+
+impl BalancingAlgorithm for ConsistentHash {
+    fn get_next_host(&mut self, client: SocketAddr) -> Option<Endpoint> {
+        if self.ring.is_empty() {
+            return None;
+        }
+
+        let key = ConsistentHash::mix(IpHash::hash_ip(client.ip()));
+        let start = self.ring.partition_point(|(hash, _)| *hash < key) % self.ring.len();
+
+        // if host on cooldown, walk the ring to the next hosts
+        for offset in 0..self.ring.len() {
+            let addr = &self.host_manager.hosts[self.ring[(start + offset) % self.ring.len()].1];
+            if !self.cooldowns.should_avoid(addr) {
+                return Some(addr.clone());
+            }
+        }
+
+        None
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.ring = ConsistentHash::build_ring(&host_manager, self.virtual_nodes);
+        self.host_manager = host_manager;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn host(port: u16) -> Endpoint {
+        Endpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    fn clients() -> impl Iterator<Item = SocketAddr> {
+        (0..10_000u32).map(|i| SocketAddr::from((Ipv4Addr::from(0x0a00_0000 + i * 7919), 40000)))
+    }
+
+    fn assign(b: &mut ConsistentHash) -> Vec<Endpoint> {
+        clients().map(|c| b.get_next_host(c).unwrap()).collect()
+    }
+
+    #[test]
+    fn adding_host_only_moves_its_share_of_clients() {
+        let mut b = ConsistentHash::new(HostManager::from_hosts(vec![host(5000), host(5001)]), 160);
+        let before = assign(&mut b);
+
+        b.update_hosts(HostManager::from_hosts(vec![host(5000), host(5001), host(5002)]));
+        let after = assign(&mut b);
+
+        // only clients of the new host move, about a third of them
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b != a).collect();
+        assert!(moved.iter().all(|(_, a)| **a == host(5002)));
+        let share = moved.len() as f64 / before.len() as f64;
+        assert!((0.28..0.40).contains(&share), "{} of clients moved", share);
+
+        // removing it again moves them back
+        b.update_hosts(HostManager::from_hosts(vec![host(5000), host(5001)]));
+        assert_eq!(assign(&mut b), before);
+    }
+
+    #[test]
+    fn ring_is_deterministic() {
+        let hosts = vec![host(5000), host(5001), host(5002)];
+        let mut first = ConsistentHash::new(HostManager::from_hosts(hosts.clone()), 40);
+        let mut second = ConsistentHash::new(HostManager::from_hosts(hosts.clone()), 40);
+        assert_eq!(first.ring.len(), 3 * 40);
+        assert_eq!(first.ring, second.ring);
+        assert_eq!(assign(&mut first), assign(&mut second));
+
+        // order of hosts doesn't matter, only their addresses
+        let mut reversed = ConsistentHash::new(HostManager::from_hosts(hosts.into_iter().rev().collect()), 40);
+        assert_eq!(assign(&mut first), assign(&mut reversed));
+
+        // client port is ignored
+        let client = SocketAddr::from(([10, 1, 2, 3], 1000));
+        let other_port = SocketAddr::from(([10, 1, 2, 3], 2000));
+        assert_eq!(first.get_next_host(client), first.get_next_host(other_port));
+    }
+}
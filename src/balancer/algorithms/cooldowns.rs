@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::Endpoint;
+use super::HostManager;
+
+/**
+    Decides how many errors a host can have before it is put on cooldown
+*/
+#[derive(Clone, Copy)]
+pub struct OutlierDetection {
+    /**
+        Host is put on cooldown once it reports this many errors within [window]
+    */
+    pub errors: u32,
+    pub window: Duration,
+    /**
+        How long the host is avoided after its first cooldown, doubled every time it fails again (up to [max_cooldown])
+    */
+    pub cooldown: Duration,
+    pub max_cooldown: Duration,
+    /**
+        Host on cooldown is fully restored (its cooldown and backoff are dropped) once it reports this many successes in a row
+    */
+    pub successes: u32,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        // every error puts the host on cooldown
+        OutlierDetection {
+            errors: 1,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+            max_cooldown: Duration::from_secs(480),
+            successes: 1,
+        }
+    }
+}
+
+/**
+    Keeps track of hosts that are on cooldown, shared by all balancing algorithms so they avoid erroring hosts the same way.
+    Hosts that keep failing after their cooldown has passed are avoided for exponentially longer, until a success is reported
+*/
+pub struct Cooldowns {
+    /**
+        Host, end of its cooldown and number of consecutive cooldowns it got
+    */
+    cooldowns: Vec<(Endpoint, Instant, u32)>,
+    /**
+        Recent errors of hosts that are not on cooldown yet
+    */
+    errors: Vec<(Endpoint, Vec<Instant>)>,
+    /**
+        Successes in a row of hosts on cooldown, since their last error
+    */
+    successes: HashMap<Endpoint, u32>,
+    /**
+        Hosts that are always avoided until they stop draining, no matter their errors
+    */
+    draining: Vec<Endpoint>,
+    /**
+        Maximum number of established connections of hosts, hosts are avoided while they have this many
+    */
+    connection_limits: Vec<(Endpoint, usize)>,
+    /**
+        Established connections of hosts, counted for every host so limits added on reload apply right away
+    */
+    connections: HashMap<Endpoint, usize>,
+    outlier_detection: OutlierDetection,
+}
+
+impl Cooldowns {
+    pub fn new(host_manager: &HostManager) -> Self {
+        let mut cooldowns = Cooldowns {
+            cooldowns: vec![],
+            errors: vec![],
+            successes: HashMap::new(),
+            draining: vec![],
+            connection_limits: vec![],
+            connections: HashMap::new(),
+            outlier_detection: OutlierDetection::default(),
+        };
+
+        cooldowns.set_connection_limits(host_manager);
+        cooldowns
+    }
+
+    pub fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.outlier_detection = outlier_detection;
+    }
+
+    /**
+        Takes connection limits from the host options (hosts without the option have no limit)
+    */
+    fn set_connection_limits(&mut self, host_manager: &HostManager) {
+        self.connection_limits = host_manager
+            .hosts
+            .iter()
+            .zip(&host_manager.max_connections)
+            .filter_map(|(h, m)| m.map(|m| (h.clone(), m)))
+            .collect();
+    }
+
+    pub fn report_connected(&mut self, addr: &Endpoint) {
+        *self.connections.entry(addr.clone()).or_insert(0) += 1;
+    }
+
+    pub fn report_disconnected(&mut self, addr: &Endpoint) {
+        if let Some(count) = self.connections.get_mut(addr) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    pub fn get_connections(&self, addr: &Endpoint) -> usize {
+        self.connections.get(addr).copied().unwrap_or(0)
+    }
+
+    /**
+        Checks if host has as many established connections as it's allowed to have
+    */
+    pub fn is_full(&self, addr: &Endpoint) -> bool {
+        match self.connection_limits.iter().find(|(h, _)| h == addr) {
+            Some((_, limit)) => self.get_connections(addr) >= *limit,
+            None => false,
+        }
+    }
+
+    fn get_host_cooldown_index(&self, addr: &Endpoint) -> Option<usize> {
+        self.cooldowns.iter().position(|c| c.0 == *addr)
+    }
+
+    /**
+        Checks if host has a cooldown entry (even if the cooldown itself has already passed)
+    */
+    pub fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.get_host_cooldown_index(addr).is_some()
+    }
+
+    /**
+        Returns how long the host is still avoided for, [None] if it has no cooldown (or it has already passed)
+    */
+    pub fn get_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        let index = self.get_host_cooldown_index(addr)?;
+        self.cooldowns[index].1.checked_duration_since(Instant::now())
+    }
+
+    /**
+        Checks if host should currently be avoided. Passed cooldowns are kept (so repeated errors can back off), until success is reported
+    */
+    pub fn should_avoid(&mut self, addr: &Endpoint) -> bool {
+        if self.draining.contains(addr) || self.is_full(addr) {
+            return true;
+        }
+
+        match self.get_host_cooldown_index(addr) {
+            Some(index) => Instant::now() <= self.cooldowns[index].1,
+            None => false,
+        }
+    }
+
+    /**
+        Returns cooldown duration for the given number of consecutive cooldowns (doubling every time, up to the maximum)
+    */
+    fn get_cooldown_duration(&self, count: u32) -> Duration {
+        let OutlierDetection { cooldown, max_cooldown, .. } = self.outlier_detection;
+        let multiplier = 1u32.checked_shl(count.saturating_sub(1)).unwrap_or(u32::MAX);
+        cooldown.checked_mul(multiplier).map_or(max_cooldown, |d| d.min(max_cooldown))
+    }
+
+    pub fn report_error(&mut self, addr: &Endpoint) {
+        let now = Instant::now();
+        self.successes.remove(addr);
+
+        match self.get_host_cooldown_index(addr) {
+            Some(index) => {
+                let (_, limit, count) = &self.cooldowns[index];
+
+                // only back off further if host failed again after its cooldown has passed, errors reported
+                // during the cooldown (e.g. from clients that were already connecting) just extend the current one
+                let count = if now > *limit { count.saturating_add(1) } else { *count };
+                self.cooldowns[index].1 = now + self.get_cooldown_duration(count);
+                self.cooldowns[index].2 = count;
+            }
+            None => {
+                if self.is_outlier(addr, now) {
+                    self.cooldowns.push((addr.clone(), now + self.get_cooldown_duration(1), 1));
+                }
+            }
+        }
+    }
+
+    /**
+        Records the error and checks if host had enough errors within the window to be put on cooldown
+    */
+    fn is_outlier(&mut self, addr: &Endpoint, now: Instant) -> bool {
+        let OutlierDetection { errors, window, .. } = self.outlier_detection;
+        if errors <= 1 {
+            return true;
+        }
+
+        let index = match self.errors.iter().position(|e| e.0 == *addr) {
+            Some(i) => i,
+            None => {
+                self.errors.push((addr.clone(), vec![]));
+                self.errors.len() - 1
+            }
+        };
+
+        // only errors within the window count
+        let recent = &mut self.errors[index].1;
+        recent.retain(|t| now.duration_since(*t) <= window);
+        recent.push(now);
+
+        if recent.len() < errors as usize {
+            return false;
+        }
+
+        self.errors.remove(index);
+        true
+    }
+
+    /**
+        Returns the host from the list whose last error is the oldest, hosts without a cooldown are returned first.
+        Returns [None] if the list is empty (or every host is draining or at its connection limit)
+    */
+    pub fn least_recently_failed(&self, hosts: &[Endpoint]) -> Option<Endpoint> {
+        // cooldown ends [get_cooldown_duration] after the last error
+        let failed_at = |host: &Endpoint| {
+            self.get_host_cooldown_index(host).map(|i| {
+                let (_, limit, count) = &self.cooldowns[i];
+                *limit - self.get_cooldown_duration(*count)
+            })
+        };
+
+        hosts
+            .iter()
+            .filter(|h| !self.draining.contains(h) && !self.is_full(h))
+            .min_by_key(|h| failed_at(h))
+            .cloned()
+    }
+
+    /**
+        Drops cooldowns of all hosts that are not in the given host list
+    */
+    pub fn retain_hosts(&mut self, hosts: &[Endpoint]) {
+        self.cooldowns.retain(|c| hosts.contains(&c.0));
+        self.errors.retain(|e| hosts.contains(&e.0));
+        self.successes.retain(|h, _| hosts.contains(h));
+        self.draining.retain(|h| hosts.contains(h));
+        self.connection_limits.retain(|(h, _)| hosts.contains(h));
+        self.connections.retain(|h, _| hosts.contains(h));
+    }
+
+    /**
+        Drops state of hosts that are no longer present and takes connection limits of the new hosts
+    */
+    pub fn update_hosts(&mut self, host_manager: &HostManager) {
+        self.retain_hosts(&host_manager.hosts);
+        self.set_connection_limits(host_manager);
+    }
+
+    /**
+        Starts (or stops) avoiding the host because it's draining
+    */
+    pub fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.draining.retain(|h| h != addr);
+        if draining {
+            self.draining.push(addr.clone());
+        }
+    }
+
+    /**
+        Resets errors of the host. Host on cooldown is removed from it (fully resetting its backoff) once it reports
+        enough successes in a row, until then it's still avoided while its cooldown lasts
+    */
+    pub fn report_success(&mut self, addr: &Endpoint) {
+        self.errors.retain(|e| e.0 != *addr);
+
+        if let Some(index) = self.get_host_cooldown_index(addr) {
+            let successes = self.successes.entry(addr.clone()).or_insert(0);
+            *successes += 1;
+            if *successes >= self.outlier_detection.successes {
+                self.successes.remove(addr);
+                self.cooldowns.remove(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn host() -> Endpoint {
+        Endpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], 5000)))
+    }
+
+    fn cooldowns(outlier_detection: OutlierDetection) -> Cooldowns {
+        let mut cooldowns = Cooldowns::new(&HostManager::from_hosts(vec![host()]));
+        cooldowns.set_outlier_detection(outlier_detection);
+        cooldowns
+    }
+
+    #[test]
+    fn ejects_after_errors_within_window() {
+        let mut c = cooldowns(OutlierDetection {
+            errors: 3,
+            window: Duration::from_secs(10),
+            ..OutlierDetection::default()
+        });
+
+        c.report_error(&host());
+        c.report_error(&host());
+        assert!(!c.is_on_cooldown(&host()));
+        assert!(!c.should_avoid(&host()));
+
+        c.report_error(&host());
+        assert!(c.is_on_cooldown(&host()));
+        assert!(c.should_avoid(&host()));
+    }
+
+    #[test]
+    fn errors_outside_window_dont_eject() {
+        let mut c = cooldowns(OutlierDetection {
+            errors: 3,
+            window: Duration::from_millis(100),
+            ..OutlierDetection::default()
+        });
+
+        for _ in 0..5 {
+            c.report_error(&host());
+            sleep(Duration::from_millis(60));
+        }
+        assert!(!c.is_on_cooldown(&host()));
+
+        // success clears errors within the window too
+        c.report_error(&host());
+        c.report_error(&host());
+        c.report_success(&host());
+        c.report_error(&host());
+        assert!(!c.is_on_cooldown(&host()));
+    }
+
+    #[test]
+    fn cooldown_doubles_up_to_max_and_resets_after_successes() {
+        let cooldown = Duration::from_millis(100);
+        let mut c = cooldowns(OutlierDetection {
+            errors: 1,
+            cooldown,
+            max_cooldown: cooldown * 4,
+            successes: 3,
+            ..OutlierDetection::default()
+        });
+
+        for expected in [cooldown, cooldown * 2, cooldown * 4, cooldown * 4] {
+            c.report_error(&host());
+            let remaining = c.get_remaining(&host()).unwrap();
+            assert!(
+                remaining <= expected && remaining > expected - Duration::from_millis(50),
+                "{:?} != {:?}",
+                remaining,
+                expected
+            );
+
+            // errors during the cooldown only extend it
+            c.report_error(&host());
+            let remaining = c.get_remaining(&host()).unwrap();
+            assert!(remaining > expected - Duration::from_millis(50), "{:?} != {:?}", remaining, expected);
+
+            sleep(expected + Duration::from_millis(10));
+            assert!(!c.should_avoid(&host()));
+            assert!(c.is_on_cooldown(&host()));
+        }
+
+        c.report_success(&host());
+        c.report_success(&host());
+        assert!(c.is_on_cooldown(&host()));
+        c.report_success(&host());
+        assert!(!c.is_on_cooldown(&host()));
+
+        // backoff starts over
+        c.report_error(&host());
+        assert!(c.get_remaining(&host()).unwrap() <= cooldown);
+    }
+
+    #[test]
+    fn cooldown_durations() {
+        let c = cooldowns(OutlierDetection::default());
+        let durations: Vec<u64> = (1..=7).map(|count| c.get_cooldown_duration(count).as_secs()).collect();
+        assert_eq!(durations, vec![30, 60, 120, 240, 480, 480, 480]);
+        assert_eq!(c.get_cooldown_duration(u32::MAX).as_secs(), 480);
+    }
+
+    #[test]
+    fn single_success_resets_backoff() {
+        let cooldown = Duration::from_millis(50);
+        let mut c = cooldowns(OutlierDetection {
+            cooldown,
+            max_cooldown: cooldown * 8,
+            ..OutlierDetection::default()
+        });
+
+        // back off to the third cooldown
+        for _ in 0..3 {
+            c.report_error(&host());
+            sleep(c.get_remaining(&host()).unwrap() + Duration::from_millis(5));
+        }
+        c.report_error(&host());
+        assert!(c.get_remaining(&host()).unwrap() > cooldown * 3);
+
+        c.report_success(&host());
+        assert!(!c.is_on_cooldown(&host()));
+        assert!(!c.should_avoid(&host()));
+        c.report_error(&host());
+        assert!(c.get_remaining(&host()).unwrap() <= cooldown);
+    }
+}
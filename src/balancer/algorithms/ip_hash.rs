@@ -0,0 +1,169 @@
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+/**
+    Maps every client IP (port is ignored) to the same host, so clients keep landing on the same backend across reconnects.
+
+    If the mapped host is on cooldown, the next hosts in order are tried instead. This means mappings are only stable
+    while hosts are healthy - when a host goes down its clients get moved to the next host and once it recovers they move back.
+    Changing the host list also changes the mapping of most clients.
+*/
+pub struct IpHash {
+    host_manager: HostManager,
+    cooldowns: Cooldowns,
+}
+
+impl IpHash {
+    pub fn new(host_manager: HostManager) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        IpHash { host_manager, cooldowns }
+    }
+
+    pub(super) fn hash_ip(ip: IpAddr) -> u64 {
+        match ip {
+            IpAddr::V4(ip) => fnv1a(&ip.octets()),
+            IpAddr::V6(ip) => fnv1a(&ip.octets()),
+        }
+    }
+}
+
+/**
+    FNV-1a hash, so the mapping stays the same between restarts and different balancer instances
+*/
+pub(super) fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash
+}
+
+impl BalancingAlgorithm for IpHash {
+    fn get_next_host(&mut self, client: SocketAddr) -> Option<Endpoint> {
+        let max_host = self.host_manager.hosts.len();
+        if max_host == 0 {
+            return None;
+        }
+
+        let starting_index = (IpHash::hash_ip(client.ip()) % max_host as u64) as usize;
+
+        // if host on cooldown, probe the next ones
+        for offset in 0..max_host {
+            let addr = &self.host_manager.hosts[(starting_index + offset) % max_host];
+            if !self.cooldowns.should_avoid(addr) {
+                return Some(addr.clone());
+            }
+        }
+
+        None
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.host_manager = host_manager;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::balancer::testing::endpoints;
+
+    fn client(i: u32, port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::from(0x0a00_0000 + i), port))
+    }
+
+    #[test]
+    fn same_ip_gets_same_host() {
+        let mut b = IpHash::new(HostManager::from_hosts(endpoints(4)));
+        let mut picked = vec![];
+        for i in 0..100 {
+            let host = b.get_next_host(client(i, 40000)).unwrap();
+            for port in 40001..40010 {
+                assert!(b.get_next_host(client(i, port)) == Some(host.clone()));
+            }
+            picked.push(host);
+        }
+
+        // clients are spread between all hosts
+        assert!(endpoints(4).iter().all(|h| picked.contains(h)));
+
+        // ipv6 clients too
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 40000));
+        let host = b.get_next_host(v6).unwrap();
+        assert_eq!(b.get_next_host(SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 1))), Some(host));
+    }
+
+    #[test]
+    fn skips_hosts_on_cooldown() {
+        let hosts = endpoints(4);
+        let mut b = IpHash::new(HostManager::from_hosts(hosts.clone()));
+        let host = b.get_next_host(client(1, 40000)).unwrap();
+        let index = hosts.iter().position(|h| *h == host).unwrap();
+
+        // next host is probed, and the client returns once its host recovers
+        b.report_error(&host);
+        assert_eq!(b.get_next_host(client(1, 40000)), Some(hosts[(index + 1) % 4].clone()));
+        b.report_success(&host);
+        assert_eq!(b.get_next_host(client(1, 40000)), Some(host));
+    }
+}
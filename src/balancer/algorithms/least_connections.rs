@@ -0,0 +1,114 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+pub struct LeastConnections {
+    current_host: usize,
+    host_manager: HostManager,
+    cooldowns: Cooldowns,
+}
+
+impl LeastConnections {
+    pub fn new(host_manager: HostManager) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        LeastConnections {
+            current_host: 0,
+            host_manager,
+            cooldowns,
+        }
+    }
+}
+
+impl BalancingAlgorithm for LeastConnections {
+    fn get_next_host(&mut self, _client: SocketAddr) -> Option<Endpoint> {
+        let max_host = self.host_manager.hosts.len();
+
+        // go through hosts in round robin order, so ties are resolved by whichever host comes first
+        let mut selected: Option<(usize, usize)> = None;
+        for offset in 0..max_host {
+            let index = (self.current_host + offset) % max_host;
+            let addr = &self.host_manager.hosts[index];
+
+            // if host on cooldown, avoid it
+            if self.cooldowns.should_avoid(addr) {
+                continue;
+            }
+
+            let count = self.cooldowns.get_connections(addr);
+            match selected {
+                Some((_, c)) if c <= count => {}
+                _ => selected = Some((index, count)),
+            }
+        }
+
+        let index = match selected {
+            Some((i, _)) => i,
+            None => return None,
+        };
+
+        self.current_host = (index + 1) % max_host;
+        Some(self.host_manager.hosts[index].clone())
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.host_manager = host_manager;
+
+        if self.current_host >= self.host_manager.hosts.len() {
+            self.current_host = 0;
+        }
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+}
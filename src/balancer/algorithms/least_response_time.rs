@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+// weight of the newest latency sample in the moving average, higher values adapt faster but are noisier
+const LATENCY_ALPHA: f64 = 0.3;
+
+/**
+    Picks the host with the lowest exponentially weighted moving average of connect latency.
+    Hosts without any samples yet are picked first, so every host gets measured
+*/
+pub struct LeastResponseTime {
+    current_host: usize,
+    host_manager: HostManager,
+    /**
+        Moving average of connect latency of every measured host (in seconds)
+    */
+    latencies: HashMap<Endpoint, f64>,
+    cooldowns: Cooldowns,
+}
+
+impl LeastResponseTime {
+    pub fn new(host_manager: HostManager) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        LeastResponseTime {
+            current_host: 0,
+            host_manager,
+            latencies: HashMap::new(),
+            cooldowns,
+        }
+    }
+}
+
+impl BalancingAlgorithm for LeastResponseTime {
+    fn get_next_host(&mut self, _client: SocketAddr) -> Option<Endpoint> {
+        let max_host = self.host_manager.hosts.len();
+
+        // go through hosts in round robin order, so unmeasured hosts and ties are spread between hosts
+        let mut selected: Option<(usize, f64)> = None;
+        for offset in 0..max_host {
+            let index = (self.current_host + offset) % max_host;
+            let addr = &self.host_manager.hosts[index];
+
+            // if host on cooldown, avoid it
+            if self.cooldowns.should_avoid(addr) {
+                continue;
+            }
+
+            // host without samples is tried right away
+            let latency = match self.latencies.get(addr) {
+                Some(l) => *l,
+                None => {
+                    selected = Some((index, 0.0));
+                    break;
+                }
+            };
+
+            match selected {
+                Some((_, l)) if l <= latency => {}
+                _ => selected = Some((index, latency)),
+            }
+        }
+
+        let (index, _) = selected?;
+        self.current_host = (index + 1) % max_host;
+        Some(self.host_manager.hosts[index].clone())
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn report_latency(&mut self, addr: &Endpoint, latency: Duration) {
+        let sample = latency.as_secs_f64();
+        self.latencies
+            .entry(addr.clone())
+            .and_modify(|l| *l = LATENCY_ALPHA * sample + (1.0 - LATENCY_ALPHA) * *l)
+            .or_insert(sample);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.latencies.retain(|addr, _| host_manager.hosts.contains(addr));
+        self.host_manager = host_manager;
+
+        if self.current_host >= self.host_manager.hosts.len() {
+            self.current_host = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::testing::{client_addr, endpoints};
+
+    #[test]
+    fn measures_every_host_then_prefers_the_fastest() {
+        let mut b = LeastResponseTime::new(HostManager::from_hosts(endpoints(3)));
+        let h = endpoints(3);
+        let latency = |host: &Endpoint| if *host == h[1] { Duration::from_millis(5) } else { Duration::from_millis(50) };
+
+        let mut picked = vec![];
+        for _ in 0..100 {
+            let host = b.get_next_host(client_addr()).unwrap();
+            b.report_latency(&host, latency(&host));
+            picked.push(host);
+        }
+
+        // every host is tried once, so it gets measured
+        assert_eq!(picked[..3], h[..]);
+        assert_eq!(picked.iter().filter(|p| **p == h[0]).count(), 1);
+        assert_eq!(picked.iter().filter(|p| **p == h[2]).count(), 1);
+        assert_eq!(picked.iter().filter(|p| **p == h[1]).count(), 98);
+    }
+
+    #[test]
+    fn moving_average_follows_latency_changes() {
+        let mut b = LeastResponseTime::new(HostManager::from_hosts(endpoints(2)));
+        let h = endpoints(2);
+        b.report_latency(&h[0], Duration::from_millis(10));
+        b.report_latency(&h[1], Duration::from_millis(20));
+        assert_eq!(b.get_next_host(client_addr()).unwrap(), h[0]);
+
+        // a single slow sample doesn't outweigh the history, a few of them do
+        b.report_latency(&h[0], Duration::from_millis(40));
+        assert_eq!(b.get_next_host(client_addr()).unwrap(), h[0]);
+        b.report_latency(&h[0], Duration::from_millis(40));
+        b.report_latency(&h[0], Duration::from_millis(40));
+        assert_eq!(b.get_next_host(client_addr()).unwrap(), h[1]);
+    }
+
+    #[test]
+    fn skips_hosts_on_cooldown() {
+        let mut b = LeastResponseTime::new(HostManager::from_hosts(endpoints(2)));
+        let h = endpoints(2);
+        b.report_latency(&h[0], Duration::from_millis(5));
+        b.report_latency(&h[1], Duration::from_millis(50));
+
+        b.report_error(&h[0]);
+        assert_eq!(b.get_next_host(client_addr()).unwrap(), h[1]);
+        b.report_error(&h[1]);
+        assert_eq!(b.get_next_host(client_addr()), None);
+    }
+}
@@ -1,5 +1,181 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+mod consistent_hash;
+mod cooldowns;
+mod ip_hash;
+mod least_connections;
+mod least_response_time;
+mod power_of_two_choices;
+mod priority_round_robin;
+mod random;
 mod round_robin;
+mod weighted_least_connections;
+mod weighted_round_robin;
 
-pub use round_robin::RoundRobin;
+use super::AlgorithmFactory;
 use super::BalancingAlgorithm;
-use super::HostManager;
\ No newline at end of file
+use super::Endpoint;
+use super::HostManager;
+pub use consistent_hash::ConsistentHash;
+pub use cooldowns::Cooldowns;
+pub use cooldowns::OutlierDetection;
+pub use ip_hash::IpHash;
+pub use least_connections::LeastConnections;
+pub use least_response_time::LeastResponseTime;
+pub use power_of_two_choices::PowerOfTwoChoices;
+pub use priority_round_robin::PriorityRoundRobin;
+pub use random::Random;
+pub use round_robin::RoundRobin;
+pub use weighted_least_connections::WeightedLeastConnections;
+pub use weighted_round_robin::WeightedRoundRobin;
+
+/**
+    Balancing algorithm that can be picked by name (e.g. on the command line)
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    RoundRobin,
+    WeightedRoundRobin,
+    PriorityRoundRobin,
+    LeastConnections,
+    WeightedLeastConnections,
+    LeastResponseTime,
+    PowerOfTwoChoices,
+    Random,
+    IpHash,
+    ConsistentHash,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 10] = [
+        Algorithm::RoundRobin,
+        Algorithm::WeightedRoundRobin,
+        Algorithm::PriorityRoundRobin,
+        Algorithm::LeastConnections,
+        Algorithm::WeightedLeastConnections,
+        Algorithm::LeastResponseTime,
+        Algorithm::PowerOfTwoChoices,
+        Algorithm::Random,
+        Algorithm::IpHash,
+        Algorithm::ConsistentHash,
+    ];
+
+    /**
+        Returns the factory creating this algorithm for every pool of hosts that doesn't pick its own algorithm.
+        Virtual nodes are only used by [ConsistentHash]
+    */
+    pub fn factory(self, virtual_nodes: usize) -> AlgorithmFactory {
+        Arc::new(move |h| h.algorithm.unwrap_or(self).create(h, virtual_nodes))
+    }
+
+    fn create(self, host_manager: HostManager, virtual_nodes: usize) -> Box<dyn BalancingAlgorithm> {
+        match self {
+            Algorithm::RoundRobin => Box::new(RoundRobin::new(host_manager)),
+            Algorithm::WeightedRoundRobin => Box::new(WeightedRoundRobin::new(host_manager)),
+            Algorithm::PriorityRoundRobin => Box::new(PriorityRoundRobin::new(host_manager)),
+            Algorithm::LeastConnections => Box::new(LeastConnections::new(host_manager)),
+            Algorithm::WeightedLeastConnections => Box::new(WeightedLeastConnections::new(host_manager)),
+            Algorithm::LeastResponseTime => Box::new(LeastResponseTime::new(host_manager)),
+            Algorithm::PowerOfTwoChoices => Box::new(PowerOfTwoChoices::new(host_manager)),
+            Algorithm::Random => Box::new(Random::new(host_manager)),
+            Algorithm::IpHash => Box::new(IpHash::new(host_manager)),
+            Algorithm::ConsistentHash => Box::new(ConsistentHash::new(host_manager, virtual_nodes)),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Algorithm::ALL.iter().copied().find(|a| a.to_string() == s).ok_or(())
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Algorithm::RoundRobin => write!(f, "round_robin"),
+            Algorithm::WeightedRoundRobin => write!(f, "weighted_round_robin"),
+            Algorithm::PriorityRoundRobin => write!(f, "priority_round_robin"),
+            Algorithm::LeastConnections => write!(f, "least_connections"),
+            Algorithm::WeightedLeastConnections => write!(f, "weighted_least_connections"),
+            Algorithm::LeastResponseTime => write!(f, "least_response_time"),
+            Algorithm::PowerOfTwoChoices => write!(f, "power_of_two_choices"),
+            Algorithm::Random => write!(f, "random"),
+            Algorithm::IpHash => write!(f, "ip_hash"),
+            Algorithm::ConsistentHash => write!(f, "consistent_hash"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
+    use super::*;
+
+    fn host(port: u16) -> Endpoint {
+        Endpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    fn client(i: u32) -> SocketAddr {
+        SocketAddr::from((std::net::Ipv4Addr::from(0x0a00_0000 + i), 40000))
+    }
+
+    #[test]
+    fn add_and_remove_hosts_while_picking() {
+        for algorithm in Algorithm::ALL {
+            let hosts = vec![host(5000), host(5001)];
+            let b: Arc<RwLock<Box<dyn BalancingAlgorithm>>> = Arc::new(RwLock::new(algorithm.create(HostManager::from_hosts(hosts.clone()), 16)));
+
+            let picker = {
+                let b = Arc::clone(&b);
+                thread::spawn(move || {
+                    for i in 0..20_000 {
+                        let mut b = b.write().unwrap();
+                        let picked = b.get_next_host(client(i)).expect("no host picked");
+                        assert!(b.get_hosts().contains(&picked), "{} picked host {} that is not present", algorithm, picked);
+                    }
+                })
+            };
+
+            for _ in 0..2_000 {
+                assert!(b.write().unwrap().add_host(host(5002)), "{} didn't add host", algorithm);
+                assert!(!b.write().unwrap().add_host(host(5002)), "{} added host twice", algorithm);
+                assert!(b.write().unwrap().remove_host(&host(5002)), "{} didn't remove host", algorithm);
+                assert!(!b.write().unwrap().remove_host(&host(5002)), "{} removed missing host", algorithm);
+            }
+
+            picker.join().unwrap();
+            assert_eq!(b.read().unwrap().get_hosts(), hosts, "{}", algorithm);
+
+            // the last host is never removed
+            let mut b = b.write().unwrap();
+            assert!(b.remove_host(&host(5001)));
+            assert!(!b.remove_host(&host(5000)), "{} removed the last host", algorithm);
+            assert_eq!(b.get_next_host(client(0)), Some(host(5000)), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn no_host_without_hosts() {
+        for algorithm in Algorithm::ALL {
+            let mut b = algorithm.create(HostManager::from_hosts(vec![]), 16);
+            assert_eq!(b.get_next_host(client(0)), None, "{}", algorithm);
+            assert_eq!(b.get_fallback_host(), None, "{}", algorithm);
+
+            let mut b = algorithm.create(HostManager::from_hosts(vec![host(5000), host(5001)]), 16);
+            assert!(b.get_next_host(client(0)).is_some(), "{}", algorithm);
+            b.update_hosts(HostManager::from_hosts(vec![]));
+            for i in 0..10 {
+                assert_eq!(b.get_next_host(client(i)), None, "{}", algorithm);
+            }
+            assert_eq!(b.get_fallback_host(), None, "{}", algorithm);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+/**
+    Picks two random hosts and takes the one with fewer established connections. It balances almost as evenly as
+    [LeastConnections], without going through all hosts for every client
+*/
+pub struct PowerOfTwoChoices {
+    state: u64,
+    host_manager: HostManager,
+    cooldowns: Cooldowns,
+}
+
+impl PowerOfTwoChoices {
+    pub fn new(host_manager: HostManager) -> Self {
+        // seed with current time, so multiple balancer instances don't pick hosts in the same order
+        let seed = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_nanos() as u64,
+            Err(_) => 0,
+        };
+
+        PowerOfTwoChoices::with_seed(host_manager, seed)
+    }
+
+    pub fn with_seed(host_manager: HostManager, seed: u64) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        PowerOfTwoChoices {
+            // xorshift state can never be zero, otherwise it would only ever produce zeros
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+            host_manager,
+            cooldowns,
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /**
+        Returns two different random indexes below [count] (the same index twice when there is only one)
+    */
+    fn pick_two(&mut self, count: usize) -> (usize, usize) {
+        let first = (self.next_random() % count as u64) as usize;
+        if count == 1 {
+            return (first, first);
+        }
+
+        // second index is picked from the remaining ones, so it can't be the first one
+        let second = (self.next_random() % (count - 1) as u64) as usize;
+        (first, if second >= first { second + 1 } else { second })
+    }
+
+    /**
+        Takes the host with fewer established connections, the first one on ties
+    */
+    fn pick_less_loaded(&self, first: &Endpoint, second: &Endpoint) -> Endpoint {
+        if self.cooldowns.get_connections(second) < self.cooldowns.get_connections(first) {
+            second.clone()
+        } else {
+            first.clone()
+        }
+    }
+}
+
+impl BalancingAlgorithm for PowerOfTwoChoices {
+    fn get_next_host(&mut self, _client: SocketAddr) -> Option<Endpoint> {
+        let count = self.host_manager.hosts.len();
+        if count == 0 {
+            return None;
+        }
+
+        // both picks are usually available, so hosts only have to be gone through when some are avoided
+        let (first, second) = self.pick_two(count);
+        let first = self.host_manager.hosts[first].clone();
+        let second = self.host_manager.hosts[second].clone();
+        match (self.cooldowns.should_avoid(&first), self.cooldowns.should_avoid(&second)) {
+            (false, false) => return Some(self.pick_less_loaded(&first, &second)),
+            (false, true) if count == 2 => return Some(first),
+            (true, false) if count == 2 => return Some(second),
+            _ => {}
+        }
+
+        // only pick between hosts that are not on cooldown
+        let mut available: Vec<Endpoint> = vec![];
+        for host in &self.host_manager.hosts {
+            if !self.cooldowns.should_avoid(host) {
+                available.push(host.clone());
+            }
+        }
+
+        if available.is_empty() {
+            return None;
+        }
+
+        let (first, second) = self.pick_two(available.len());
+        Some(self.pick_less_loaded(&available[first], &available[second]))
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.host_manager = host_manager;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::testing::{client_addr, endpoints};
+
+    fn picks(b: &mut PowerOfTwoChoices, count: usize) -> Vec<Endpoint> {
+        (0..count).map(|_| b.get_next_host(client_addr()).unwrap()).collect()
+    }
+
+    #[test]
+    fn same_seed_picks_same_hosts() {
+        let mut first = PowerOfTwoChoices::with_seed(HostManager::from_hosts(endpoints(5)), 42);
+        let mut second = PowerOfTwoChoices::with_seed(HostManager::from_hosts(endpoints(5)), 42);
+        assert_eq!(picks(&mut first, 1000), picks(&mut second, 1000));
+
+        let mut other = PowerOfTwoChoices::with_seed(HostManager::from_hosts(endpoints(5)), 43);
+        assert_ne!(picks(&mut first, 1000), picks(&mut other, 1000));
+    }
+
+    #[test]
+    fn picks_two_different_hosts() {
+        let mut b = PowerOfTwoChoices::with_seed(HostManager::from_hosts(endpoints(5)), 7);
+        for _ in 0..1000 {
+            let (first, second) = b.pick_two(5);
+            assert_ne!(first, second);
+            assert!(first < 5 && second < 5);
+        }
+        assert_eq!(b.pick_two(1), (0, 0));
+    }
+
+    #[test]
+    fn prefers_less_loaded_host() {
+        let hosts = endpoints(2);
+        let mut b = PowerOfTwoChoices::with_seed(HostManager::from_hosts(hosts.clone()), 7);
+        b.report_connected(&hosts[0]);
+        assert!(picks(&mut b, 100).iter().all(|h| *h == hosts[1]));
+
+        b.report_connected(&hosts[1]);
+        b.report_connected(&hosts[1]);
+        assert!(picks(&mut b, 100).iter().all(|h| *h == hosts[0]));
+    }
+}
@@ -0,0 +1,197 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+/**
+    Round robin over hosts of the highest priority tier (lowest priority value) that has any hosts not on cooldown.
+    Lower tiers (backups) are only used when every host of all higher tiers is on cooldown, as soon as a higher tier
+    host recovers new clients return to it
+*/
+pub struct PriorityRoundRobin {
+    /**
+        Host indexes grouped by tier, ordered from highest priority to lowest
+    */
+    tiers: Vec<Vec<usize>>,
+    /**
+        Round robin counter of every tier
+    */
+    current_hosts: Vec<usize>,
+    host_manager: HostManager,
+    cooldowns: Cooldowns,
+}
+
+impl PriorityRoundRobin {
+    pub fn new(host_manager: HostManager) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        let tiers = PriorityRoundRobin::get_tiers(&host_manager);
+        PriorityRoundRobin {
+            current_hosts: vec![0; tiers.len()],
+            tiers,
+            host_manager,
+            cooldowns,
+        }
+    }
+
+    fn get_tiers(host_manager: &HostManager) -> Vec<Vec<usize>> {
+        let mut priorities = host_manager.priorities.clone();
+        priorities.sort_unstable();
+        priorities.dedup();
+
+        priorities
+            .iter()
+            .map(|p| (0..host_manager.hosts.len()).filter(|i| host_manager.priorities[*i] == *p).collect())
+            .collect()
+    }
+
+    /**
+        Picks next host of the tier, skipping hosts on cooldown. Returns [None] if no host can be picked
+    */
+    fn select(&mut self, tier: usize) -> Option<usize> {
+        let hosts = &self.tiers[tier];
+        for _ in 0..hosts.len() {
+            let index = hosts[self.current_hosts[tier] % hosts.len()];
+            self.current_hosts[tier] = (self.current_hosts[tier] + 1) % hosts.len();
+
+            if self.cooldowns.should_avoid(&self.host_manager.hosts[index]) {
+                continue;
+            }
+
+            return Some(index);
+        }
+
+        None
+    }
+}
+
+impl BalancingAlgorithm for PriorityRoundRobin {
+    fn get_next_host(&mut self, _client: SocketAddr) -> Option<Endpoint> {
+        for tier in 0..self.tiers.len() {
+            if let Some(index) = self.select(tier) {
+                return Some(self.host_manager.hosts[index].clone());
+            }
+        }
+
+        None
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.tiers = PriorityRoundRobin::get_tiers(&host_manager);
+        self.current_hosts = vec![0; self.tiers.len()];
+        self.host_manager = host_manager;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::testing::{client_addr, endpoints};
+
+    /**
+        Two primaries (priority 1) and two backups (priority 2)
+    */
+    fn balancer() -> PriorityRoundRobin {
+        let mut host_manager = HostManager::from_hosts(endpoints(4));
+        host_manager.priorities = vec![1, 1, 2, 2];
+        PriorityRoundRobin::new(host_manager)
+    }
+
+    fn picks(b: &mut PriorityRoundRobin, count: usize) -> Vec<Endpoint> {
+        (0..count).map(|_| b.get_next_host(client_addr()).unwrap()).collect()
+    }
+
+    #[test]
+    fn uses_only_primaries_while_they_are_up() {
+        let mut b = balancer();
+        let h = endpoints(4);
+        assert_eq!(picks(&mut b, 4), vec![h[0].clone(), h[1].clone(), h[0].clone(), h[1].clone()]);
+
+        // one primary down, the other one takes all clients
+        b.report_error(&h[0]);
+        assert_eq!(picks(&mut b, 3), vec![h[1].clone(); 3]);
+    }
+
+    #[test]
+    fn fails_over_to_backups_and_recovers() {
+        let mut b = balancer();
+        let h = endpoints(4);
+
+        b.report_error(&h[0]);
+        b.report_error(&h[1]);
+        let picked = picks(&mut b, 4);
+        assert_eq!(picked, vec![h[2].clone(), h[3].clone(), h[2].clone(), h[3].clone()]);
+
+        // clients return to the primary as soon as it recovers
+        b.report_success(&h[1]);
+        assert_eq!(picks(&mut b, 3), vec![h[1].clone(); 3]);
+
+        b.report_success(&h[0]);
+        let picked = picks(&mut b, 4);
+        assert!(picked.iter().all(|p| *p == h[0] || *p == h[1]));
+        assert!(picked.contains(&h[0]));
+    }
+
+    #[test]
+    fn no_host_when_every_tier_is_down() {
+        let mut b = balancer();
+        for host in endpoints(4) {
+            b.report_error(&host);
+        }
+
+        assert_eq!(b.get_next_host(client_addr()), None);
+        assert!(b.get_fallback_host().is_some());
+    }
+}
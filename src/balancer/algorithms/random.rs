@@ -0,0 +1,171 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+pub struct Random {
+    state: u64,
+    host_manager: HostManager,
+    cooldowns: Cooldowns,
+}
+
+impl Random {
+    pub fn new(host_manager: HostManager) -> Self {
+        // seed with current time, so multiple balancer instances don't pick hosts in the same order
+        let seed = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_nanos() as u64,
+            Err(_) => 0,
+        };
+
+        Random::with_seed(host_manager, seed)
+    }
+
+    pub fn with_seed(host_manager: HostManager, seed: u64) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        Random {
+            // xorshift state can never be zero, otherwise it would only ever produce zeros
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+            host_manager,
+            cooldowns,
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64*
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl BalancingAlgorithm for Random {
+    fn get_next_host(&mut self, _client: SocketAddr) -> Option<Endpoint> {
+        let hosts = &self.host_manager.hosts;
+
+        // only pick between hosts that are not on cooldown
+        let mut available: Vec<Endpoint> = vec![];
+        for host in hosts {
+            if !self.cooldowns.should_avoid(host) {
+                available.push(host.clone());
+            }
+        }
+
+        if available.is_empty() {
+            return None;
+        }
+
+        let index = (self.next_random() % available.len() as u64) as usize;
+        Some(available.swap_remove(index))
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.host_manager = host_manager;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::testing::{client_addr, endpoints};
+
+    fn picks(b: &mut Random, count: usize) -> Vec<Endpoint> {
+        (0..count).map(|_| b.get_next_host(client_addr()).unwrap()).collect()
+    }
+
+    #[test]
+    fn same_seed_picks_same_hosts() {
+        let mut first = Random::with_seed(HostManager::from_hosts(endpoints(5)), 42);
+        let mut second = Random::with_seed(HostManager::from_hosts(endpoints(5)), 42);
+        assert_eq!(picks(&mut first, 1000), picks(&mut second, 1000));
+
+        let mut other = Random::with_seed(HostManager::from_hosts(endpoints(5)), 43);
+        assert_ne!(picks(&mut first, 1000), picks(&mut other, 1000));
+
+        // zero seed still produces random hosts
+        let mut zero = Random::with_seed(HostManager::from_hosts(endpoints(5)), 0);
+        let picked = picks(&mut zero, 1000);
+        assert!(endpoints(5).iter().all(|h| picked.contains(h)));
+    }
+
+    #[test]
+    fn picks_hosts_uniformly() {
+        let mut b = Random::with_seed(HostManager::from_hosts(endpoints(4)), 7);
+        let picked = picks(&mut b, 40_000);
+        for host in endpoints(4) {
+            let count = picked.iter().filter(|h| **h == host).count();
+            assert!((9_000..11_000).contains(&count), "{} picked {} times", host, count);
+        }
+    }
+
+    #[test]
+    fn avoids_hosts_on_cooldown() {
+        let hosts = endpoints(3);
+        let mut b = Random::with_seed(HostManager::from_hosts(hosts.clone()), 7);
+        b.report_error(&hosts[1]);
+        assert!(picks(&mut b, 1000).iter().all(|h| *h != hosts[1]));
+
+        b.report_error(&hosts[0]);
+        b.report_error(&hosts[2]);
+        assert_eq!(b.get_next_host(client_addr()), None);
+        assert!(b.get_fallback_host().is_some());
+    }
+}
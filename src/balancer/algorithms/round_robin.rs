@@ -1,46 +1,33 @@
 use std::net::SocketAddr;
 use std::time::Duration;
-use std::time::Instant;
-use std::usize;
 
 use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
 use super::HostManager;
+use super::OutlierDetection;
 
 pub struct RoundRobin {
     current_host: usize,
     max_host: usize,
     host_manager: HostManager,
-    cooldowns: Vec<(SocketAddr, Instant)>,
+    cooldowns: Cooldowns,
 }
 
 impl RoundRobin {
-    // how long the host is avoided (on cooldown) when first error is reported
-    const TARGET_DOWN_COOLDOWN: Duration = Duration::from_secs(30);
-
     pub fn new(host_manager: HostManager) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
         let max = host_manager.hosts.len();
         RoundRobin {
             current_host: 0,
-            host_manager: host_manager,
+            host_manager,
             max_host: max,
-            cooldowns: vec![],
-        }
-    }
-
-    fn get_host_cooldown_index(&self, addr: SocketAddr) -> i32 {
-        let mut index: i32 = -1;
-        for i in 0..self.cooldowns.len() {
-            if self.cooldowns[i].0 == addr {
-                index = i as i32;
-                break;
-            }
+            cooldowns,
         }
-
-        index
     }
 
     fn increment_host_counter(&mut self) {
-        self.current_host = self.current_host + 1;
+        self.current_host += 1;
         if self.current_host >= self.max_host {
             self.current_host = 0
         }
@@ -48,65 +35,107 @@ impl RoundRobin {
 }
 
 impl BalancingAlgorithm for RoundRobin {
-    fn get_next_host(&mut self) -> SocketAddr {
-        let mut val;
-        let starting_host_index = self.current_host;
-
-        loop {
-            // select host
-            val = self.host_manager.hosts[self.current_host];
-
-            // offset host selector to next one
+    fn get_next_host(&mut self, _client: SocketAddr) -> Option<Endpoint> {
+        for _ in 0..self.max_host {
+            // select host and offset host selector to next one
+            let index = self.current_host;
             self.increment_host_counter();
 
-            // if host on cooldown, avoid it (but if we made a full cycle, just return the initial choice)
-            let cooldown_index = self.get_host_cooldown_index(val);
-            let cycle_reached = starting_host_index == self.current_host;
-            if cooldown_index >= 0 && !cycle_reached {
-                // check if cooldown has passed
-                if Instant::now() > self.cooldowns[cooldown_index as usize].1 {
-                    // cooldown passed, remove it
-                    self.cooldowns.remove(cooldown_index as usize);
-                    break;
-                }
-
-                continue;
-            } else if cycle_reached {
-                // cycle reached, let's increment the counter to continue trying different hosts until one actually connects
-                self.increment_host_counter();
+            // if host on cooldown, avoid it
+            let val = &self.host_manager.hosts[index];
+            if !self.cooldowns.should_avoid(val) {
+                return Some(val.clone());
             }
-
-            break;
         }
 
-        val
+        None
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
     }
 
-    fn report_error(&mut self, addr: SocketAddr) {
-        let index: i32 = self.get_host_cooldown_index(addr);
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
 
-        let new_limit = Instant::now() + RoundRobin::TARGET_DOWN_COOLDOWN;
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
 
-        if index < 0 {
-            // add it
-            self.cooldowns.push((addr, new_limit));
-        } else {
-            // update it
-            self.cooldowns[index as usize].1 = new_limit;
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.max_host = host_manager.hosts.len();
+        self.host_manager = host_manager;
+
+        if self.current_host >= self.max_host {
+            self.current_host = 0;
         }
     }
 
-    fn report_success(&mut self, addr: SocketAddr) {
-        let index: i32 = self.get_host_cooldown_index(addr);
-        if index < 0 {
-            return;
+    fn add_host(&mut self, addr: Endpoint) -> bool {
+        if !self.host_manager.add_host(addr) {
+            return false;
         }
 
-        self.cooldowns.remove(index as usize);
+        self.max_host = self.host_manager.hosts.len();
+        true
     }
 
-    fn is_on_cooldown(&self, addr: SocketAddr) -> bool {
-        let index: i32 = self.get_host_cooldown_index(addr);
-        return index >= 0;
+    fn remove_host(&mut self, addr: &Endpoint) -> bool {
+        // there always has to be a host to return
+        let index = match self.host_manager.hosts.iter().position(|h| h == addr) {
+            Some(i) if self.max_host > 1 => i,
+            _ => return false,
+        };
+
+        self.host_manager.remove_host(addr);
+        self.cooldowns.retain_hosts(&self.host_manager.hosts);
+        self.max_host = self.host_manager.hosts.len();
+
+        // keep pointing at the same next host, hosts after the removed one moved back by one
+        if index < self.current_host {
+            self.current_host -= 1;
+        }
+        if self.current_host >= self.max_host {
+            self.current_host = 0;
+        }
+        true
     }
 }
@@ -0,0 +1,181 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+/**
+    Picks the host with the least active connections relative to its weight, so hosts with bigger weights carry
+    proportionally more concurrent connections. Ties are resolved in round robin order
+*/
+pub struct WeightedLeastConnections {
+    current_host: usize,
+    host_manager: HostManager,
+    cooldowns: Cooldowns,
+}
+
+impl WeightedLeastConnections {
+    pub fn new(host_manager: HostManager) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        WeightedLeastConnections {
+            current_host: 0,
+            host_manager,
+            cooldowns,
+        }
+    }
+}
+
+impl BalancingAlgorithm for WeightedLeastConnections {
+    fn get_next_host(&mut self, _client: SocketAddr) -> Option<Endpoint> {
+        let max_host = self.host_manager.hosts.len();
+
+        // go through hosts in round robin order, so ties are resolved by whichever host comes first
+        let mut selected: Option<(usize, u64, u64)> = None;
+        for offset in 0..max_host {
+            let index = (self.current_host + offset) % max_host;
+            let addr = &self.host_manager.hosts[index];
+
+            // if host on cooldown, avoid it
+            if self.cooldowns.should_avoid(addr) {
+                continue;
+            }
+
+            // compare connections / weight without dividing (count_a / weight_a < count_b / weight_b)
+            let count = self.cooldowns.get_connections(addr) as u64;
+            let weight = self.host_manager.weights[index] as u64;
+            match selected {
+                Some((_, c, w)) if c * weight <= count * w => {}
+                _ => selected = Some((index, count, weight)),
+            }
+        }
+
+        let index = match selected {
+            Some((i, _, _)) => i,
+            None => return None,
+        };
+
+        self.current_host = (index + 1) % max_host;
+        Some(self.host_manager.hosts[index].clone())
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.host_manager = host_manager;
+
+        if self.current_host >= self.host_manager.hosts.len() {
+            self.current_host = 0;
+        }
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::balancer::testing::{client_addr, endpoints};
+
+    fn balancer(weights: Vec<u32>) -> WeightedLeastConnections {
+        let mut host_manager = HostManager::from_hosts(endpoints(weights.len() as u16));
+        host_manager.weights = weights;
+        WeightedLeastConnections::new(host_manager)
+    }
+
+    #[test]
+    fn heavier_host_carries_proportionally_more_connections() {
+        let mut b = balancer(vec![1, 3]);
+        let h = endpoints(2);
+
+        // steady arrival, the oldest connection closes whenever a new one arrives once there are 40 of them
+        let mut active = VecDeque::new();
+        for _ in 0..1000 {
+            if active.len() == 40 {
+                let oldest = active.pop_front().unwrap();
+                b.report_disconnected(&oldest);
+            }
+
+            let host = b.get_next_host(client_addr()).unwrap();
+            b.report_connected(&host);
+            active.push_back(host);
+        }
+
+        let light = active.iter().filter(|a| **a == h[0]).count();
+        let heavy = active.iter().filter(|a| **a == h[1]).count();
+        assert!((9..=11).contains(&light), "light host has {} connections", light);
+        assert!((29..=31).contains(&heavy), "heavy host has {} connections", heavy);
+    }
+
+    #[test]
+    fn ties_are_resolved_in_round_robin_order() {
+        let mut b = balancer(vec![2, 2, 2]);
+        let h = endpoints(3);
+        let picked: Vec<Endpoint> = (0..6).map(|_| b.get_next_host(client_addr()).unwrap()).collect();
+        assert_eq!(picked, vec![h[0].clone(), h[1].clone(), h[2].clone(), h[0].clone(), h[1].clone(), h[2].clone()]);
+    }
+
+    #[test]
+    fn skips_hosts_on_cooldown() {
+        let mut b = balancer(vec![1, 3]);
+        let h = endpoints(2);
+        b.report_error(&h[1]);
+        for _ in 0..5 {
+            let host = b.get_next_host(client_addr()).unwrap();
+            assert_eq!(host, h[0]);
+            b.report_connected(&host);
+        }
+
+        b.report_error(&h[0]);
+        assert_eq!(b.get_next_host(client_addr()), None);
+    }
+}
@@ -0,0 +1,116 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::BalancingAlgorithm;
+use super::Cooldowns;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+/**
+    Smooth weighted round robin (same as used by nginx). Hosts are picked proportionally to their weights,
+    but picks of the same host are spread out instead of being returned in a row
+*/
+pub struct WeightedRoundRobin {
+    host_manager: HostManager,
+    current_weights: Vec<i64>,
+    cooldowns: Cooldowns,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(host_manager: HostManager) -> Self {
+        let cooldowns = Cooldowns::new(&host_manager);
+        let current_weights = vec![0; host_manager.hosts.len()];
+        WeightedRoundRobin {
+            host_manager,
+            current_weights,
+            cooldowns,
+        }
+    }
+
+    fn select(&mut self) -> Option<usize> {
+        let mut total: i64 = 0;
+        let mut selected: Option<usize> = None;
+
+        for i in 0..self.host_manager.hosts.len() {
+            // if host on cooldown, avoid it
+            if self.cooldowns.should_avoid(&self.host_manager.hosts[i]) {
+                continue;
+            }
+
+            let weight = self.host_manager.weights[i] as i64;
+            self.current_weights[i] += weight;
+            total += weight;
+
+            match selected {
+                Some(s) if self.current_weights[s] >= self.current_weights[i] => {}
+                _ => selected = Some(i),
+            }
+        }
+
+        let index = selected?;
+        self.current_weights[index] -= total;
+        Some(index)
+    }
+}
+
+impl BalancingAlgorithm for WeightedRoundRobin {
+    fn get_next_host(&mut self, _client: SocketAddr) -> Option<Endpoint> {
+        let index = self.select()?;
+        Some(self.host_manager.hosts[index].clone())
+    }
+
+    fn get_fallback_host(&self) -> Option<Endpoint> {
+        self.cooldowns.least_recently_failed(&self.host_manager.hosts)
+    }
+
+    fn report_error(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_error(addr);
+    }
+
+    fn report_success(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_success(addr);
+    }
+
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_on_cooldown(addr)
+    }
+
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration> {
+        self.cooldowns.get_remaining(addr)
+    }
+
+    fn set_outlier_detection(&mut self, outlier_detection: OutlierDetection) {
+        self.cooldowns.set_outlier_detection(outlier_detection);
+    }
+
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool) {
+        self.cooldowns.set_draining(addr, draining);
+    }
+
+    fn is_full(&self, addr: &Endpoint) -> bool {
+        self.cooldowns.is_full(addr)
+    }
+
+    fn report_connected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_connected(addr);
+    }
+
+    fn report_disconnected(&mut self, addr: &Endpoint) {
+        self.cooldowns.report_disconnected(addr);
+    }
+
+    fn get_hosts(&self) -> Vec<Endpoint> {
+        self.host_manager.hosts.clone()
+    }
+
+    fn host_manager(&self) -> &HostManager {
+        &self.host_manager
+    }
+
+    fn update_hosts(&mut self, host_manager: HostManager) {
+        self.cooldowns.update_hosts(&host_manager);
+        self.current_weights = vec![0; host_manager.hosts.len()];
+        self.host_manager = host_manager;
+    }
+}
@@ -1,132 +1,1293 @@
 use std::collections::HashMap;
 use std::io::ErrorKind;
+use std::io::Result;
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
-use std::usize;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec;
-use std::{thread, time::Duration, u16};
 
+use super::health;
+use super::AccessList;
+use super::AccessLog;
+use super::AccessLogWriter;
+use super::AdminApi;
+use super::Algorithm;
+use super::AlgorithmFactory;
+use super::AllHostsDown;
+use super::BalancerStats;
 use super::BalancingAlgorithm;
-use super::RoundRobin;
+use super::CloseReason;
+use super::Config;
+use super::ConnectionLimitAction;
+use super::DnsResolver;
+use super::Endpoint;
+#[cfg(feature = "geoip")]
+use super::GeoIp;
+use super::HealthCheck;
+use super::HostManager;
+use super::HostRemoval;
+use super::HostStats;
+use super::IpConnectionCounts;
+use super::MetricsServer;
+use super::PendingOverflow;
+use super::Pools;
+use super::Routing;
+use super::SharedHostManager;
+use super::SharedPools;
+use super::SocketOptions;
+use super::StatsHandle;
+use super::StatsRegistry;
+use super::StickKey;
+use super::StickTable;
+use super::Stream;
+use super::TargetPool;
 use super::TcpClient;
-use mio::net::TcpStream;
+use super::Throughput;
+use super::ThroughputStats;
+use log::{debug, error, info, warn};
 use mio::Events;
-use mio::Interest;
 use mio::Poll;
+use mio::Registry;
 use mio::Token;
+use mio::Waker;
+use rustls::ServerConfig;
 
-// this is used as the total timeout allowed to connect before client is disconnected
-const TOTAL_CONNECTION_TIMEOUT: Duration = Duration::from_millis(4000);
+// this token is used by wakers, client tokens never reach it (see [TokenAllocator])
+pub const WAKE_TOKEN: Token = Token(usize::MAX);
 
-// this is used as the timeout to connect to a target host
-const CONNECTION_TIMEOUT: Duration = Duration::from_millis(400);
+// this is used as the interval between checks if clients of a worker thread should be moved to another thread
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(1);
 
-pub struct LoadBalancer {
+// longest time a worker thread with clients sleeps for, so changes made without waking it up (e.g. hosts removed by the API)
+// are noticed. Threads without any clients sleep until they are woken up
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+// this is used as the interval between connection attempts of clients waiting for a host with free connections
+const WAIT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// tokens below this one are reserved (same as the first tokens used by the listener), clients get tokens above them
+const FIRST_CLIENT_TOKEN: usize = 2;
+
+pub type ClientCounts = Arc<[AtomicUsize]>;
+pub type SharedAlgorithm = Arc<RwLock<Box<dyn BalancingAlgorithm>>>;
+pub type PendingClientLists = Arc<[PendingClients]>;
+
+/**
+    Queue of clients handed over to a worker thread that it has not picked up yet. Pushing never waits for the worker,
+    the length is counted separately so the least busy thread can be picked without touching the queue
+*/
+pub struct PendingClients {
+    sender: Sender<TcpClient>,
+    len: AtomicUsize,
     /**
-        Holds client counts for all threads
+        Number of the oldest queued clients that the worker should close instead of picking up, see [PendingOverflow::DropOldest]
     */
-    client_counts: Arc<RwLock<Vec<Arc<RwLock<usize>>>>>,
+    shed: AtomicUsize,
+}
+
+impl PendingClients {
+    fn new() -> (Self, Receiver<TcpClient>) {
+        let (sender, receiver) = mpsc::channel();
+        let pending = PendingClients {
+            sender,
+            len: AtomicUsize::new(0),
+            shed: AtomicUsize::new(0),
+        };
+        (pending, receiver)
+    }
+
     /**
-        Newly added clients are added here, threads will add them to polling when they can
+        Returns the new length. Clients pushed after the worker has stopped are dropped (which closes them)
     */
-    client_lists_pending: Arc<RwLock<Vec<Arc<RwLock<Vec<TcpClient>>>>>>,
-    threads: u16,
-    stopped: Arc<RwLock<bool>>,
-    debug: Arc<RwLock<bool>>,
-    balancing_algorithm: Arc<RwLock<RoundRobin>>,
+    fn push(&self, client: TcpClient) -> usize {
+        // counted before sending, so the worker never takes a client that is not counted yet
+        let len = self.len.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.sender.send(client).is_err() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        len
+    }
+
+    /**
+        Takes the next client without waiting, only the worker owning the receiver calls this
+    */
+    fn take(&self, receiver: &Receiver<TcpClient>) -> Option<TcpClient> {
+        let client = receiver.try_recv().ok()?;
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Some(client)
+    }
+
+    /**
+        Marks the oldest queued client to be closed by the worker, so it does not count towards the length anymore
+    */
+    fn shed_oldest(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /**
+        Returns [true] if the worker should close the client it just took instead of picking it up
+    */
+    fn take_shed(&self) -> bool {
+        self.shed.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| s.checked_sub(1)).is_ok()
+    }
+
+    /**
+        Called by the worker once the queue is empty, clients left to close were moved clients that are never closed
+    */
+    fn clear_shed(&self) {
+        self.shed.store(0, Ordering::Relaxed);
+    }
+
+    /**
+        Returns number of queued clients that will be picked up
+    */
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed).saturating_sub(self.shed.load(Ordering::Relaxed))
+    }
 }
 
-impl LoadBalancer {
-    pub fn new(balancing_algorithm: RoundRobin, threads: u16, debug: bool) -> Self {
-        // prepare client lists for every thread
-        let mut client_counts: Vec<Arc<RwLock<usize>>> = vec![];
-        for _ in 0..threads {
-            client_counts.push(Arc::new(RwLock::new(0)));
+/**
+    Hands out tokens for clients of a worker thread. Tokens of removed clients are reused, so live clients never share
+    a token and tokens stay far away from [WAKE_TOKEN]. Client tokens are even, the odd token following each one is used
+    by its target stream
+*/
+struct TokenAllocator {
+    next: usize,
+    free: Vec<usize>,
+}
+
+impl TokenAllocator {
+    fn new() -> Self {
+        TokenAllocator {
+            next: FIRST_CLIENT_TOKEN,
+            free: vec![],
+        }
+    }
+
+    fn allocate(&mut self) -> Token {
+        if let Some(id) = self.free.pop() {
+            return Token(id);
+        }
+
+        // only grows up to the highest number of concurrent clients, so it can't reach [WAKE_TOKEN]
+        // (nor the admin token of the listener just below it, with the target token following the client token)
+        assert!(self.next < WAKE_TOKEN.0 - 2, "Worker thread ran out of client tokens");
+        let id = self.next;
+        self.next += 2;
+        Token(id)
+    }
+
+    fn release(&mut self, token: Token) {
+        self.free.push(token.0);
+    }
+}
+
+/**
+    Builds a [LoadBalancer], starting from default options
+*/
+pub struct LoadBalancerBuilder {
+    config: Config,
+    access_list: AccessList,
+    tls_config: Option<Arc<ServerConfig>>,
+}
+
+impl LoadBalancerBuilder {
+    /**
+        Replaces all options with the given config (e.g. parsed from the config file)
+    */
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn access_list(mut self, access_list: AccessList) -> Self {
+        self.access_list = access_list;
+        self
+    }
+
+    /**
+        Terminates TLS from clients with given server config
+    */
+    pub fn tls_config(mut self, tls_config: Option<Arc<ServerConfig>>) -> Self {
+        self.tls_config = tls_config;
+        self
+    }
+
+    /**
+        Creates the balancer for given hosts, [algorithm] is used to create the balancing algorithm for every pool of hosts
+    */
+    pub fn build(self, mut host_manager: HostManager, algorithm: AlgorithmFactory) -> LoadBalancer {
+        let LoadBalancerBuilder {
+            config,
+            access_list,
+            tls_config,
+        } = self;
+        let threads = config.threads;
+        LoadBalancer::check_connect_timeouts(&host_manager, config.total_connection_timeout);
+        LoadBalancer::set_fallback_pool(&mut host_manager, config.fallback_pool.clone());
+
+        let mut pools = Pools::new(host_manager.clone(), algorithm, config.outlier_detection());
+        if let Some(ttl) = config.stick_table_ttl {
+            pools.set_stick_table(StickTable::new(ttl, config.stick_key));
+        }
+
+        // country database is kept by the access list, as it's checked at the same time
+        #[cfg(feature = "geoip")]
+        let access_list = match &config.geoip_file {
+            Some(file) => {
+                let pools = LoadBalancer::check_country_pools(&host_manager, &config.country_pools);
+                access_list.with_geoip(GeoIp::new(file, &config.deny_countries, &pools))
+            }
+            None => access_list,
+        };
+        #[cfg(not(feature = "geoip"))]
+        if config.geoip_file.is_some() {
+            warn!("[Config] Balancer was built without the 'geoip' feature, ignoring 'geoip_file'");
         }
-        let client_counts = Arc::new(RwLock::new(client_counts));
 
-        // prepare pending client lists for every thread
-        let mut client_lists_pending: Vec<Arc<RwLock<Vec<TcpClient>>>> = vec![];
-        for _ in 0..threads {
-            let lists: Vec<TcpClient> = vec![];
-            client_lists_pending.push(Arc::new(RwLock::new(lists)));
+        let transparent_proxy = config.transparent_proxy && LoadBalancer::check_transparent_proxy();
+
+        // PROXY protocol header is only sent once per target connection and describes a single client
+        let connection_pool_idle = match config.connection_pool_idle {
+            Some(_) if config.proxy_protocol => {
+                warn!("[Config] Connection pooling can't be used together with PROXY protocol, disabling it");
+                None
+            }
+            // same goes for the source IP of transparent connections
+            Some(_) if transparent_proxy => {
+                warn!("[Config] Connection pooling can't be used together with transparent proxy, disabling it");
+                None
+            }
+            idle => idle,
+        };
+
+        // without terminating TLS, the balancer only sees encrypted requests of clients routed by SNI
+        let forwarded_headers = config.forwarded_headers && (config.routing != Routing::Sni || tls_config.is_some());
+        if config.forwarded_headers && !forwarded_headers {
+            warn!("[Config] Forwarded headers can't be added to requests of clients routed by SNI unless TLS is terminated, disabling them");
         }
-        let client_lists_pending = Arc::new(RwLock::new(client_lists_pending));
 
-        let b = LoadBalancer {
+        let access_log_writer = match &config.access_log_file {
+            Some(path) if config.access_log != AccessLog::None => match AccessLogWriter::spawn(path) {
+                Ok(w) => Some(w),
+                Err(e) => {
+                    error!(
+                        "[Config] Failed to open access log file '{}', writing access log through the logger! {}",
+                        path, e
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        // prepare client counts for every thread
+        let client_counts: ClientCounts = (0..threads).map(|_| AtomicUsize::new(0)).collect();
+
+        // prepare pending client queues for every thread, receivers are handed to the threads once they are spawned
+        let (client_lists_pending, pending_receivers): (Vec<PendingClients>, Vec<Receiver<TcpClient>>) = (0..threads).map(|_| PendingClients::new()).unzip();
+
+        LoadBalancer {
             client_counts,
-            client_lists_pending,
+            client_lists_pending: client_lists_pending.into(),
+            pending_receivers: Mutex::new(pending_receivers),
             threads,
+            connection_timeout: config.connection_timeout,
+            total_connection_timeout: config.total_connection_timeout,
+            max_connection_attempts: config.max_connection_attempts,
+            idle_timeout: config.idle_timeout,
+            connection_pool_idle,
+            max_connections: config.max_connections,
+            connection_limit_action: config.connection_limit_action,
+            connection_limit_response: config.connection_limit_response.as_deref().map(Arc::from),
+            max_pending_clients: config.max_pending_clients,
+            pending_overflow: config.pending_overflow,
+            max_pending_reached: Arc::new(AtomicUsize::new(0)),
+            stale_events: Arc::new(AtomicUsize::new(0)),
+            max_connections_per_ip: config.max_connections_per_ip,
+            access_list: Arc::new(access_list),
+            proxy_protocol: config.proxy_protocol,
+            accept_proxy_protocol: config.accept_proxy_protocol,
+            transparent_proxy,
+            tls_config,
+            ip_counts: Arc::new(RwLock::new(HashMap::new())),
+            buffer_size: config.buffer_size,
+            socket_options: SocketOptions {
+                nodelay: config.nodelay,
+                keepalive: config.keepalive,
+                keepalive_interval: config.keepalive_interval,
+                keepalive_probes: config.keepalive_probes,
+            },
+            metrics_port: config.metrics_port,
+            api_port: config.api_port,
             stopped: Arc::new(RwLock::new(false)),
-            debug: Arc::new(RwLock::new(debug)),
-            balancing_algorithm: Arc::new(RwLock::new(balancing_algorithm)),
-        };
+            host_manager: Arc::new(RwLock::new(host_manager)),
+            dns_refresh: config.dns_refresh,
+            health_check_interval: config.health_check_interval,
+            health_check_timeout: config.health_check_timeout,
+            health_check: config.health_check,
+            health_check_path: config.health_check_path.clone(),
+            host_removal: config.host_removal,
+            all_hosts_down: config.all_hosts_down,
+            retry_mid_stream: config.retry_mid_stream,
+            pools: Arc::new(RwLock::new(pools)),
+            routing: config.routing,
+            forwarded_headers,
+            fallback_pool: config.fallback_pool.clone(),
+            access_log: config.access_log,
+            access_log_writer,
+            stats: Arc::new(StatsRegistry::new()),
+            throughput: Arc::new(Throughput::default()),
+            thread_throughput: Arc::new((0..threads).map(|_| Arc::new(Throughput::default())).collect()),
+            wakers: Arc::new(vec![]),
+            rebalance_threshold: config.rebalance_threshold,
+            listener_waker: None,
+        }
+    }
+}
+
+// setters of single options, the binary itself sets all of them through [config]
+impl LoadBalancerBuilder {
+    pub fn threads(mut self, threads: u16) -> Self {
+        self.config.threads = threads.max(1);
+        self
+    }
+
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.config.debug = debug;
+        self
+    }
+
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connection_timeout = timeout;
+        self
+    }
+
+    pub fn total_connection_timeout(mut self, timeout: Duration) -> Self {
+        self.config.total_connection_timeout = timeout;
+        self
+    }
+
+    pub fn max_connection_attempts(mut self, max_attempts: u32) -> Self {
+        self.config.max_connection_attempts = max_attempts;
+        self
+    }
+
+    /**
+        Hosts put on cooldown are avoided for [cooldown], doubled every time they fail again (up to [max_cooldown])
+    */
+    pub fn cooldown(mut self, cooldown: Duration, max_cooldown: Duration) -> Self {
+        self.config.cooldown = cooldown;
+        self.config.max_cooldown = max_cooldown;
+        self
+    }
+
+    /**
+        Host on cooldown is fully restored once this many connections (or health checks) in a row succeed
+    */
+    pub fn recovery_successes(mut self, successes: u32) -> Self {
+        self.config.recovery_successes = successes.max(1);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    pub fn connection_pool_idle(mut self, idle: Option<Duration>) -> Self {
+        self.config.connection_pool_idle = idle;
+        self
+    }
 
-        b
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = max_connections;
+        self
+    }
+
+    pub fn connection_limit_action(mut self, action: ConnectionLimitAction) -> Self {
+        self.config.connection_limit_action = action;
+        self
+    }
+
+    pub fn connection_limit_response(mut self, response: &[u8]) -> Self {
+        self.config.connection_limit_response = Some(response.to_vec()).filter(|r| !r.is_empty());
+        self
+    }
+
+    pub fn max_pending_clients(mut self, max_pending: usize) -> Self {
+        self.config.max_pending_clients = max_pending;
+        self
+    }
+
+    pub fn pending_overflow(mut self, overflow: PendingOverflow) -> Self {
+        self.config.pending_overflow = overflow;
+        self
+    }
+
+    pub fn max_connections_per_ip(mut self, max_connections: usize) -> Self {
+        self.config.max_connections_per_ip = max_connections;
+        self
+    }
+
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.config.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    pub fn metrics_port(mut self, port: u16) -> Self {
+        self.config.metrics_port = port;
+        self
+    }
+
+    pub fn api_port(mut self, port: u16) -> Self {
+        self.config.api_port = port;
+        self
+    }
+
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.nodelay = nodelay;
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Option<Duration>) -> Self {
+        self.config.keepalive = keepalive;
+        self
+    }
+
+    /**
+        Time between keepalive probes and number of unanswered probes before connections are dropped, [None] keeps system defaults
+    */
+    pub fn keepalive_probes(mut self, interval: Option<Duration>, probes: Option<u32>) -> Self {
+        self.config.keepalive_interval = interval;
+        self.config.keepalive_probes = probes;
+        self
+    }
+
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.config.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    pub fn accept_proxy_protocol(mut self, accept: bool) -> Self {
+        self.config.accept_proxy_protocol = accept;
+        self
+    }
+
+    /**
+        Connects to hosts from client IPs (Linux only, needs CAP_NET_ADMIN)
+    */
+    pub fn transparent_proxy(mut self, transparent: bool) -> Self {
+        self.config.transparent_proxy = transparent;
+        self
+    }
+
+    pub fn routing(mut self, routing: Routing) -> Self {
+        self.config.routing = routing;
+        self
+    }
+
+    pub fn forwarded_headers(mut self, forwarded_headers: bool) -> Self {
+        self.config.forwarded_headers = forwarded_headers;
+        self
+    }
+
+    /**
+        Pool used by clients of the default pool while none of its hosts can be picked
+    */
+    pub fn fallback_pool(mut self, pool: Option<String>) -> Self {
+        self.config.fallback_pool = pool;
+        self
+    }
+
+    /**
+        Clients keep returning to the host they used last until [ttl] passes after their last connection, [None] disables it
+    */
+    pub fn stick_table(mut self, ttl: Option<Duration>, key: StickKey) -> Self {
+        self.config.stick_table_ttl = ttl;
+        self.config.stick_key = key;
+        self
+    }
+
+    /**
+        Country database of client IPs, clients of [deny_countries] are rejected and clients of countries in [country_pools]
+        use the paired pool instead of the default pool
+    */
+    pub fn geoip(mut self, file: &str, deny_countries: Vec<String>, country_pools: Vec<(String, String)>) -> Self {
+        self.config.geoip_file = Some(file.to_string());
+        self.config.deny_countries = deny_countries;
+        self.config.country_pools = country_pools;
+        self
+    }
+
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.config.access_log = access_log;
+        self
+    }
+
+    /**
+        Appends the access log to given file ("-" for stdout) instead of writing it through the logger
+    */
+    pub fn access_log_file(mut self, path: Option<String>) -> Self {
+        self.config.access_log_file = path;
+        self
+    }
+
+    pub fn dns_refresh(mut self, interval: Option<Duration>) -> Self {
+        self.config.dns_refresh = interval;
+        self
+    }
+
+    pub fn health_check_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.health_check_interval = interval;
+        self
+    }
+
+    pub fn health_check_timeout(mut self, timeout: Duration) -> Self {
+        self.config.health_check_timeout = timeout;
+        self
+    }
+
+    pub fn health_check(mut self, check: HealthCheck, path: &str) -> Self {
+        self.config.health_check = check;
+        self.config.health_check_path = path.to_string();
+        self
+    }
+
+    pub fn rebalance_threshold(mut self, threshold: usize) -> Self {
+        self.config.rebalance_threshold = threshold;
+        self
+    }
+
+    /**
+        Whether clients are connected to another host when their host fails after data was exchanged with it
+    */
+    pub fn retry_mid_stream(mut self, retry: bool) -> Self {
+        self.config.retry_mid_stream = retry;
+        self
+    }
+
+    pub fn all_hosts_down(mut self, all_hosts_down: AllHostsDown) -> Self {
+        self.config.all_hosts_down = all_hosts_down;
+        self
+    }
+
+    pub fn host_removal(mut self, host_removal: HostRemoval) -> Self {
+        self.config.host_removal = host_removal;
+        self
+    }
+}
+
+pub struct LoadBalancer {
+    /**
+        Holds client counts for all threads
+    */
+    client_counts: ClientCounts,
+    /**
+        Newly added clients are added here, threads will add them to polling when they can
+    */
+    client_lists_pending: PendingClientLists,
+    /**
+        Receiving ends of [client_lists_pending], taken by threads when they are spawned (receivers are not [Sync], hence the mutex)
+    */
+    pending_receivers: Mutex<Vec<Receiver<TcpClient>>>,
+    threads: u16,
+    connection_timeout: Duration,
+    total_connection_timeout: Duration,
+    max_connection_attempts: u32,
+    idle_timeout: Option<Duration>,
+    /**
+        How long idle connections to hosts are pooled for reuse by other clients, pooling is disabled when [None]
+    */
+    connection_pool_idle: Option<Duration>,
+    max_connections: usize,
+    /**
+        What happens to clients above [max_connections] or when every host is at its connection limit
+    */
+    connection_limit_action: ConnectionLimitAction,
+    connection_limit_response: Option<Arc<[u8]>>,
+    max_pending_clients: usize,
+    pending_overflow: PendingOverflow,
+    /**
+        Highest number of clients any pending list held at once (for capacity planning)
+    */
+    max_pending_reached: Arc<AtomicUsize>,
+    /**
+        Number of events polled for tokens whose clients were already removed (by all threads)
+    */
+    stale_events: Arc<AtomicUsize>,
+    max_connections_per_ip: usize,
+    access_list: Arc<AccessList>,
+    proxy_protocol: bool,
+    /**
+        Clients are expected to start with a PROXY protocol header, the address from it is used instead of the peer address
+    */
+    accept_proxy_protocol: bool,
+    /**
+        Hosts are connected to from client IPs, instead of the IP of the balancer
+    */
+    transparent_proxy: bool,
+    tls_config: Option<Arc<ServerConfig>>,
+    ip_counts: IpConnectionCounts,
+    buffer_size: usize,
+    socket_options: SocketOptions,
+    metrics_port: u16,
+    /**
+        Port of the HTTP API for controlling the balancer at runtime, disabled when 0
+    */
+    api_port: u16,
+    stopped: Arc<RwLock<bool>>,
+    /**
+        Balancing algorithm of every pool of hosts
+    */
+    pools: SharedPools,
+    /**
+        Hosts the pools were created from, needed to resolve hosts given as domain names again
+    */
+    host_manager: SharedHostManager,
+    dns_refresh: Option<Duration>,
+    /**
+        Interval between health checks of all hosts, hosts are only checked by client traffic when [None]
+    */
+    health_check_interval: Option<Duration>,
+    health_check_timeout: Duration,
+    health_check: HealthCheck,
+    health_check_path: String,
+    /**
+        How established connections to hosts removed by [update_hosts] are handled
+    */
+    host_removal: HostRemoval,
+    all_hosts_down: AllHostsDown,
+    retry_mid_stream: bool,
+    routing: Routing,
+    forwarded_headers: bool,
+    /**
+        Backup pool of the default pool, it comes from the config, so it's set again every time hosts are updated
+    */
+    fallback_pool: Option<String>,
+    access_log: AccessLog,
+    access_log_writer: Option<AccessLogWriter>,
+    stats: Arc<StatsRegistry>,
+    /**
+        Total bytes forwarded by all clients
+    */
+    throughput: Arc<Throughput>,
+    /**
+        Bytes forwarded by clients of every worker thread (indexed by thread)
+    */
+    thread_throughput: Arc<Vec<Arc<Throughput>>>,
+    /**
+        Wakers of worker threads (indexed by thread), used to make threads pick up pending clients immediately
+    */
+    wakers: Arc<Vec<Waker>>,
+    /**
+        Clients are moved between worker threads when their client counts differ by more than this, disabled when 0
+    */
+    rebalance_threshold: usize,
+    /**
+        Waker of the poll that is accepting clients, woken when balancer is stopped
+    */
+    listener_waker: Option<Waker>,
+}
+
+impl LoadBalancer {
+    /**
+        Creates a round robin balancer for given hosts, other options use their default values
+    */
+    pub fn new(host_manager: HostManager, threads: u16, debug: bool) -> Self {
+        LoadBalancer::builder()
+            .threads(threads)
+            .debug(debug)
+            .build(host_manager, Algorithm::RoundRobin.factory(Config::default().virtual_nodes))
+    }
+
+    /**
+        Creates a balancer for given hosts, [algorithm] is used to create the balancing algorithm for every pool of hosts
+    */
+    pub fn with_config(
+        host_manager: HostManager,
+        algorithm: AlgorithmFactory,
+        access_list: AccessList,
+        tls_config: Option<Arc<ServerConfig>>,
+        config: &Config,
+    ) -> Self {
+        LoadBalancer::builder()
+            .config(config.clone())
+            .access_list(access_list)
+            .tls_config(tls_config)
+            .build(host_manager, algorithm)
+    }
+
+    /**
+        Returns a builder for the balancer, options that are not set use their default values (same as missing config keys)
+    */
+    pub fn builder() -> LoadBalancerBuilder {
+        LoadBalancerBuilder {
+            config: Config::default(),
+            access_list: AccessList::allow_all(),
+            tls_config: None,
+        }
     }
 
     pub fn start(&mut self) {
         self.spawn_threads();
+
+        if let Some(interval) = self.health_check_interval {
+            self.spawn_health_checker(interval);
+        }
+
+        if let Some(interval) = self.dns_refresh {
+            let resolver = DnsResolver::new(Arc::clone(&self.host_manager), Arc::clone(&self.pools));
+            resolver.spawn(interval, Arc::clone(&self.stopped));
+        }
+
+        if self.metrics_port > 0 {
+            let metrics = MetricsServer::new(
+                Arc::clone(&self.client_counts),
+                Arc::clone(&self.pools),
+                Arc::clone(&self.stats),
+                Arc::clone(&self.throughput),
+                Arc::clone(&self.thread_throughput),
+                Arc::clone(&self.max_pending_reached),
+            );
+            metrics.spawn(self.metrics_port, Arc::clone(&self.stopped)).unwrap_or_else(|e| {
+                error!("[Metrics] Failed to start metrics server on port {}! {}", self.metrics_port, e);
+            });
+        }
+
+        if self.api_port > 0 {
+            let api = AdminApi::new(Arc::clone(&self.host_manager), Arc::clone(&self.pools), self.stats_handle());
+            api.spawn(self.api_port, Arc::clone(&self.stopped)).unwrap_or_else(|e| {
+                error!("[API] Failed to start API server on port {}! {}", self.api_port, e);
+            });
+        }
     }
 
-    pub fn add_client(&mut self, stream: TcpStream) {
-        let client = TcpClient::new(stream);
+    /**
+        Hands accepted client over to the least busy worker thread, client uses the given pool unless it's routed to another one
+    */
+    pub fn add_client(&self, stream: Stream, pool: Option<&str>) {
+        // reject client right away if connection limit is reached
+        if self.max_connections > 0 && self.get_client_count() >= self.max_connections {
+            let addr = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            warn!("[Listener] Connection limit ({}) reached, rejecting client {}", self.max_connections, addr);
+
+            // TLS session was not started yet, so the response can only be sent to plain clients
+            if let Some(response) = self.connection_limit_response.as_ref().filter(|_| self.tls_config.is_none()) {
+                (&stream).write_all(response).unwrap_or(());
+            }
+            stream.shutdown(Shutdown::Both).unwrap_or(());
+            return;
+        }
+
+        let mut client = TcpClient::new(stream, self.socket_options);
+
+        if self.proxy_protocol {
+            client.enable_proxy_protocol();
+        }
+
+        if self.transparent_proxy {
+            client.enable_transparent_proxy();
+        }
+
+        client.use_pool(pool);
+        client.enable_routing(self.routing);
+        if self.forwarded_headers {
+            client.enable_forwarded_headers();
+        }
+
+        if let Some(tls_config) = &self.tls_config {
+            if let Err(e) = client.enable_tls(Arc::clone(tls_config)) {
+                warn!("[Listener] Failed to start TLS session for client {}! {}", client.address, e);
+                return;
+            }
+        }
+
+        // address of clients behind a proxy is only known once their PROXY protocol header is read, they are checked then
+        if self.accept_proxy_protocol {
+            client.expect_proxy_header();
+        } else if !LoadBalancer::admit_client(&mut client, &self.access_list, &self.ip_counts, self.max_connections_per_ip, &self.stats) {
+            // dropping the client closes it
+            return;
+        }
+
+        if self.max_connection_attempts > 0 {
+            client.limit_connection_attempts(self.max_connection_attempts);
+        }
+
+        if self.all_hosts_down == AllHostsDown::Retry {
+            client.retry_when_hosts_down();
+        }
+
+        if !self.retry_mid_stream {
+            client.retry_only_failed_connects();
+        }
+
+        if self.connection_limit_action == ConnectionLimitAction::Queue {
+            client.queue_when_hosts_full();
+        }
+
+        if let Some(response) = &self.connection_limit_response {
+            client.set_limit_response(Arc::clone(response));
+        }
+
+        client.track_throughput(Arc::clone(&self.throughput));
 
-        // pick client list with least clients and add it to pending list
-        let client_counts = self.client_counts.read().unwrap();
-        let client_lists_pending = self.client_lists_pending.read().unwrap();
+        if self.connection_pool_idle.is_some() {
+            client.enable_target_pooling();
+        }
 
-        // find client list with least clients first
+        // find client list with least clients first (counting clients not yet picked up, so bursts are spread too)
+        let get_length = |i: usize| self.client_counts[i].load(Ordering::Relaxed) + self.client_lists_pending[i].len();
         let mut min_index = 0;
-        let mut min_length = *client_counts[0].read().unwrap();
-        for i in 1..client_counts.len() {
-            let len = *client_counts[i].read().unwrap();
+        let mut min_length = get_length(0);
+        for i in 1..self.client_counts.len() {
+            let len = get_length(i);
             if len < min_length {
                 min_length = len;
                 min_index = i;
             }
         }
 
-        if *self.debug.read().unwrap() {
-            println!("[Thread {}] Connected from {}", min_index, client.address);
+        let pending = &self.client_lists_pending[min_index];
+
+        // reject client if even the least busy thread can't keep up with picking up new clients (dropping the client closes it)
+        if self.max_pending_clients > 0 && pending.len() >= self.max_pending_clients {
+            match self.pending_overflow {
+                PendingOverflow::Reject | PendingOverflow::Reset => {
+                    warn!(
+                        "[Listener] Pending client limit ({}) reached, rejecting client {}",
+                        self.max_pending_clients, client.address
+                    );
+                    if self.pending_overflow == PendingOverflow::Reset {
+                        client.reset_connection();
+                    }
+                    return;
+                }
+                PendingOverflow::DropOldest => {
+                    warn!(
+                        "[Listener] Pending client limit ({}) reached, dropping oldest pending client of thread {}",
+                        self.max_pending_clients, min_index
+                    );
+                    pending.shed_oldest();
+                }
+            }
         }
 
+        // only clients that were not rejected are logged
+        client.enable_access_log(self.access_log, self.access_log_writer.clone());
+
+        debug!("[Thread {}] Connected from {}", min_index, client.address);
+
         // add client to pending list
-        client_lists_pending[min_index].write().unwrap().push(client);
+        self.stats.record_accepted();
+        let len = pending.push(client);
+        self.max_pending_reached.fetch_max(len, Ordering::Relaxed);
+
+        // let the thread know it has a new client, instead of waiting for its poll to time out
+        self.wake_up(min_index);
+    }
+
+    /**
+        Returns [false] if client's IP is not allowed or already has too many connections, otherwise client is counted
+        in the connections of its IP. Clients without an IP (connected through Unix domain sockets) are always admitted
+    */
+    fn admit_client(
+        client: &mut TcpClient,
+        access_list: &AccessList,
+        ip_counts: &IpConnectionCounts,
+        max_connections_per_ip: usize,
+        s: &StatsRegistry,
+    ) -> bool {
+        if !client.has_ip() {
+            return true;
+        }
+
+        if !access_list.is_allowed(client.address.ip()) {
+            debug!("[Listener] Client {} is not allowed to connect, rejecting it", client.address);
+            s.record_denied();
+            return false;
+        }
+
+        #[cfg(feature = "geoip")]
+        if let Some(pool) = access_list.get_country_pool(client.address.ip()) {
+            client.use_country_pool(pool);
+        }
+
+        if max_connections_per_ip > 0 {
+            let count = ip_counts.read().unwrap().get(&client.address.ip()).copied().unwrap_or(0);
+            if count >= max_connections_per_ip {
+                debug!(
+                    "[Listener] Connection limit per IP ({}) reached, rejecting client {}",
+                    max_connections_per_ip, client.address
+                );
+                return false;
+            }
+
+            client.track_ip(Arc::clone(ip_counts));
+        }
+
+        true
     }
 
-    pub fn stop(&mut self) {
+    /**
+        Wakes the worker thread from polling, so it processes its pending clients immediately
+    */
+    pub fn wake_up(&self, thread: usize) {
+        if let Some(waker) = self.wakers.get(thread) {
+            waker.wake().unwrap_or_else(|e| error!("[Thread {}] Failed to wake up thread! {}", thread, e));
+        }
+    }
+
+    /**
+        Registers a waker with the poll that is accepting clients, it is woken with given token when balancer is stopped
+    */
+    pub fn register_poll(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        self.listener_waker = Some(Waker::new(registry, token)?);
+        Ok(())
+    }
+
+    /**
+        Returns number of clients connected to every worker thread (indexed by thread)
+    */
+    pub fn connections_per_thread(&self) -> Vec<usize> {
+        self.client_counts.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+    }
+
+    /**
+        Returns number of clients connected to all worker threads
+    */
+    pub fn total_connections(&self) -> usize {
+        self.connections_per_thread().iter().sum()
+    }
+
+    /**
+        Returns the highest number of clients that were waiting to be picked up by a single thread at once
+    */
+    pub fn max_pending_reached(&self) -> usize {
+        self.max_pending_reached.load(Ordering::Relaxed)
+    }
+
+    /**
+        Returns how many events were polled for clients that were already removed, it only grows while clients are being removed.
+        Growing without clients closing would mean a removed stream is still registered
+    */
+    pub fn stale_events(&self) -> usize {
+        self.stale_events.load(Ordering::Relaxed)
+    }
+
+    /**
+        Returns [true] if [max_connections] is reached and clients above it are queued, they should be left in the listen backlog
+    */
+    pub fn is_queueing_clients(&self) -> bool {
+        self.connection_limit_action == ConnectionLimitAction::Queue && self.max_connections > 0 && self.get_client_count() >= self.max_connections
+    }
+
+    /**
+        Returns number of all clients, including the ones not yet picked up by their threads
+    */
+    pub fn get_client_count(&self) -> usize {
+        let connected = self.total_connections();
+        let pending: usize = self.client_lists_pending.iter().map(|p| p.len()).sum();
+        connected + pending
+    }
+
+    /**
+        Logs a snapshot of client counts, hosts of every pool (with their cooldowns) and forwarded bytes.
+        Only read locks are taken, so workers are not disturbed
+    */
+    pub fn dump_state(&self) {
+        let counts = self.connections_per_thread();
+        let pending: usize = self.client_lists_pending.iter().map(|p| p.len()).sum();
+        let total = counts.iter().sum::<usize>() + pending;
+        let counts: Vec<String> = counts.iter().map(|c| c.to_string()).collect();
+        info!(
+            "[Dump] clients={} pending={} max_pending={} per_thread={}",
+            total,
+            pending,
+            self.max_pending_reached.load(Ordering::Relaxed),
+            counts.join(",")
+        );
+
+        for (name, algorithm) in self.pools.read().unwrap().all_named() {
+            let algorithm = algorithm.read().unwrap();
+            for host in algorithm.get_hosts() {
+                let cooldown = match algorithm.get_cooldown_remaining(&host) {
+                    Some(remaining) => format!("{:.1}s", remaining.as_secs_f64()),
+                    None => "none".to_string(),
+                };
+                info!("[Dump] pool={} host={} cooldown={}", name.as_deref().unwrap_or("default"), host, cooldown);
+            }
+        }
+
+        let throughput = self.throughput.snapshot();
+        info!(
+            "[Dump] bytes_to_targets={} bytes_from_targets={}",
+            throughput.bytes_to_targets, throughput.bytes_from_targets
+        );
+        for (thread, throughput) in self.thread_throughput().iter().enumerate() {
+            info!(
+                "[Dump] thread={} bytes_to_targets={} bytes_from_targets={}",
+                thread, throughput.bytes_to_targets, throughput.bytes_from_targets
+            );
+        }
+        for stats in self.host_stats() {
+            info!(
+                "[Dump] host={} active={} draining={} bytes_to_host={} bytes_from_host={}",
+                stats.host, stats.active_connections, stats.draining, stats.throughput.bytes_to_targets, stats.throughput.bytes_from_targets
+            );
+        }
+    }
+
+    /**
+        Returns [true] if the host file has a pool with given name
+    */
+    pub fn has_pool(&self, name: &str) -> bool {
+        self.pools.read().unwrap().contains(name)
+    }
+
+    /**
+        Returns [true] if at least one host (of any pool) is not on cooldown and can be picked for new clients
+    */
+    pub fn is_ready(&self) -> bool {
+        let pools = self.pools.read().unwrap();
+        pools.all().iter().any(|b| {
+            let algorithm = b.read().unwrap();
+            algorithm.get_hosts().iter().any(|h| !algorithm.is_on_cooldown(h) && !pools.is_draining(h))
+        })
+    }
+
+    /**
+        Swaps the hosts used by the balancing algorithm. Established connections to removed hosts are drained or closed
+        by the worker threads, depending on [host_removal]
+    */
+    pub fn update_hosts(&self, mut host_manager: HostManager) {
+        LoadBalancer::check_connect_timeouts(&host_manager, self.total_connection_timeout);
+        LoadBalancer::set_fallback_pool(&mut host_manager, self.fallback_pool.clone());
+
+        // lock is held while updating the pools, so the DNS resolver can't overwrite the new hosts with old ones
+        let mut current = self.host_manager.write().unwrap();
+        *current = host_manager.clone();
+        self.pools.write().unwrap().update_hosts(host_manager);
+        drop(current);
+
+        // connections to removed hosts are handled by the threads right away
+        for thread in 0..self.wakers.len() {
+            self.wake_up(thread);
+        }
+    }
+
+    /**
+        Adds host to the default pool without reloading the host file, returns [false] if nothing changed
+    */
+    pub fn add_host(&self, addr: Endpoint) -> bool {
+        // hosts are updated the same way, so the DNS resolver doesn't bring back the old hosts
+        let mut current = self.host_manager.write().unwrap();
+        let added = self.pools.read().unwrap().get(None).write().unwrap().add_host(addr.clone());
+        if added {
+            current.add_host(addr);
+        }
+        added
+    }
+
+    /**
+        Removes host from the default pool, returns [false] if nothing changed. Established connections are not affected
+    */
+    pub fn remove_host(&self, addr: &Endpoint) -> bool {
+        let mut current = self.host_manager.write().unwrap();
+        let removed = self.pools.read().unwrap().get(None).write().unwrap().remove_host(addr);
+        if removed {
+            current.remove_host(addr);
+        }
+        removed
+    }
+
+    /**
+        Returns a snapshot of statistics for all hosts
+    */
+    pub fn host_stats(&self) -> Vec<HostStats> {
+        self.stats.snapshot(&self.pools.read().unwrap())
+    }
+
+    /**
+        Returns a snapshot of worker threads, totals and hosts of the balancer
+    */
+    pub fn stats(&self) -> BalancerStats {
+        self.stats_handle().snapshot()
+    }
+
+    /**
+        Returns a handle for taking snapshots of the balancer from other threads, e.g. while the [Poller] owns the balancer
+    */
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            client_counts: Arc::clone(&self.client_counts),
+            client_lists_pending: Arc::clone(&self.client_lists_pending),
+            pools: Arc::clone(&self.pools),
+            stats: Arc::clone(&self.stats),
+            throughput: Arc::clone(&self.throughput),
+            thread_throughput: Arc::clone(&self.thread_throughput),
+        }
+    }
+
+    /**
+        Returns total bytes forwarded between clients and targets since the balancer was created
+    */
+    pub fn throughput(&self) -> ThroughputStats {
+        self.throughput.snapshot()
+    }
+
+    /**
+        Returns bytes forwarded by clients of every worker thread (indexed by thread), clients moved between threads
+        count towards the thread that forwarded the bytes
+    */
+    pub fn thread_throughput(&self) -> Vec<ThroughputStats> {
+        self.thread_throughput.iter().map(|t| t.snapshot()).collect()
+    }
+
+    pub fn stop(&self) {
         *self.stopped.write().unwrap() = true;
+
+        // wake everyone up, so they notice the balancer was stopped
+        for thread in 0..self.wakers.len() {
+            self.wake_up(thread);
+        }
+        if let Some(waker) = &self.listener_waker {
+            waker.wake().unwrap_or(());
+        }
+    }
+
+    /**
+        Warns about hosts with connect timeout longer than the total connection timeout, clients would give up before it passes
+    */
+    fn check_connect_timeouts(host_manager: &HostManager, total_connection_timeout: Duration) {
+        for pool in std::iter::once(host_manager).chain(host_manager.pools.iter().map(|(_, p)| p)) {
+            for (host, timeout) in pool.hosts.iter().zip(&pool.connect_timeouts) {
+                match timeout {
+                    Some(t) if *t > total_connection_timeout => warn!(
+                        "[Parser] Connect timeout of host {} ({}ms) exceeds the total connection timeout ({}ms)",
+                        host,
+                        t.as_millis(),
+                        total_connection_timeout.as_millis()
+                    ),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Drops country pools that are not in the host file, [default] pool is dropped too (clients use it anyway)
+    #[cfg(feature = "geoip")]
+    fn check_country_pools(host_manager: &HostManager, pools: &[(String, String)]) -> Vec<(String, String)> {
+        pools
+            .iter()
+            .filter(|(country, pool)| match pool.as_str() {
+                "default" => false,
+                p if !host_manager.pools.iter().any(|(n, _)| n == p) => {
+                    warn!("[Config] Pool '{}' of country {} is not in the host file, ignoring it", p, country);
+                    false
+                }
+                _ => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /**
+        Returns [false] if transparent proxy can't be used here, instead of failing every connection to hosts later
+    */
+    fn check_transparent_proxy() -> bool {
+        #[cfg(target_os = "linux")]
+        match Stream::check_transparent() {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("[Config] Transparent proxy needs CAP_NET_ADMIN, disabling it -> {}", e);
+                false
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            warn!("[Config] Transparent proxy is only supported on Linux, disabling it");
+            false
+        }
+    }
+
+    /// Sets the backup pool of the default pool, pools that are not in the host file are ignored
+    fn set_fallback_pool(host_manager: &mut HostManager, fallback: Option<String>) {
+        host_manager.fallback = match fallback {
+            Some(f) if !host_manager.pools.iter().any(|(n, _)| *n == f) => {
+                warn!("[Config] Fallback pool '{}' is not in the host file, ignoring it", f);
+                None
+            }
+            f => f,
+        };
     }
 
     fn spawn_threads(&mut self) {
         let th = self.threads as u32;
 
+        // polls are created first, so every thread can wake up the others (e.g. when moving clients to them)
+        let mut polls = vec![];
+        let mut wakers = vec![];
+        for _ in 0..th {
+            let poll = Poll::new().unwrap();
+            wakers.push(Waker::new(poll.registry(), WAKE_TOKEN).unwrap());
+            polls.push(poll);
+        }
+        self.wakers = Arc::new(wakers);
+
         // WORKERS
-        for id in 0..th {
+        let receivers = std::mem::take(self.pending_receivers.get_mut().unwrap());
+        for (id, (mut poll, receiver)) in polls.into_iter().zip(receivers).enumerate() {
+            let id = id as u32;
+            let wakers = Arc::clone(&self.wakers);
+            let rebalance_threshold = self.rebalance_threshold;
+            let host_removal = self.host_removal;
             let stopped = Arc::clone(&self.stopped);
-            let d = Arc::clone(&self.debug);
-            let b = Arc::clone(&self.balancing_algorithm);
+            let pools = Arc::clone(&self.pools);
+            let s = Arc::clone(&self.stats);
+            let throughput = Arc::clone(&self.thread_throughput[id as usize]);
             let client_counts = Arc::clone(&self.client_counts);
             let client_list_pending = Arc::clone(&self.client_lists_pending);
+            let connection_timeout = self.connection_timeout;
+            let total_connection_timeout = self.total_connection_timeout;
+            let idle_timeout = self.idle_timeout;
+            let connection_pool_idle = self.connection_pool_idle;
+            let access_list = Arc::clone(&self.access_list);
+            let ip_counts = Arc::clone(&self.ip_counts);
+            let max_connections_per_ip = self.max_connections_per_ip;
+            let buffer_size = self.buffer_size;
+            let stale_events = Arc::clone(&self.stale_events);
 
             thread::spawn(move || {
                 let mut connected_sockets: HashMap<Token, TcpClient> = HashMap::new();
-                let mut next_token_id: usize = 0;
+                let mut tokens = TokenAllocator::new();
 
-                let mut get_next_token = || {
-                    let token = Token(next_token_id);
-                    next_token_id += 1;
-                    if next_token_id >= usize::MAX {
-                        next_token_id = 1;
-                    }
-                    token
-                };
+                // data read from a client (or its target) is copied out right away, so all clients can share one buffer
+                let mut buffer = vec![0; buffer_size].into_boxed_slice();
 
                 let client_list_index = id as usize;
 
-                let mut poll = Poll::new().unwrap();
                 let mut events = Events::with_capacity(1024);
+                let mut next_rebalance = Instant::now() + REBALANCE_INTERVAL;
+                let mut removed_generation = 0;
+
+                // clients only release target connections when pooling is enabled, otherwise the pool stays empty
+                let mut target_pool = TargetPool::new(connection_pool_idle.unwrap_or_default());
+
+                // how long the next poll can sleep for if nothing happens, [None] sleeps until woken up
+                let mut poll_timeout = Some(Duration::ZERO);
+
+                // clients that stopped forwarding at the limit of reads per event, they are processed again without an event
+                let mut unfinished: Vec<Token> = vec![];
 
                 loop {
                     // keep checking if balancer has been stopped
@@ -137,14 +1298,13 @@ impl LoadBalancer {
                     // -------------------------------
                     // EVENT POLLING
                     // -------------------------------
-                    match poll.poll(&mut events, Some(Duration::from_millis(10))) {
+                    match poll.poll(&mut events, poll_timeout) {
                         Ok(_) => {}
                         Err(ref e) if e.kind() == ErrorKind::Interrupted => {
-                            // this handler does not get called on Windows, so we use timeout and check it outside
-                            *stopped.write().unwrap() = true;
+                            // polling can be interrupted by any signal (e.g. SIGHUP), stopping is handled through [stopped]
                         }
                         Err(e) => {
-                            println!("[Thread {}] Failed to poll for events! {}", id, e.to_string());
+                            error!("[Thread {}] Failed to poll for events! {}", id, e);
                             break;
                         }
                     };
@@ -153,35 +1313,77 @@ impl LoadBalancer {
                     // PROCESS PENDING CLIENTS
                     // -------------------------------
                     {
-                        // check if any pending clients (try to read to avoid blocking)
-                        let r: i32 = match client_list_pending.read().unwrap()[client_list_index].try_read() {
-                            Ok(r) => r.len() as i32,
-                            Err(_) => -1,
-                        };
-                        if r > 0 {
-                            let p_list = &*client_list_pending.read().unwrap()[client_list_index];
+                        // move all pending clients over to our client_list and register them with poll
+                        let pending = &client_list_pending[client_list_index];
+                        let mut picked_up = false;
+                        while let Some(mut client) = pending.take(&receiver) {
+                            // only new clients are dropped, clients moved from other threads could already be forwarding data
+                            if !client.is_connected() && !client.is_connecting() && pending.take_shed() {
+                                debug!("[Thread {}] Dropping pending client ({}) over the pending client limit", id, client.address);
+                                client.close_connection(CloseReason::ConnectionLimit);
+                                continue;
+                            }
+
+                            let token = tokens.allocate();
+
+                            // clients moved from other threads can already be connected to their target
+                            client.register_with_poll(poll.registry(), token);
+                            client.track_thread_throughput(Arc::clone(&throughput));
+
+                            // insert into hashmap for quick lookup
+                            connected_sockets.insert(token, client);
+                            picked_up = true;
+                        }
 
-                            let pending = &mut *match p_list.try_write() {
-                                Ok(w) => w,
-                                Err(_) => continue,
-                            };
+                        pending.clear_shed();
 
-                            // move all pending clients over to our client_list and register them with poll
-                            let plen = pending.len();
-                            for i in 0..plen {
-                                let index = (plen - 1) - i;
-                                let mut client = pending.remove(index);
+                        if picked_up {
+                            // update count
+                            client_counts[client_list_index].store(connected_sockets.len(), Ordering::Relaxed);
+                        }
+                    }
+
+                    // -------------------------------
+                    // REMOVED HOSTS (draining connections to hosts removed by reloading)
+                    // -------------------------------
+                    {
+                        let p = pools.read().unwrap();
+                        let (generation, removed) = p.get_removed_hosts();
+                        if generation != removed_generation {
+                            removed_generation = generation;
+
+                            for host in removed {
+                                target_pool.remove_host(host);
+                            }
 
-                                let token = get_next_token();
+                            let mut counts: HashMap<Endpoint, usize> = HashMap::new();
+                            for (token, client) in &mut connected_sockets {
+                                let target = match client.get_target_addr() {
+                                    Some(t) if removed.contains(&t) => t,
+                                    _ => continue,
+                                };
 
-                                poll.registry().register(&mut client.stream, token, Interest::READABLE).unwrap();
+                                if client.is_connecting() {
+                                    // nothing was forwarded yet, so client can simply use another host
+                                    client.close_connection_to_target(false);
+                                    LoadBalancer::start_connection(id, *token, client, &poll, &p, Arc::clone(&s), &mut target_pool);
+                                    continue;
+                                }
 
-                                // insert into hashmap for quick lookup
-                                connected_sockets.insert(token, client);
+                                // closed client is removed on the next check (target is closed first, so it's not pooled)
+                                if host_removal == HostRemoval::Immediate {
+                                    client.close_connection_to_target(false);
+                                    client.close_connection(CloseReason::HostRemoved);
+                                }
+                                *counts.entry(target).or_insert(0) += 1;
                             }
 
-                            // update count
-                            *client_counts.read().unwrap()[client_list_index].write().unwrap() = connected_sockets.len();
+                            for (host, count) in counts {
+                                match host_removal {
+                                    HostRemoval::Graceful => info!("[Thread {}] Draining {} connections to removed host {}", id, count, host),
+                                    HostRemoval::Immediate => info!("[Thread {}] Closed {} connections to removed host {}", id, count, host),
+                                }
+                            }
                         }
                     }
 
@@ -191,66 +1393,177 @@ impl LoadBalancer {
                     {
                         // check for connecting clients for time outs and their current state
                         let mut tokens_to_remove: Vec<Box<Token>> = vec![];
+                        let mut next_check: Option<Instant> = None;
                         for (token, client) in &mut connected_sockets {
+                            let b = pools.read().unwrap().get(client.get_route());
+
+                            // report any closed target connections back to the balancing algorithm
+                            LoadBalancer::report_target_disconnect(client, &pools.read().unwrap(), &s);
+
                             // if client not connected, schedule for removal
                             if !client.is_client_connected() {
-                                let t = Box::new(token.clone());
+                                let t = Box::new(*token);
                                 tokens_to_remove.push(t);
                                 continue;
                             }
 
+                            // remember when the client has to be checked again, so the thread can sleep until then
+                            let deadline =
+                                LoadBalancer::get_check_deadline(client, &pools.read().unwrap(), connection_timeout, total_connection_timeout, idle_timeout);
+                            next_check = next_check.into_iter().chain(deadline).min();
+
+                            // HANDLE ROUTING TIMEOUT (client did not send enough data to be routed)
+                            if !client.is_routed() && client.last_connection_loss.elapsed() > total_connection_timeout {
+                                debug!("[Thread {}] Timed out while routing ({})", id, client.address);
+
+                                client.close_connection(CloseReason::RoutingTimeout);
+                                continue;
+                            }
+
+                            // HANDLE CLIENTS WAITING FOR A HOST (every host was at its connection limit)
+                            if let Some(waiting) = client.get_waiting_time().filter(|_| !client.is_connecting() && !client.is_connected()) {
+                                if waiting > total_connection_timeout {
+                                    debug!("[Thread {}] Timed out while waiting for a host ({})", id, client.address);
+                                    client.reject();
+                                } else {
+                                    LoadBalancer::start_connection(id, *token, client, &poll, &pools.read().unwrap(), Arc::clone(&s), &mut target_pool);
+                                }
+                                continue;
+                            }
+
+                            // HANDLE IDLE TIMEOUT (established connections with no traffic)
+                            if let Some(idle) = idle_timeout {
+                                if client.is_connected() && client.last_activity.elapsed() > idle {
+                                    debug!("[Thread {}] Idle timeout ({})", id, client.address);
+
+                                    // will be removed on next check
+                                    client.close_connection(CloseReason::IdleTimeout);
+                                    continue;
+                                }
+                            }
+
                             // if client not in IN_CONNECTING state, we can't check for time outs
                             if !client.is_connecting() {
                                 continue;
                             }
 
-                            // HANDLE TIMEOUT TO SINGLE TARGET
-                            if client.started_connecting.elapsed() > CONNECTION_TIMEOUT {
-                                if *d.read().unwrap() {
-                                    println!(
-                                        "[Thread {}] Connection to target timed out ({} <-> {})",
-                                        id,
-                                        client.address,
-                                        client.get_target_addr().unwrap()
-                                    );
-                                }
+                            // HANDLE TIMEOUT TO SINGLE TARGET (host can override the global timeout)
+                            let target = client.get_target_addr().unwrap();
+                            let timeout = pools.read().unwrap().get_connect_timeout(&target).unwrap_or(connection_timeout);
+                            if client.started_connecting.elapsed() > timeout {
+                                debug!("[Thread {}] Connection to target timed out ({} <-> {})", id, client.address, target);
 
                                 // we timed out! Let's try another host
                                 client.close_connection_to_target(true);
-                                LoadBalancer::report_target_error(client, Arc::clone(&b));
-                                LoadBalancer::start_connection(id, token.clone(), client, &poll, Arc::clone(&d), Arc::clone(&b));
+                                LoadBalancer::report_target_error(client, Arc::clone(&b), Arc::clone(&s));
+                                LoadBalancer::start_connection(id, *token, client, &poll, &pools.read().unwrap(), Arc::clone(&s), &mut target_pool);
                             }
 
                             // HANDLE TOTAL TIMEOUT
-                            if client.last_connection_loss.elapsed() > TOTAL_CONNECTION_TIMEOUT {
-                                if *d.read().unwrap() {
-                                    println!("[Thread {}] Timed out ({})", id, client.address);
-                                }
+                            if client.last_connection_loss.elapsed() > total_connection_timeout {
+                                debug!("[Thread {}] Timed out ({})", id, client.address);
 
                                 // we timed out completely!
-                                client.close_connection();
+                                client.close_connection(CloseReason::ConnectTimeout);
                             }
                         }
 
+                        target_pool.remove_expired();
+
+                        // timeouts are only reached once more time than the timeout has elapsed, hence the extra millisecond
+                        let now = Instant::now();
+                        poll_timeout = if connected_sockets.is_empty() && target_pool.is_empty() {
+                            None
+                        } else {
+                            let next_check = next_check.map_or(now + HOUSEKEEPING_INTERVAL, |c| c.min(now + HOUSEKEEPING_INTERVAL));
+                            Some(next_check.saturating_duration_since(now) + Duration::from_millis(1))
+                        };
+
                         // now remove the marked clients
-                        if tokens_to_remove.len() > 0 {
+                        if !tokens_to_remove.is_empty() {
                             for token in tokens_to_remove {
                                 let mut client = connected_sockets.remove(&token).unwrap();
-                                poll.registry().deregister(&mut client.stream).unwrap();
-
-                                if *d.read().unwrap() {
-                                    println!(
-                                        "[Thread {}] Connection ended ({}) [Remaining clients: {}]",
-                                        id,
-                                        client.address,
-                                        connected_sockets.len()
-                                    );
+
+                                // target stream is deregistered too (if still there), so nothing is left registered with the token
+                                client.deregister_from_poll(poll.registry());
+
+                                // healthy target connection is kept for the next client connecting to the same host
+                                if let Some((target, mut stream)) = client.take_released_target() {
+                                    poll.registry().deregister(&mut stream).unwrap_or(());
+                                    target_pool.put(target, stream);
                                 }
+
+                                // no events can arrive for the deregistered client anymore, so its token can be reused
+                                tokens.release(*token);
+
+                                let (sent, received) = client.get_bytes_forwarded();
+                                debug!(
+                                    "[Thread {}] Connection ended ({}, sent {} B, received {} B) [Remaining clients: {}]",
+                                    id,
+                                    client.address,
+                                    sent,
+                                    received,
+                                    connected_sockets.len()
+                                );
                             }
 
                             // update count
-                            *client_counts.read().unwrap()[client_list_index].write().unwrap() = connected_sockets.len();
+                            client_counts[client_list_index].store(connected_sockets.len(), Ordering::Relaxed);
+                        }
+                    }
+
+                    // -------------------------------
+                    // REBALANCING (moving clients to the least busy thread)
+                    // -------------------------------
+                    if rebalance_threshold > 0 && Instant::now() >= next_rebalance {
+                        next_rebalance = Instant::now() + REBALANCE_INTERVAL;
+
+                        let counts: Vec<usize> = client_counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+                        let (target_thread, min_count) = counts.iter().copied().enumerate().min_by_key(|(_, c)| *c).unwrap();
+                        let count = connected_sockets.len();
+
+                        if count > min_count + rebalance_threshold {
+                            // move half of the difference, so both threads end up with similar counts
+                            let moving: Vec<Token> = connected_sockets
+                                .iter()
+                                .filter(|(_, c)| c.is_client_connected())
+                                .map(|(t, _)| *t)
+                                .take((count - min_count) / 2)
+                                .collect();
+
+                            let pending = &client_list_pending[target_thread];
+                            for token in &moving {
+                                let mut client = connected_sockets.remove(token).unwrap();
+                                client.deregister_from_poll(poll.registry());
+                                tokens.release(*token);
+
+                                debug!("[Thread {}] Moving client ({}) to thread {}", id, client.address, target_thread);
+                                pending.push(client);
+                            }
+
+                            info!("[Thread {}] Moved {} clients to thread {}", id, moving.len(), target_thread);
+                            client_counts[client_list_index].store(connected_sockets.len(), Ordering::Relaxed);
+                            wakers[target_thread].wake().unwrap_or(());
+                        }
+                    }
+
+                    // -------------------------------
+                    // UNFINISHED CLIENTS (data left in sockets after the last event, no new event arrives for it)
+                    // -------------------------------
+                    for token in std::mem::take(&mut unfinished) {
+                        let client = match connected_sockets.get_mut(&token) {
+                            Some(c) if c.is_connected() => c,
+                            _ => continue,
+                        };
+
+                        LoadBalancer::process_client(client, &mut buffer, false, true, true, &pools.read().unwrap(), Arc::clone(&s));
+                        if client.take_unfinished() {
+                            unfinished.push(token);
                         }
+                        client.update_interests(poll.registry(), token);
+                    }
+                    if !unfinished.is_empty() {
+                        poll_timeout = Some(Duration::ZERO);
                     }
 
                     // ------------------------------
@@ -260,95 +1573,356 @@ impl LoadBalancer {
                         continue;
                     }
                     for event in events.iter() {
-                        match event.token() {
-                            token => {
-                                let client = match connected_sockets.get_mut(&token) {
-                                    Some(c) => c,
-                                    None => {
-                                        // println!("ERROR - Tried getting client that was not present in hash map! -> token: {:?}", token);
-                                        // TODO: maybe deregister from poll if this is ever even called
+                        let token = event.token();
+                        if token == WAKE_TOKEN {
+                            // pending clients were already processed above
+                            continue;
+                        }
+
+                        // target streams use the odd token following the token of their client
+                        let from_target = token.0 & 1 == 1;
+                        let token = Token(token.0 & !1);
+
+                        // closed streams and errors are handled by the next read (or write) failing
+                        let readable = event.is_readable() || event.is_read_closed() || event.is_error();
+                        let writable = event.is_writable() || event.is_write_closed() || event.is_error();
+
+                        let client = match connected_sockets.get_mut(&token) {
+                            Some(c) => c,
+                            None => {
+                                // streams are always deregistered before their token is released, so this can only be a stale
+                                // event polled before its client was removed above - nothing is registered with the token anymore
+                                stale_events.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
+                        };
+
+                        if !client.is_client_connected() {
+                            // ignore, will be handled in later loop and cleaned
+                            continue;
+                        }
+
+                        // data that could not be written before connecting to target (e.g. TLS handshake)
+                        if !from_target && writable && !client.is_connected() && !client.flush_to_client() {
+                            continue;
+                        }
+
+                        // clients behind a proxy are checked once their real address is known
+                        if !from_target && client.is_proxy_header_pending() {
+                            match client.read_proxy_header(&mut buffer) {
+                                Ok(true) => {
+                                    debug!("[Thread {}] Client address from PROXY protocol header: {}", id, client.address);
+                                    if !LoadBalancer::admit_client(client, &access_list, &ip_counts, max_connections_per_ip, &s) {
+                                        client.close_connection(CloseReason::Denied);
                                         continue;
                                     }
-                                };
-
-                                if !client.is_client_connected() {
-                                    // ignore, will be handled in later loop and cleaned
+                                }
+                                Ok(false) => continue,
+                                Err(e) => {
+                                    debug!("[Thread {}] Failed to read PROXY protocol header ({}) -> {}", id, client.address, e);
+                                    client.close_connection(CloseReason::InvalidProxyHeader);
                                     continue;
                                 }
+                            }
+                        }
 
-                                // if client is in process of connecting, check if connection has been established
-                                if client.is_connecting() {
-                                    LoadBalancer::try_confirm_connection(id, client, Arc::clone(&d), Arc::clone(&b));
-                                }
+                        // client has to be routed to a pool of hosts before connecting to any of them
+                        if !from_target && !client.is_routed() {
+                            match client.read_route(&mut buffer) {
+                                Ok(true) => {
+                                    // names without a pool of their own use the pool of the listener
+                                    match client.get_route().map(|r| pools.read().unwrap().find_route(r)) {
+                                        Some(Some(pool)) => client.set_route(pool),
+                                        Some(None) => client.use_listener_pool(),
+                                        None => {}
+                                    }
 
-                                // if connected, process it normally, otherwise start a new connection to next host
-                                if client.is_connected() {
-                                    LoadBalancer::process_client(client, Arc::clone(&b));
-                                } else if !client.is_connecting() {
-                                    LoadBalancer::start_connection(id, token, client, &poll, Arc::clone(&d), Arc::clone(&b));
+                                    if let Some(route) = client.get_route() {
+                                        debug!("[Thread {}] Routing client ({} -> {})", id, client.address, route);
+                                    }
+                                }
+                                Ok(false) => {
+                                    client.update_interests(poll.registry(), token);
+                                    continue;
                                 }
+                                Err(_) => {
+                                    // will be removed in later loop
+                                    client.close_connection(CloseReason::ClientError);
+                                    continue;
+                                }
+                            }
+                        }
+
+                        let b = pools.read().unwrap().get(client.get_route());
+
+                        // if client is in process of connecting, check if connection has been established
+                        let was_connecting = client.is_connecting();
+                        if was_connecting {
+                            LoadBalancer::try_confirm_connection(id, client, Arc::clone(&b), Arc::clone(&s));
+                        }
+
+                        // if connected, process it normally, otherwise start a new connection to next host
+                        if client.is_connected() {
+                            if was_connecting {
+                                // client data that arrived while connecting was not read yet, so both directions are processed
+                                LoadBalancer::process_client(client, &mut buffer, false, true, true, &pools.read().unwrap(), Arc::clone(&s));
+                            } else {
+                                LoadBalancer::process_client(client, &mut buffer, from_target, readable, writable, &pools.read().unwrap(), Arc::clone(&s));
                             }
+                        } else if !client.is_connecting() {
+                            LoadBalancer::start_connection(id, token, client, &poll, &pools.read().unwrap(), Arc::clone(&s), &mut target_pool);
                         }
+
+                        if client.take_unfinished() && !unfinished.contains(&token) {
+                            unfinished.push(token);
+                        }
+
+                        // writable interest is only kept while there is something to write
+                        client.update_interests(poll.registry(), token);
                     }
+
+                    // handling events can close clients or start connections, they are checked without waiting
+                    poll_timeout = Some(Duration::ZERO);
                 }
             });
         }
     }
 
-    fn try_confirm_connection(id: u32, client: &mut TcpClient, d: Arc<RwLock<bool>>, b: Arc<RwLock<RoundRobin>>) {
-        let server_connected = client.check_target_connected().unwrap_or_else(|e| {
-            println!("Not connected unknown error -> {}", e.to_string());
-            // TODO: should probably disconnect - there was an error while connecting other than NotConnected
-            false
+    fn spawn_health_checker(&mut self, interval: Duration) {
+        let timeout = self.health_check_timeout;
+        let check = self.health_check;
+        let path = self.health_check_path.clone();
+        let stopped = Arc::clone(&self.stopped);
+        let pools = Arc::clone(&self.pools);
+        let s = Arc::clone(&self.stats);
+
+        thread::spawn(move || {
+            let mut next_check = Instant::now();
+
+            loop {
+                // keep checking if balancer has been stopped
+                if *stopped.read().unwrap() {
+                    break;
+                }
+
+                // sleep in short intervals, so we can exit quickly when stopped
+                if Instant::now() < next_check {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                next_check = Instant::now() + interval;
+
+                // try connecting to every host (without holding the lock while connecting)
+                let (algorithms, hosts) = {
+                    let p = pools.read().unwrap();
+                    (p.all(), p.get_hosts())
+                };
+                for addr in hosts {
+                    let healthy = health::check_health(&addr, check, &path, timeout);
+
+                    // same host can be part of multiple pools
+                    let mut was_on_cooldown = false;
+                    for b in &algorithms {
+                        let mut algorithm = b.write().unwrap();
+                        if !algorithm.get_hosts().contains(&addr) {
+                            continue;
+                        }
+
+                        let on_cooldown = algorithm.is_on_cooldown(&addr);
+                        was_on_cooldown |= on_cooldown;
+                        if healthy && on_cooldown {
+                            algorithm.report_success(&addr);
+                        } else if !healthy {
+                            algorithm.report_error(&addr);
+                        }
+                    }
+
+                    if healthy && was_on_cooldown {
+                        info!("[Health] Host {} is back up", addr);
+                    } else if !healthy {
+                        if !was_on_cooldown {
+                            warn!("[Health] Host {} failed health check", addr);
+                        }
+                        s.record_error(&addr);
+                    }
+                }
+            }
         });
+    }
+
+    /**
+        Returns when the client has to be checked for its next timeout, [None] if it has no timeout to check
+        (or it's about to be removed)
+    */
+    fn get_check_deadline(
+        client: &TcpClient,
+        pools: &Pools,
+        connection_timeout: Duration,
+        total_connection_timeout: Duration,
+        idle_timeout: Option<Duration>,
+    ) -> Option<Instant> {
+        if !client.is_routed() {
+            return Some(client.last_connection_loss + total_connection_timeout);
+        }
+
+        if client.is_connecting() {
+            let target = client.get_target_addr()?;
+            let timeout = pools.get_connect_timeout(&target).unwrap_or(connection_timeout);
+            return Some((client.started_connecting + timeout).min(client.last_connection_loss + total_connection_timeout));
+        }
+
+        if client.is_connected() {
+            return idle_timeout.map(|i| client.last_activity + i);
+        }
+
+        // clients waiting for a host keep trying to connect
+        client.get_waiting_time().map(|_| Instant::now() + WAIT_RETRY_INTERVAL)
+    }
+
+    fn try_confirm_connection(id: u32, client: &mut TcpClient, b: SharedAlgorithm, s: Arc<StatsRegistry>) {
+        let server_connected = match client.check_target_connected() {
+            Ok(c) => c,
+            Err(e) => {
+                // target connection was closed, client connects to the next host
+                debug!("[Thread {}] Failed to connect to target ({}) -> {}", id, client.address, e);
+                LoadBalancer::report_target_error(client, Arc::clone(&b), Arc::clone(&s));
+                false
+            }
+        };
 
         if server_connected {
             let addr = client.get_target_addr().unwrap();
 
-            if *d.read().unwrap() && !client.is_connecting() {
-                println!("[Thread {}] Client connected to target ({} -> {})", id, client.address, addr);
+            if !client.is_connecting() {
+                debug!("[Thread {}] Client connected to target ({} -> {})", id, client.address, addr);
             }
 
             // report success if connection succeeded
-            if b.read().unwrap().is_on_cooldown(addr) {
-                b.write().unwrap().report_success(addr);
+            // success also clears errors of hosts that are not on cooldown
+            let mut algorithm = b.write().unwrap();
+            algorithm.report_success(&addr);
+
+            algorithm.report_connected(&addr);
+            // reused connections were established earlier, there is no connect latency to report
+            if !client.is_target_reused() {
+                algorithm.report_latency(&addr, client.started_connecting.elapsed());
             }
+            s.record_success(&addr);
+            s.record_connected(&addr);
         }
     }
 
-    fn process_client(client: &mut TcpClient, b: Arc<RwLock<RoundRobin>>) {
-        let success = client.process();
+    fn process_client(client: &mut TcpClient, buffer: &mut [u8], from_target: bool, readable: bool, writable: bool, pools: &Pools, s: Arc<StatsRegistry>) {
+        let success = client.process(buffer, from_target, readable, writable);
+        let b = pools.get(client.get_route());
 
-        if success == false {
+        if !success {
             // connection to either server or client has failed
 
             // removal from list is handled later
 
-            LoadBalancer::report_target_error(client, Arc::clone(&b));
+            LoadBalancer::report_target_error(client, Arc::clone(&b), Arc::clone(&s));
         }
+
+        LoadBalancer::report_target_disconnect(client, pools, &s);
     }
 
-    fn start_connection(id: u32, token: Token, client: &mut TcpClient, poll: &Poll, d: Arc<RwLock<bool>>, b: Arc<RwLock<RoundRobin>>) {
+    fn start_connection(id: u32, token: Token, client: &mut TcpClient, poll: &Poll, pools: &Pools, s: Arc<StatsRegistry>, target_pool: &mut TargetPool) {
+        // give up instead of trying more hosts (e.g. when all hosts are down)
+        if client.connection_attempts_exhausted() {
+            debug!(
+                "[Thread {}] Giving up after {} connection attempts ({})",
+                id,
+                client.get_connection_attempts(),
+                client.address
+            );
+
+            // will be removed on next check
+            client.close_connection(CloseReason::ConnectFailed);
+            return;
+        }
+
         // determine target host to connect to, using the balancing algorithm!
+        let mut b = pools.get(client.get_route());
         let target_socket = match client.get_target_addr() {
-            Some(s) => s,
-            None => b.write().unwrap().get_next_host(),
+            Some(t) => Some(t),
+            None => {
+                // clients return to the host they used last while it's available, the algorithm picks it otherwise
+                let host = match pools.get_sticky_host(client.get_route(), client.address) {
+                    Some(h) => Some(h),
+                    None => b.write().unwrap().get_next_host(client.address),
+                };
+
+                // backup pools are only used while no host of the pool itself can be picked
+                let host = match host {
+                    None => match pools.get_fallback_host(client.get_route(), client.address) {
+                        Some((fallback, h)) => {
+                            debug!(
+                                "[Thread {}] No available hosts in pool, using backup pool '{}' ({})",
+                                id, fallback, client.address
+                            );
+                            client.set_route(fallback);
+                            b = pools.get(client.get_route());
+                            Some(h)
+                        }
+                        None => None,
+                    },
+                    host => host,
+                };
+
+                match host {
+                    None if client.should_retry_when_hosts_down() => b.write().unwrap().get_fallback_host(),
+                    host => host,
+                }
+            }
+        };
+        let target_socket = match target_socket {
+            Some(t) => t,
+            None => {
+                let hosts_full = {
+                    let algorithm = b.read().unwrap();
+                    algorithm.get_hosts().iter().any(|h| algorithm.is_full(h))
+                };
+
+                if hosts_full && client.should_queue_when_hosts_full() {
+                    // client tries again on its next event (or the next check of waiting clients)
+                    if client.get_waiting_time().is_none() {
+                        debug!(
+                            "[Thread {}] All hosts are at their connection limit, client is waiting ({})",
+                            id, client.address
+                        );
+                    }
+                    client.wait_for_host();
+                } else if hosts_full {
+                    warn!("[Thread {}] All hosts are at their connection limit, rejecting client ({})", id, client.address);
+                    client.reject();
+                } else {
+                    // no reason to wait for hosts that are most likely down to time out
+                    warn!("[Thread {}] No healthy hosts, closing client ({})", id, client.address);
+                    client.close_connection(CloseReason::NoHosts);
+                }
+                return;
+            }
         };
+        s.record_connection(&target_socket);
+        client.track_target_throughput(s.get_throughput(&target_socket));
 
-        if *d.read().unwrap() && !client.is_connecting() {
-            println!("[Thread {}] Connecting client ({} -> {})", id, client.address, target_socket);
+        if !client.is_connecting() {
+            debug!("[Thread {}] Connecting client ({} -> {})", id, client.address, target_socket);
         }
 
-        // connect to target
-        let success = match client.connect_to_target(target_socket) {
+        // connect to target, reusing an idle connection to it if there is one
+        let pooled = target_pool.take(&target_socket);
+        if pooled.is_some() {
+            debug!("[Thread {}] Reusing pooled connection ({} -> {})", id, client.address, target_socket);
+        }
+
+        let success = match client.connect_to_target(target_socket.clone(), pooled) {
             Ok(s) => s,
             Err(e) => {
-                println!(
+                error!(
                     "[Thread {}] Unexpected error while trying to start a connection! {} ({} -> {})",
-                    id,
-                    e.to_string(),
-                    client.address,
-                    target_socket
+                    id, e, client.address, target_socket
                 );
                 false
             }
@@ -356,19 +1930,363 @@ impl LoadBalancer {
 
         if success {
             // connection to target host started
-            // add server to poll (with same token as client)
-            client.register_target_with_poll(&poll, token);
+            // add server to poll (with the token following the client token)
+            client.register_target_with_poll(poll.registry(), token);
+            pools.stick(client.get_route(), client.address, &target_socket);
         } else {
             // report host error to host manager
-            LoadBalancer::report_target_error(client, Arc::clone(&b));
+            LoadBalancer::report_target_error(client, Arc::clone(&b), s);
         }
     }
 
-    fn report_target_error(client: &mut TcpClient, b: Arc<RwLock<RoundRobin>>) {
+    fn report_target_error(client: &mut TcpClient, b: SharedAlgorithm, s: Arc<StatsRegistry>) {
         // report host error to host manager
         let last_t = client.get_last_target_addr();
-        if client.last_target_errored() && last_t.is_some() {
-            b.write().unwrap().report_error(last_t.unwrap());
+        if let Some(addr) = last_t {
+            if client.last_target_errored() {
+                b.write().unwrap().report_error(&addr);
+                s.record_error(&addr);
+            }
+        }
+    }
+
+    fn report_target_disconnect(client: &mut TcpClient, pools: &Pools, s: &StatsRegistry) {
+        // report closed target connection, so algorithm (and stats) can keep track of active connections
+        if let Some(addr) = client.take_closed_target() {
+            pools.get(client.get_route()).write().unwrap().report_disconnected(&addr);
+            s.record_disconnected(&addr);
+
+            // stick table entry is refreshed, so it expires [ttl] after the connection was closed
+            pools.stick(client.get_route(), client.address, &addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::balancer::client::target_token;
+    use crate::balancer::testing::{assert_closed, assert_echoed, backend, echo_backend, hosts, wait_for, TestBalancer};
+
+    #[test]
+    fn client_tokens_are_even_and_followed_by_target_tokens() {
+        let mut tokens = TokenAllocator::new();
+        let allocated: Vec<Token> = (0..100).map(|_| tokens.allocate()).collect();
+
+        assert_eq!(allocated[0], Token(2));
+        for (i, token) in allocated.iter().enumerate() {
+            assert_eq!(token.0 % 2, 0);
+            assert_eq!(*token, Token(2 + i * 2));
+            assert_eq!(target_token(*token), Token(token.0 + 1));
+            assert!(!allocated.contains(&target_token(*token)));
+        }
+    }
+
+    #[test]
+    fn released_tokens_are_reused() {
+        let mut tokens = TokenAllocator::new();
+        let first = tokens.allocate();
+        let second = tokens.allocate();
+        tokens.release(first);
+
+        assert_eq!(tokens.allocate(), first);
+        assert_eq!(tokens.allocate(), Token(second.0 + 2));
+
+        tokens.release(second);
+        tokens.release(first);
+        assert_eq!(tokens.allocate(), first);
+        assert_eq!(tokens.allocate(), second);
+    }
+
+    #[test]
+    fn reserved_tokens_are_never_handed_out() {
+        // start right below the reserved tokens ([WAKE_TOKEN] and the admin token of the listener)
+        let mut tokens = TokenAllocator {
+            next: usize::MAX - 3,
+            free: vec![],
+        };
+        let last = tokens.allocate();
+        assert_eq!(last, Token(usize::MAX - 3));
+        assert!(target_token(last).0 < usize::MAX - 1);
+
+        // freed tokens can still be reused once the rest are used up
+        tokens.release(last);
+        assert_eq!(tokens.allocate(), last);
+
+        let result = std::panic::catch_unwind(move || tokens.allocate());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let (backend, host) = backend();
+        let balancer = LoadBalancer::builder().threads(2).idle_timeout(Some(Duration::from_millis(300)));
+        let balancer = TestBalancer::start(balancer.build(hosts(&[&host]), Algorithm::RoundRobin.factory(1)));
+
+        let mut client = balancer.connect();
+        client.write_all(b"hello").unwrap();
+        let (mut target, _) = backend.accept().unwrap();
+        target.read_exact(&mut [0; 5]).unwrap();
+
+        // connection is closed once nothing is forwarded for the idle timeout
+        let silent = Instant::now();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        assert!(silent.elapsed() >= Duration::from_millis(300));
+        balancer.wait_for_no_clients();
+    }
+
+    #[test]
+    fn rejects_clients_above_connection_limit() {
+        let host = echo_backend();
+        let balancer = LoadBalancer::builder().threads(2).max_connections(2);
+        let balancer = TestBalancer::start(balancer.build(hosts(&[&host]), Algorithm::RoundRobin.factory(1)));
+
+        let first = balancer.connect();
+        let second = balancer.connect();
+        assert_echoed(&first, b"first");
+        assert_echoed(&second, b"second");
+
+        let third = balancer.connect();
+        assert_closed(&third);
+        assert_echoed(&first, b"first again");
+        assert_echoed(&second, b"second again");
+
+        // closed client makes room for the next one
+        drop(first);
+        wait_for("client to be closed", || balancer.balancer.total_connections() == 1);
+        let fourth = balancer.connect();
+        assert_echoed(&fourth, b"fourth");
+    }
+
+    #[test]
+    fn counts_connections_per_thread() {
+        let host = echo_backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 3, false));
+        assert_eq!(balancer.balancer.connections_per_thread(), vec![0, 0, 0]);
+
+        // new clients go to the thread with least clients
+        let mut clients: Vec<TcpStream> = (0..6).map(|_| balancer.connect()).collect();
+        for client in &clients {
+            assert_echoed(client, b"hello");
+        }
+        assert_eq!(balancer.balancer.connections_per_thread(), vec![2, 2, 2]);
+        assert_eq!(balancer.balancer.total_connections(), 6);
+
+        clients.truncate(4);
+        wait_for("clients to be closed", || balancer.balancer.total_connections() == 4);
+        assert_eq!(balancer.balancer.connections_per_thread().iter().sum::<usize>(), 4);
+
+        clients.clear();
+        balancer.wait_for_no_clients();
+        assert_eq!(balancer.balancer.connections_per_thread(), vec![0, 0, 0]);
+    }
+
+    /**
+        Returns backend that doesn't finish new connections until its first connection is accepted. It listens with no backlog
+        and the returned connection fills its accept queue, so SYNs of new connections are dropped (and only retransmitted after a second)
+    */
+    fn slow_backend() -> (TcpListener, TcpStream, Endpoint) {
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        socket.listen(0).unwrap();
+        let listener: TcpListener = socket.into();
+        let addr = listener.local_addr().unwrap();
+        (listener, TcpStream::connect(addr).unwrap(), Endpoint::Tcp(addr))
+    }
+
+    fn balancer_with_slow_host(slow_timeout: Option<Duration>) -> (TestBalancer, TcpListener, TcpStream, Endpoint) {
+        let (slow, filler, slow_host) = slow_backend();
+        let fast = echo_backend();
+        let mut host_manager = hosts(&[&slow_host, &fast]);
+        host_manager.connect_timeouts = vec![slow_timeout, None];
+
+        let balancer = LoadBalancer::builder()
+            .threads(2)
+            .connection_timeout(Duration::from_millis(200))
+            .total_connection_timeout(Duration::from_secs(10));
+        let balancer = TestBalancer::start(balancer.build(host_manager, Algorithm::RoundRobin.factory(1)));
+        (balancer, slow, filler, slow_host)
+    }
+
+    #[test]
+    fn slow_host_with_longer_connect_timeout_is_not_failed_over() {
+        let (balancer, slow, _filler, slow_host) = balancer_with_slow_host(Some(Duration::from_secs(5)));
+
+        let mut client = balancer.connect();
+        client.write_all(b"hello").unwrap();
+
+        // connection only finishes once there is room in the accept queue, long after the global connect timeout
+        thread::sleep(Duration::from_millis(400));
+        slow.accept().unwrap();
+        let (mut target, _) = slow.accept().unwrap();
+        let mut request = [0; 5];
+        target.read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"hello");
+
+        let stats = balancer.balancer.host_stats();
+        let slow_stats = stats.iter().find(|h| h.host == slow_host).unwrap();
+        assert_eq!(slow_stats.errors, 0);
+        assert_eq!(stats.iter().map(|h| h.connections).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn slow_host_is_failed_over_after_global_connect_timeout() {
+        let (balancer, _slow, _filler, slow_host) = balancer_with_slow_host(None);
+
+        let client = balancer.connect();
+        assert_echoed(&client, b"hello");
+
+        let stats = balancer.balancer.host_stats();
+        assert_eq!(stats.iter().find(|h| h.host == slow_host).unwrap().errors, 1);
+    }
+
+    #[test]
+    fn stale_events_of_removed_clients_are_ignored() {
+        let host = echo_backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 1, false));
+        let long_lived = balancer.connect();
+        assert_echoed(&long_lived, b"hello");
+
+        // clients reset right after sending, so their events are often still pending when they are removed and their
+        // tokens are given to the next clients
+        for i in 0..200 {
+            let mut client = balancer.connect();
+            client.write_all(b"data").unwrap();
+            socket2::SockRef::from(&client).set_linger(Some(Duration::from_secs(0))).unwrap();
+            drop(client);
+
+            if i % 20 == 0 {
+                assert_echoed(&long_lived, b"still here");
+            }
+        }
+
+        wait_for("reset clients to be removed", || balancer.balancer.total_connections() == 1);
+        assert_echoed(&long_lived, b"still here");
+
+        // at most an event for each of the two streams of every removed client, and none once the clients are gone
+        let stale = balancer.balancer.stale_events();
+        assert!(stale <= 2 * 200, "{} stale events", stale);
+        thread::sleep(Duration::from_millis(300));
+        assert_echoed(&long_lived, b"still here");
+        assert_eq!(balancer.balancer.stale_events(), stale);
+
+        // clients reusing the tokens are served normally
+        let clients: Vec<TcpStream> = (0..10).map(|_| balancer.connect()).collect();
+        for client in &clients {
+            assert_echoed(client, b"new client");
+        }
+        assert_eq!(balancer.balancer.total_connections(), 11);
+        assert_eq!(balancer.balancer.stale_events(), stale);
+    }
+
+    #[test]
+    fn pending_clients_are_bounded() {
+        let host = echo_backend();
+        let balancer = LoadBalancer::builder().threads(1).max_pending_clients(3);
+        let mut balancer = TestBalancer::stopped(balancer.build(hosts(&[&host]), Algorithm::RoundRobin.factory(1)));
+
+        // thread is not picking up clients yet, so the flood stays pending
+        let clients: Vec<TcpStream> = (0..20).map(|_| balancer.connect()).collect();
+        assert_eq!(balancer.balancer.max_pending_reached(), 3);
+        assert_eq!(balancer.balancer.stats().threads[0].pending, 3);
+        for client in &clients[3..] {
+            assert_closed(client);
+        }
+
+        balancer.balancer.start();
+        for client in &clients[..3] {
+            assert_echoed(client, b"hello");
+        }
+        assert_eq!(balancer.balancer.total_connections(), 3);
+        assert_eq!(balancer.balancer.max_pending_reached(), 3);
+    }
+
+    #[test]
+    fn reuses_idle_target_connections() {
+        // echo backend counting connections it accepted
+        let (backend, host) = backend();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&accepted);
+        thread::spawn(move || {
+            for mut stream in backend.incoming().flatten() {
+                count.fetch_add(1, Ordering::Relaxed);
+                thread::spawn(move || {
+                    let mut reader = stream.try_clone().unwrap();
+                    std::io::copy(&mut reader, &mut stream).unwrap_or(0);
+                });
+            }
+        });
+
+        // pool is per thread, a single thread gets all clients
+        let balancer = LoadBalancer::builder().threads(1).connection_pool_idle(Some(Duration::from_millis(500)));
+        let balancer = TestBalancer::start(balancer.build(hosts(&[&host]), Algorithm::RoundRobin.factory(1)));
+
+        // pool is empty, so the first client connects to the backend
+        let first = balancer.connect();
+        assert_echoed(&first, b"first");
+        drop(first);
+        balancer.wait_for_no_clients();
+
+        // next client takes the connection of the previous one
+        let second = balancer.connect();
+        assert_echoed(&second, b"second");
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+        drop(second);
+        balancer.wait_for_no_clients();
+
+        // once the pooled connection expires, a new one is made again
+        thread::sleep(Duration::from_millis(700));
+        let third = balancer.connect();
+        assert_echoed(&third, b"third");
+        assert_eq!(accepted.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn closes_clients_without_hosts() {
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[]), 1, false));
+
+        let mut client = balancer.connect();
+        client.write_all(b"hello").unwrap();
+        assert_closed(&client);
+        balancer.wait_for_no_clients();
+
+        // worker thread is still running, it serves clients once there are hosts
+        balancer.balancer.update_hosts(hosts(&[&echo_backend()]));
+        let client = balancer.connect();
+        assert_echoed(&client, b"hello");
+    }
+
+    #[test]
+    fn live_clients_keep_their_tokens_while_others_churn() {
+        let host = echo_backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 1, false));
+        let open: Vec<TcpStream> = (0..10).map(|_| balancer.connect()).collect();
+        for (i, client) in open.iter().enumerate() {
+            assert_echoed(client, format!("open client {}", i).as_bytes());
+        }
+
+        // tokens of closed clients are given to the next ones, a token shared with an open client would mix up their data
+        for round in 0..200 {
+            let client = balancer.connect();
+            assert_echoed(&client, format!("churn client {}", round).as_bytes());
+            drop(client);
+            wait_for("churn client to be closed", || balancer.balancer.total_connections() == open.len());
+
+            if round % 20 == 0 {
+                for (i, client) in open.iter().enumerate() {
+                    assert_echoed(client, format!("open client {} in round {}", i, round).as_bytes());
+                }
+            }
+        }
+
+        for (i, client) in open.iter().enumerate() {
+            assert_echoed(client, format!("open client {} at the end", i).as_bytes());
         }
     }
 }
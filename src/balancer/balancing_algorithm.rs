@@ -1,19 +1,105 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+/**
+    Creates a balancing algorithm for the given hosts, used to create a separate algorithm for every pool of hosts
+*/
+pub type AlgorithmFactory = Arc<dyn Fn(HostManager) -> Box<dyn BalancingAlgorithm> + Send + Sync>;
+
 pub trait BalancingAlgorithm: Sync + Send {
     /**
-        Returns the next host for the client to try to connect to    
+        Returns the next host for the given client to try to connect to, [None] if every host is on cooldown
+        (or there are no hosts)
     */
-    fn get_next_host(&mut self) -> SocketAddr;
+    fn get_next_host(&mut self, client: SocketAddr) -> Option<Endpoint>;
+    /**
+        Returns the host that failed least recently, used instead of [get_next_host] when every host is on cooldown
+        and clients should keep retrying anyway. [None] if there are no hosts at all
+    */
+    fn get_fallback_host(&self) -> Option<Endpoint>;
     /**
         Reports error for the given host address. Host can then be placed on cooldown, this can affect the [get_next_host] call
     */
-    fn report_error(&mut self, addr: SocketAddr);
+    fn report_error(&mut self, addr: &Endpoint);
     /**
         Reports success for the given host address. Host can be removed from cooldown
     */
-    fn report_success(&mut self, addr: SocketAddr);
+    fn report_success(&mut self, addr: &Endpoint);
     /**
         Checks if host is currently on cooldown or in any way affected by the reported errors
     */
-    fn is_on_cooldown(&self, addr: SocketAddr) -> bool;
+    fn is_on_cooldown(&self, addr: &Endpoint) -> bool;
+    /**
+        Returns how long the host is still avoided for, [None] if it's not avoided
+    */
+    fn get_cooldown_remaining(&self, addr: &Endpoint) -> Option<Duration>;
+    /**
+        Starts (or stops) draining the host. Draining host is not returned by [get_next_host] or [get_fallback_host],
+        but established connections to it are not affected
+    */
+    fn set_draining(&mut self, addr: &Endpoint, draining: bool);
+    /**
+        Checks if host has as many established connections as its [max_connections] option allows.
+        Such host is not returned by [get_next_host] or [get_fallback_host] until one of its connections closes
+    */
+    fn is_full(&self, addr: &Endpoint) -> bool;
+    /**
+        Returns all hosts the algorithm is choosing from
+    */
+    fn get_hosts(&self) -> Vec<Endpoint>;
+    /**
+        Replaces the hosts the algorithm is choosing from. Cooldowns of hosts that are no longer present are dropped
+    */
+    fn update_hosts(&mut self, host_manager: HostManager);
+    /**
+        Returns hosts (and their options) the algorithm is choosing from
+    */
+    fn host_manager(&self) -> &HostManager;
+    /**
+        Adds a single host to choose from, returns [false] if nothing changed (host is already present).
+        By default the hosts are replaced with [update_hosts], so the algorithm rebuilds its state for the new hosts
+    */
+    fn add_host(&mut self, addr: Endpoint) -> bool {
+        let mut host_manager = self.host_manager().clone();
+        if !host_manager.add_host(addr) {
+            return false;
+        }
+
+        self.update_hosts(host_manager);
+        true
+    }
+    /**
+        Removes a single host, returns [false] if nothing changed (host is not present or it's the last host, as there
+        always has to be a host to return). By default the hosts are replaced with [update_hosts], like in [add_host]
+    */
+    fn remove_host(&mut self, addr: &Endpoint) -> bool {
+        let mut host_manager = self.host_manager().clone();
+        if host_manager.hosts.len() <= 1 || !host_manager.remove_host(addr) {
+            return false;
+        }
+
+        self.update_hosts(host_manager);
+        true
+    }
+    /**
+        Reports that a client has established a connection to the given host
+    */
+    fn report_connected(&mut self, addr: &Endpoint);
+    /**
+        Reports that an established connection to the given host was closed
+    */
+    fn report_disconnected(&mut self, addr: &Endpoint);
+    /**
+        Reports how long it took to establish a connection to the given host
+    */
+    fn report_latency(&mut self, _addr: &Endpoint, _latency: Duration) {}
+    /**
+        Sets how many errors a host can have before it is put on cooldown
+    */
+    fn set_outlier_detection(&mut self, _outlier_detection: OutlierDetection) {}
 }
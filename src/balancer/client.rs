@@ -1,74 +1,829 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::io::prelude::*;
+use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Result;
+use std::net::IpAddr;
 use std::net::Shutdown;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::RwLock;
 
+use std::time::Duration;
 use std::time::Instant;
 
-use mio::net::TcpStream;
+use log::info;
+use log::warn;
 use mio::Interest;
-use mio::Poll;
+use mio::Registry;
 use mio::Token;
+use rustls::ServerConfig;
+use rustls::ServerConnection;
+use socket2::SockRef;
+use socket2::TcpKeepalive;
+
+use super::access_log;
+use super::http;
+use super::http::RequestHead;
+use super::proxy_protocol;
+use super::proxy_protocol::ProxyHeader;
+use super::sni;
+use super::sni::ClientHello;
+use super::tls::TlsIo;
+use super::AccessLog;
+use super::AccessLogWriter;
+use super::Endpoint;
+use super::Routing;
+use super::Stream;
+use super::Throughput;
+
+// maximum amount of client data buffered while determining the route, client is routed to default pool when exceeded
+const MAX_ROUTING_DATA: usize = 64 * 1024;
+
+// most reads forwarded in one direction per readiness event, so a single busy client can't hold up other clients of its thread
+const MAX_READS_PER_EVENT: usize = 32;
+
+/**
+    Number of connected clients for every source IP
+*/
+pub type IpConnectionCounts = Arc<RwLock<HashMap<IpAddr, usize>>>;
+
+/**
+    Options applied to both the client stream and every target stream
+*/
+#[derive(Clone, Copy)]
+pub struct SocketOptions {
+    /**
+        Disables Nagle's algorithm (TCP_NODELAY), so small writes are sent immediately
+    */
+    pub nodelay: bool,
+    /**
+        Enables SO_KEEPALIVE with given idle time before first keepalive probe is sent, disabled when [None]
+    */
+    pub keepalive: Option<Duration>,
+    /**
+        Time between keepalive probes and number of unanswered probes before the connection is dropped,
+        system defaults are used when [None] (not supported on every platform)
+    */
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_probes: Option<u32>,
+}
+
+impl SocketOptions {
+    /**
+        Applies options to the stream. Options that fail to apply are only logged, as they are not supported on every platform.
+        Unix domain socket streams have no TCP options, they are left as they are
+    */
+    pub fn apply(&self, stream: &Stream) {
+        let stream = match stream.as_tcp() {
+            Some(s) => s,
+            None => return,
+        };
+
+        if self.nodelay {
+            if let Err(e) = stream.set_nodelay(true) {
+                warn!("Failed to set TCP_NODELAY -> {}", e);
+            }
+        }
+
+        if let Some(idle) = self.keepalive {
+            #[allow(unused_mut)]
+            let mut keepalive = TcpKeepalive::new().with_time(idle);
+            #[cfg(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "linux",
+                target_os = "netbsd",
+                target_vendor = "apple",
+                windows
+            ))]
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            #[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux", target_os = "netbsd", target_vendor = "apple"))]
+            if let Some(probes) = self.keepalive_probes {
+                keepalive = keepalive.with_retries(probes);
+            }
+
+            if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+                warn!("Failed to set SO_KEEPALIVE -> {}", e);
+            }
+        }
+    }
+}
 
 pub struct TcpClient {
-    pub stream: TcpStream,
-    buffer: [u8; 4096],
+    pub stream: Stream,
+    /**
+        Data read from client that the target did not accept yet
+    */
+    to_target_pending: Vec<u8>,
+    /**
+        Data read from target that the client did not accept yet
+    */
+    to_client_pending: Vec<u8>,
 
+    /**
+        Address of the client, unspecified (0.0.0.0:0) for clients connected through a Unix domain socket
+    */
     pub address: SocketAddr,
-    target: Option<SocketAddr>,
-    target_stream: Option<TcpStream>,
+    target: Option<Endpoint>,
+    target_stream: Option<Stream>,
     is_connected: bool,
     is_connecting: bool,
     is_client_connected: bool,
+    /**
+        Client finished sending (half-closed its side), target was told the same by shutting down writing to it
+    */
+    client_finished: bool,
+    /**
+        Target finished sending, client was told the same by shutting down writing to it
+    */
+    target_finished: bool,
     pub last_connection_loss: Instant,
     pub started_connecting: Instant,
-    last_target: Option<SocketAddr>,
+    /**
+        Number of connections to targets started since client was last connected to a target
+    */
+    connection_attempts: u32,
+    /**
+        Client is closed instead of connecting to another target once this many attempts failed, unlimited when [None]
+    */
+    max_connection_attempts: Option<u32>,
+    /**
+        Whether client keeps connecting to the least recently failed host when every host is on cooldown (instead of being closed)
+    */
+    retry_when_hosts_down: bool,
+    /**
+        Whether client connects to another target when its target fails after data was exchanged with it (instead of being closed)
+    */
+    retry_mid_stream: bool,
+    /**
+        Whether any data was forwarded to (or from) the current target
+    */
+    target_used: bool,
+    /**
+        Forwarding stopped at [MAX_READS_PER_EVENT] with data possibly still waiting, no new readiness event arrives for it
+    */
+    unfinished: bool,
+    /**
+        Whether client waits for a host when every host is at its connection limit (instead of being rejected)
+    */
+    queue_when_hosts_full: bool,
+    /**
+        When client started waiting for a host with free connections, [None] if it's not waiting
+    */
+    waiting_since: Option<Instant>,
+    /**
+        Sent to client before it's closed because of a connection limit
+    */
+    limit_response: Option<Arc<[u8]>>,
+    /**
+        Last time any bytes were forwarded in either direction
+    */
+    pub last_activity: Instant,
+    last_target: Option<Endpoint>,
     last_target_error: bool,
+    closed_target: Option<Endpoint>,
+    socket_options: SocketOptions,
+    /**
+        Shared per-IP counts this client is counted in, decremented when client is dropped
+    */
+    ip_counts: Option<IpConnectionCounts>,
+    /**
+        Whether PROXY protocol v1 header is sent to every target before any client data
+    */
+    proxy_protocol: bool,
+    /**
+        Connections to targets are made from the client's IP (see [Stream::connect_transparent])
+    */
+    transparent_proxy: bool,
+    /**
+        Client has to start with a PROXY protocol header (sent by an upstream proxy), its address is replaced by the one
+        from the header before the client is routed
+    */
+    proxy_header_pending: bool,
+    /**
+        TLS session with the client, when [None] client stream is used as plain TCP
+    */
+    tls: Option<Box<ServerConnection>>,
+    routing: Routing,
+    is_routed: bool,
+    /**
+        Whether [X-Forwarded-For] and [X-Forwarded-Proto] headers are added to the first HTTP request of the client
+    */
+    forwarded_headers: bool,
+    /**
+        Name the client requested (e.g. SNI server name or HTTP host with the path of the request), used to pick the pool of hosts
+    */
+    route: Option<String>,
+    /**
+        Pool of the listener that accepted the client, used when client is not routed to a pool of its own
+    */
+    listener_pool: Option<String>,
+    /**
+        Client data read while determining the route, it is forwarded to target as soon as client is connected to it
+    */
+    initial_data: Vec<u8>,
+    access_log: AccessLog,
+    /**
+        Writes access log records to the access log file, [None] writes them through the logger
+    */
+    access_log_writer: Option<AccessLogWriter>,
+    /**
+        When the client was accepted
+    */
+    accepted: Instant,
+    /**
+        Last target the client was connected to
+    */
+    served_by: Option<Endpoint>,
+    /**
+        Bytes written to targets (through all targets client was connected to)
+    */
+    bytes_to_target: u64,
+    /**
+        Bytes read from targets and written to client
+    */
+    bytes_from_target: u64,
+    /**
+        Shared totals of all clients, updated together with the counters of this client
+    */
+    throughput: Option<Arc<Throughput>>,
+    /**
+        Shared totals of clients of the worker thread currently handling this client
+    */
+    thread_throughput: Option<Arc<Throughput>>,
+    /**
+        Shared totals of the current target
+    */
+    target_throughput: Option<Arc<Throughput>>,
+    /**
+        Whether healthy target connection is kept for other clients (instead of being closed) when client disconnects
+    */
+    pool_target: bool,
+    /**
+        Interests the client stream is registered with, [None] when it's not registered with any poll
+    */
+    client_interest: Option<Interest>,
+    /**
+        Interests the target stream is registered with, [None] when it's not registered (or there is no target stream)
+    */
+    target_interest: Option<Interest>,
+    /**
+        Whether the current target connection was taken from the pool instead of being newly established
+    */
+    target_reused: bool,
+    /**
+        Target connection kept open after client disconnected, it's moved to the pool of the worker thread
+    */
+    released_target: Option<(Endpoint, Stream)>,
 }
 
 impl TcpClient {
-    pub fn new(stream: TcpStream) -> Self {
-        let addr: SocketAddr = stream.peer_addr().unwrap();
+    pub fn new(stream: Stream, socket_options: SocketOptions) -> Self {
+        socket_options.apply(&stream);
+
+        let mut addr: SocketAddr = stream.peer_addr().unwrap_or_else(|| ([0, 0, 0, 0], 0).into());
+
+        // IPv4 clients connecting through a dual-stack IPv6 listener show up as IPv4-mapped addresses (::ffff:a.b.c.d),
+        // use the plain IPv4 address instead, so they are logged and hashed the same as on an IPv4 listener
+        addr.set_ip(addr.ip().to_canonical());
 
         TcpClient {
-            stream: stream,
-            buffer: [0; 4096],
+            stream,
+            to_target_pending: vec![],
+            to_client_pending: vec![],
             target: None,
             target_stream: None,
             address: addr,
             is_connected: false,
             is_connecting: false,
             is_client_connected: true,
+            client_finished: false,
+            target_finished: false,
             last_connection_loss: Instant::now(),
             started_connecting: Instant::now(),
+            connection_attempts: 0,
+            max_connection_attempts: None,
+            retry_when_hosts_down: false,
+            retry_mid_stream: true,
+            target_used: false,
+            unfinished: false,
+            queue_when_hosts_full: false,
+            waiting_since: None,
+            limit_response: None,
+            last_activity: Instant::now(),
             last_target: None,
             last_target_error: false,
+            closed_target: None,
+            socket_options,
+            ip_counts: None,
+            proxy_protocol: false,
+            transparent_proxy: false,
+            proxy_header_pending: false,
+            tls: None,
+            routing: Routing::None,
+            is_routed: true,
+            forwarded_headers: false,
+            route: None,
+            listener_pool: None,
+            initial_data: vec![],
+            access_log: AccessLog::None,
+            access_log_writer: None,
+            accepted: Instant::now(),
+            served_by: None,
+            bytes_to_target: 0,
+            bytes_from_target: 0,
+            throughput: None,
+            thread_throughput: None,
+            target_throughput: None,
+            pool_target: false,
+            client_interest: None,
+            target_interest: None,
+            target_reused: false,
+            released_target: None,
         }
     }
 
-    pub fn register_target_with_poll(&mut self, poll: &Poll, token: Token) -> Option<()> {
-        let mut str = self.target_stream.take()?;
+    /**
+        Counts this client in given per-IP counts until it is dropped
+    */
+    pub fn track_ip(&mut self, ip_counts: IpConnectionCounts) {
+        *ip_counts.write().unwrap().entry(self.address.ip()).or_insert(0) += 1;
+        self.ip_counts = Some(ip_counts);
+    }
+
+    /**
+        Adds bytes forwarded by this client to given shared totals
+    */
+    pub fn track_throughput(&mut self, throughput: Arc<Throughput>) {
+        self.throughput = Some(throughput);
+    }
+
+    /**
+        Adds bytes forwarded by this client to the shared totals of its worker thread (replacing those of the previous thread)
+    */
+    pub fn track_thread_throughput(&mut self, throughput: Arc<Throughput>) {
+        self.thread_throughput = Some(throughput);
+    }
+
+    /**
+        Adds bytes forwarded by this client to the shared totals of the target it is connecting to
+    */
+    pub fn track_target_throughput(&mut self, throughput: Arc<Throughput>) {
+        self.target_throughput = Some(throughput);
+    }
+
+    /**
+        Returns bytes written to targets and bytes written to client so far
+    */
+    pub fn get_bytes_forwarded(&self) -> (u64, u64) {
+        (self.bytes_to_target, self.bytes_from_target)
+    }
+
+    /**
+        Keeps healthy target connection open when client disconnects, so it can be reused by other clients.
+        Client closing its side of the connection ends the whole connection, as target can't be told about it
+    */
+    pub fn enable_target_pooling(&mut self) {
+        self.pool_target = true;
+    }
+
+    /**
+        Returns target connection that was kept open after client disconnected (if any)
+    */
+    pub fn take_released_target(&mut self) -> Option<(Endpoint, Stream)> {
+        self.released_target.take()
+    }
+
+    /**
+        Returns [true] if the current target connection was taken from the pool
+    */
+    pub fn is_target_reused(&self) -> bool {
+        self.target_reused
+    }
+
+    /**
+        Sends PROXY protocol v1 header to every target this client connects to, so targets know the real client address
+    */
+    pub fn enable_proxy_protocol(&mut self) {
+        self.proxy_protocol = true;
+    }
+
+    /**
+        Connects to TCP targets from the client's IP, so targets see the real client address. Clients without an IP
+        (Unix domain sockets) and targets of another IP version are connected to as usual
+    */
+    pub fn enable_transparent_proxy(&mut self) {
+        self.transparent_proxy = true;
+    }
+
+    /**
+        Client is only routed once it sent the PROXY protocol header (see [read_proxy_header])
+    */
+    pub fn expect_proxy_header(&mut self) {
+        self.proxy_header_pending = true;
+        self.is_routed = false;
+    }
+
+    pub fn is_proxy_header_pending(&self) -> bool {
+        self.proxy_header_pending
+    }
+
+    /**
+        Returns [false] if client has no IP address to check (it's connected through a Unix domain socket and the
+        PROXY protocol header did not provide one either)
+    */
+    pub fn has_ip(&self) -> bool {
+        !self.address.ip().is_unspecified()
+    }
+
+    /**
+        Reads the PROXY protocol header from the client stream (before TLS), address of the client is replaced by the source
+        address from the header. Data received after the header is kept for routing. Returns [true] once header was read.
+        Data is read through [buffer], which is shared by all clients of the worker thread (same for the methods below)
+    */
+    pub fn read_proxy_header(&mut self, buffer: &mut [u8]) -> Result<bool> {
+        if !self.proxy_header_pending {
+            return Ok(true);
+        }
+
+        loop {
+            let (length, source) = match proxy_protocol::parse_header(&self.initial_data) {
+                ProxyHeader::Incomplete => (0, None),
+                ProxyHeader::Parsed(length, source) => (length, Some(source)),
+                ProxyHeader::Invalid => return Err(Error::new(ErrorKind::InvalidData, "invalid PROXY protocol header")),
+            };
+
+            if let Some(source) = source {
+                if let Some(mut addr) = source {
+                    addr.set_ip(addr.ip().to_canonical());
+                    self.address = addr;
+                }
+
+                self.proxy_header_pending = false;
+                self.is_routed = self.is_routed_without_data();
+
+                // header was read directly from the stream, so data after it has to be passed to the TLS session
+                let rest = self.initial_data.split_off(length);
+                self.initial_data.clear();
+                match &mut self.tls {
+                    Some(session) => TlsIo { session, stream: &self.stream }.receive(&rest)?,
+                    None => self.initial_data = rest,
+                }
+                return Ok(true);
+            }
+
+            let mut stream = &self.stream;
+            match stream.read(buffer) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(r) => self.initial_data.extend_from_slice(&buffer[..r]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /**
+        Terminates TLS on the client stream, data is decrypted before being forwarded to target (and encrypted on the way back)
+    */
+    pub fn enable_tls(&mut self, config: Arc<ServerConfig>) -> Result<()> {
+        let session = ServerConnection::new(config).map_err(Error::other)?;
+        self.tls = Some(Box::new(session));
+        Ok(())
+    }
+
+    /**
+        Client has to be routed (see [read_route]) before connecting to a target
+    */
+    pub fn enable_routing(&mut self, routing: Routing) {
+        self.routing = routing;
+        self.is_routed = self.is_routed_without_data() && !self.proxy_header_pending;
+    }
+
+    /**
+        Client is only routed once headers of its first HTTP request were received, so the headers can be added to it
+        (see [read_route])
+    */
+    pub fn enable_forwarded_headers(&mut self) {
+        self.forwarded_headers = true;
+        self.is_routed = false;
+    }
+
+    fn is_routed_without_data(&self) -> bool {
+        self.routing == Routing::None && !self.forwarded_headers
+    }
+
+    /**
+        Writes an access log record in given format when client connection is closed
+    */
+    pub fn enable_access_log(&mut self, access_log: AccessLog, writer: Option<AccessLogWriter>) {
+        self.access_log = access_log;
+        self.access_log_writer = writer;
+    }
+
+    /**
+        Limits number of target connections started (without becoming connected) before client is given up on
+    */
+    pub fn limit_connection_attempts(&mut self, max_attempts: u32) {
+        self.max_connection_attempts = Some(max_attempts);
+    }
+
+    /**
+        Returns [true] if client used up all allowed connection attempts
+    */
+    pub fn connection_attempts_exhausted(&self) -> bool {
+        match self.max_connection_attempts {
+            Some(max) => self.connection_attempts >= max,
+            None => false,
+        }
+    }
+
+    /**
+        Keeps the client connecting to the least recently failed host when every host is on cooldown
+    */
+    pub fn retry_when_hosts_down(&mut self) {
+        self.retry_when_hosts_down = true;
+    }
+
+    pub fn should_retry_when_hosts_down(&self) -> bool {
+        self.retry_when_hosts_down
+    }
+
+    /**
+        Closes the client when its target fails after data was exchanged with it, only failed connects are retried.
+        Another target could not continue a request the failed one already received
+    */
+    pub fn retry_only_failed_connects(&mut self) {
+        self.retry_mid_stream = false;
+    }
+
+    /**
+        Keeps the client waiting for a host when every host is at its connection limit
+    */
+    pub fn queue_when_hosts_full(&mut self) {
+        self.queue_when_hosts_full = true;
+    }
+
+    pub fn should_queue_when_hosts_full(&self) -> bool {
+        self.queue_when_hosts_full
+    }
+
+    /**
+        Data written to client when it's rejected because of a connection limit, see [reject]
+    */
+    pub fn set_limit_response(&mut self, response: Arc<[u8]>) {
+        self.limit_response = Some(response);
+    }
+
+    /**
+        Marks the client as waiting for a host with free connections, the time it started waiting is kept until it connects
+    */
+    pub fn wait_for_host(&mut self) {
+        self.waiting_since.get_or_insert_with(Instant::now);
+    }
+
+    /**
+        Returns how long the client has been waiting for a host, [None] if it's not waiting
+    */
+    pub fn get_waiting_time(&self) -> Option<Duration> {
+        self.waiting_since.map(|w| w.elapsed())
+    }
+
+    /**
+        Closes the client because of a connection limit, the limit response is written first (as much of it as the stream accepts)
+    */
+    pub fn reject(&mut self) {
+        if let Some(response) = self.limit_response.take() {
+            let mut pending = response.to_vec();
+            let mut client_io = ClientIo::new(&self.stream, &mut self.tls);
+            if write_pending(&mut client_io, &mut pending).is_ok() {
+                client_io.flush().unwrap_or(());
+            }
+        }
+
+        self.close_connection(CloseReason::ConnectionLimit);
+    }
+
+    pub fn get_connection_attempts(&self) -> u32 {
+        self.connection_attempts
+    }
+
+    pub fn is_routed(&self) -> bool {
+        self.is_routed
+    }
+
+    pub fn get_route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    /**
+        Client uses given pool (instead of the default one) when routing doesn't pick another one
+    */
+    pub fn use_pool(&mut self, pool: Option<&str>) {
+        self.listener_pool = pool.map(|p| p.to_string());
+        self.route = self.listener_pool.clone();
+    }
+
+    /**
+        Uses the pool of the client's country, unless the listener that accepted the client picked a pool for it
+    */
+    #[cfg(feature = "geoip")]
+    pub fn use_country_pool(&mut self, pool: &str) {
+        if self.listener_pool.is_none() {
+            self.use_pool(Some(pool));
+        }
+    }
+
+    /**
+        Replaces the route picked by routing with the pool it belongs to (see [Pools::find_route])
+    */
+    pub fn set_route(&mut self, pool: String) {
+        self.route = Some(pool);
+    }
+
+    /**
+        Drops the route picked by routing, e.g. when there is no pool for the requested name
+    */
+    pub fn use_listener_pool(&mut self) {
+        self.route = self.listener_pool.clone();
+    }
+
+    /**
+        Reads client data until route of the client can be determined. Read data is kept and forwarded to target once connected.
+        Returns [true] once client is routed, clients that did not request any name keep the pool of their listener
+    */
+    pub fn read_route(&mut self, buffer: &mut [u8]) -> Result<bool> {
+        if self.is_routed {
+            return Ok(true);
+        }
+
+        while self.initial_data.len() < MAX_ROUTING_DATA {
+            match ClientIo::new(&self.stream, &mut self.tls).read(buffer) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(r) => {
+                    self.initial_data.extend_from_slice(&buffer[..r]);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let route = match self.routing {
+            Routing::None => Some(None),
+            Routing::Sni => self.get_sni_route(),
+            // path is part of the route, so pools can be picked by path prefix as well (e.g. "example.com/api/users")
+            Routing::Host => match http::parse_request_head(&self.initial_data) {
+                RequestHead::Incomplete => None,
+                RequestHead::Parsed(host, path) => Some(Some(host.unwrap_or_default() + &path).filter(|r| !r.is_empty())),
+            },
+        };
+
+        // headers can only be added once all of them were received, clients not sending HTTP requests are forwarded unchanged
+        if self.forwarded_headers && route.is_some() {
+            let proto = if self.tls.is_some() { "https" } else { "http" };
+            if !http::add_forwarded_headers(&mut self.initial_data, self.address.ip().to_canonical(), proto) && self.initial_data.len() < MAX_ROUTING_DATA {
+                return Ok(false);
+            }
+        }
+
+        // upgraded connections (e.g. WebSocket) keep using the new protocol until closed, so other clients can't reuse them
+        if self.pool_target && http::is_upgrade_request(&self.initial_data) {
+            self.pool_target = false;
+        }
+
+        match route {
+            Some(Some(r)) => self.route = Some(r),
+            // clients that did not request any name stay in the pool of the listener
+            Some(None) => {}
+            // give up on routing if client sent too much data without it being routable
+            None if self.initial_data.len() >= MAX_ROUTING_DATA => {}
+            None => return Ok(false),
+        }
+
+        self.is_routed = true;
+        Ok(true)
+    }
+
+    /**
+        Returns [None] while the ClientHello was not fully received yet
+    */
+    fn get_sni_route(&self) -> Option<Option<String>> {
+        // when terminating TLS, the session has already parsed the ClientHello
+        if let Some(session) = &self.tls {
+            return match session.server_name() {
+                Some(name) => Some(Some(name.to_lowercase())),
+                None if !session.is_handshaking() => Some(None),
+                None => None,
+            };
+        }
+
+        match sni::parse_client_hello(&self.initial_data) {
+            ClientHello::Incomplete => None,
+            ClientHello::Parsed(name) => Some(name),
+            ClientHello::Invalid => Some(None),
+        }
+    }
+
+    /**
+        Registers client stream with given token and target stream (if client already has one, e.g. when moved from
+        another thread) with the token following it, see [target_token]
+    */
+    pub fn register_with_poll(&mut self, registry: &Registry, token: Token) {
+        let interest = self.get_client_interest();
+        registry.register(&mut self.stream, token, interest).unwrap();
+        self.client_interest = Some(interest);
+
+        self.register_target_with_poll(registry, token);
+    }
+
+    /**
+        Registers target stream with the token following the client token, so events tell which stream is ready
+    */
+    pub fn register_target_with_poll(&mut self, registry: &Registry, token: Token) -> Option<()> {
+        let interest = self.get_target_interest();
+        let str = self.target_stream.as_mut()?;
+
+        registry.register(str, target_token(token), interest).unwrap();
+        self.target_interest = Some(interest);
+
+        Some(())
+    }
+
+    /**
+        Deregisters client and target streams, so client can be registered with another poll (or dropped)
+    */
+    pub fn deregister_from_poll(&mut self, registry: &Registry) {
+        registry.deregister(&mut self.stream).unwrap_or(());
+        if let Some(stream) = self.target_stream.as_mut() {
+            registry.deregister(stream).unwrap_or(());
+        }
+
+        self.client_interest = None;
+        self.target_interest = None;
+    }
+
+    /**
+        Reregisters streams whose interests changed since they were registered, writable interest is only kept while
+        there is data waiting to be written (or the target is connecting), so streams don't wake the thread for nothing
+    */
+    pub fn update_interests(&mut self, registry: &Registry, token: Token) {
+        let interest = self.get_client_interest();
+        if self.client_interest.is_some() && self.client_interest != Some(interest) {
+            registry.reregister(&mut self.stream, token, interest).unwrap_or(());
+            self.client_interest = Some(interest);
+        }
 
-        poll.registry().register(&mut str, token, Interest::READABLE | Interest::WRITABLE).unwrap();
+        let interest = self.get_target_interest();
+        if let Some(str) = self.target_stream.as_mut() {
+            if self.target_interest.is_some() && self.target_interest != Some(interest) {
+                registry.reregister(str, target_token(token), interest).unwrap_or(());
+                self.target_interest = Some(interest);
+            }
+        }
+    }
 
-        self.target_stream = Some(str);
+    fn get_client_interest(&self) -> Interest {
+        let tls_pending = self.tls.as_ref().is_some_and(|t| t.wants_write());
+        if !self.to_client_pending.is_empty() || tls_pending {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        }
+    }
 
-        Some(())
+    fn get_target_interest(&self) -> Interest {
+        // connection is established once the stream becomes writable
+        if self.is_connecting || !self.to_target_pending.is_empty() {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        }
     }
 
-    pub fn get_target_addr(&self) -> Option<SocketAddr> {
-        self.target
+    pub fn get_target_addr(&self) -> Option<Endpoint> {
+        self.target.clone()
     }
 
-    pub fn get_last_target_addr(&self) -> Option<SocketAddr> {
-        self.last_target
+    pub fn get_last_target_addr(&self) -> Option<Endpoint> {
+        self.last_target.clone()
     }
 
     pub fn last_target_errored(&self) -> bool {
         self.last_target_error
     }
 
+    /**
+        Returns the target whose established connection was closed since the last call (if any)
+    */
+    pub fn take_closed_target(&mut self) -> Option<Endpoint> {
+        self.closed_target.take()
+    }
+
     pub fn is_connected(&self) -> bool {
         self.is_connected
     }
@@ -81,20 +836,32 @@ impl TcpClient {
         self.is_client_connected
     }
 
-    pub fn connect_to_target(&mut self, target: SocketAddr) -> Result<bool> {
+    /**
+        Starts connecting to target, [pooled] connection to the same target is used instead of a new one when given
+    */
+    pub fn connect_to_target(&mut self, target: Endpoint, pooled: Option<Stream>) -> Result<bool> {
         if self.is_connecting {
-            println!("[WARNING] Already connecting, this shouldn't happen");
+            warn!("Already connecting, this shouldn't happen");
             return Ok(false);
         }
 
         self.close_connection_to_target(false);
+        self.connection_attempts += 1;
+        self.waiting_since = None;
+        self.target_reused = pooled.is_some();
 
-        // start connecting
-        let stream = match TcpStream::connect(target) {
-            Ok(t) => t,
-            Err(_) => {
-                return Ok(false);
-            }
+        // start connecting (pooled connection is already established, it's confirmed like a new one)
+        let stream = match pooled {
+            Some(s) => s,
+            None => match self.open_target_stream(&target) {
+                Ok(t) => {
+                    self.socket_options.apply(&t);
+                    t
+                }
+                Err(_) => {
+                    return Ok(false);
+                }
+            },
         };
 
         self.is_connecting = true;
@@ -105,157 +872,839 @@ impl TcpClient {
         Ok(true)
     }
 
+    fn open_target_stream(&self, target: &Endpoint) -> Result<Stream> {
+        // clients of dual-stack listeners have IPv4-mapped addresses
+        #[cfg(target_os = "linux")]
+        if let (true, Endpoint::Tcp(addr)) = (self.transparent_proxy && self.has_ip(), target) {
+            let source = self.address.ip().to_canonical();
+            if source.is_ipv4() == addr.is_ipv4() {
+                return Stream::connect_transparent(*addr, source);
+            }
+        }
+
+        Stream::connect(target)
+    }
+
     pub fn check_target_connected(&mut self) -> Result<bool> {
         let stream = self.target_stream.as_ref().unwrap();
 
-        let mut buf: [u8; 1] = [0; 1];
-        match stream.peek(&mut buf) {
-            Ok(_) => true,
-            Err(ref e) if e.kind() == ErrorKind::NotConnected => return Ok(false),
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => true,
+        match stream.is_connected() {
+            Ok(true) => {}
+            Ok(false) => return Ok(false),
             Err(e) => {
+                // e.g. connection refused, error is only reported once so the stream can't be used anymore
+                self.close_connection_to_target(true);
                 return Err(e);
             }
         };
 
         self.set_connected();
+
+        // header has to precede all client data, this is the first thing written to target
+        if self.proxy_protocol {
+            let header = self.get_proxy_header();
+            self.to_target_pending.extend_from_slice(header.as_bytes());
+        }
+
+        // followed by data that was read while routing the client
+        self.to_target_pending.append(&mut self.initial_data);
+
+        match write_pending(self.target_stream.as_ref().unwrap(), &mut self.to_target_pending) {
+            Ok(written) => self.record_to_target(written),
+            Err(e) => {
+                self.close_connection_to_target(true);
+                return Err(e);
+            }
+        }
+
         Ok(true)
     }
 
+    /**
+        Builds the PROXY protocol v1 header, destination is the address the client connected to.
+        Clients connected through a Unix domain socket have no addresses, so the header is UNKNOWN
+    */
+    fn get_proxy_header(&self) -> String {
+        let source = self.address;
+        let destination = self.stream.local_addr().map(|mut a| {
+            a.set_ip(a.ip().to_canonical());
+            a
+        });
+
+        match destination {
+            Ok(d) if source.is_ipv4() == d.is_ipv4() => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                d.ip(),
+                source.port(),
+                d.port()
+            ),
+            _ => "PROXY UNKNOWN\r\n".to_string(),
+        }
+    }
+
     fn set_connected(&mut self) {
         self.is_connected = true;
         self.is_connecting = false;
+        self.served_by = self.target.clone();
+        self.connection_attempts = 0;
+        self.last_activity = Instant::now();
     }
 
     /**
-        Reads from client and forwards it to server. Boolean represents processing success, will be [false] when connection to either client or server fails.
-        Equivalent of calling [forward_to_target] and [forward_from_target] methods
+        Handles readiness of the client stream (or of the target stream when [target] is set) of a connected client.
+        Stream that became writable gets its pending data written first, after which the opposite stream is read again,
+        as reading it was paused while the data was pending. Boolean represents processing success, will be [false] when
+        connection to either client or server fails
     */
-    pub fn process(&mut self) -> bool {
-        if !self.forward_to_target() {
-            return false;
-        }
-
-        if !self.forward_from_target() {
-            return false;
+    pub fn process(&mut self, buffer: &mut [u8], target: bool, readable: bool, writable: bool) -> bool {
+        if target {
+            if writable && !(self.flush_to_target() && self.forward_to_target(buffer)) {
+                return false;
+            }
+            if readable && !self.forward_from_target(buffer) {
+                return false;
+            }
+        } else {
+            if writable && !(self.flush_to_client() && self.forward_from_target(buffer)) {
+                return false;
+            }
+            if readable && !self.forward_to_target(buffer) {
+                return false;
+            }
         }
 
-        return true;
+        true
     }
 
     /**
-        Forwards client messages to connected target. (Reads from client stream and writes to target stream)
+        Returns [true] (once) if forwarding stopped at [MAX_READS_PER_EVENT], client has to be processed again without
+        waiting for a readiness event
     */
-    pub fn forward_to_target(&mut self) -> bool {
-        let mut str = self.target_stream.as_ref().unwrap();
+    pub fn take_unfinished(&mut self) -> bool {
+        std::mem::take(&mut self.unfinished)
+    }
 
-        // READ FROM CLIENT
-        let read: i32 = match self.stream.read(&mut self.buffer) {
-            Ok(r) => r as i32,
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => -1,
+    /**
+        Writes client data that could not be fully written to target before. Boolean represents success
+    */
+    fn flush_to_target(&mut self) -> bool {
+        let str = self.target_stream.as_ref().unwrap();
+        match write_pending(str, &mut self.to_target_pending) {
+            Ok(written) => self.record_to_target(written),
             Err(_) => {
+                // error with connection to server
+                self.fail_target();
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /**
+        Writes target data (and TLS records) that could not be fully written to client before, this is also needed before
+        the client is connected to any target (e.g. for TLS handshake). Boolean represents success
+    */
+    pub fn flush_to_client(&mut self) -> bool {
+        let mut client_io = ClientIo::new(&self.stream, &mut self.tls);
+        match write_pending(&mut client_io, &mut self.to_client_pending) {
+            Ok(written) if client_io.flush().is_ok() => self.record_from_target(written),
+            _ => {
                 // error with connection to client
-                self.close_connection();
+                self.close_connection(CloseReason::ClientError);
                 return false;
             }
-        };
+        }
 
-        // WRITE TO SERVER
-        if read > 0 {
-            match str.write(&self.buffer[..(read as usize)]) {
-                Ok(_) => {}
-                Err(_e) => {
+        true
+    }
+
+    /**
+        Forwards client messages to connected target. (Reads from client stream and writes to target stream)
+        Keeps forwarding until there is nothing more to read or target stops accepting data, as readiness events are edge-triggered
+        (up to [MAX_READS_PER_EVENT] reads, the rest is forwarded once the client is processed again)
+    */
+    pub fn forward_to_target(&mut self, buffer: &mut [u8]) -> bool {
+        let str = self.target_stream.as_ref().unwrap();
+
+        // don't read more from client until target accepts the data it has not yet been able to
+        let mut reads = 0;
+        while self.to_target_pending.is_empty() && !self.client_finished {
+            if reads == MAX_READS_PER_EVENT {
+                self.unfinished = true;
+                break;
+            }
+            reads += 1;
+
+            // READ FROM CLIENT
+            let read = match ClientIo::new(&self.stream, &mut self.tls).read(buffer) {
+                Ok(0) if self.pool_target => {
+                    // pooled target connection can't be half-closed, so client closing its side ends the connection
+                    self.close_connection(CloseReason::Finished);
+                    return false;
+                }
+                Ok(0) => {
+                    // client won't send anything more, but it can still receive the rest of the response
+                    self.client_finished = true;
+                    str.shutdown(Shutdown::Write).unwrap_or(());
+                    if self.target_finished {
+                        self.close_connection(CloseReason::Finished);
+                        return false;
+                    }
+                    break;
+                }
+                Ok(r) => r,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    // error with connection to client
+                    self.close_connection(CloseReason::ClientError);
+                    return false;
+                }
+            };
+
+            self.last_activity = Instant::now();
+
+            // WRITE TO SERVER
+            self.to_target_pending.extend_from_slice(&buffer[..read]);
+            match write_pending(str, &mut self.to_target_pending) {
+                // counted inline, as the target stream stays borrowed for the whole loop
+                Ok(written) => {
+                    self.bytes_to_target += written as u64;
+                    self.target_used |= written > 0;
+                    for throughput in self.shared_throughput() {
+                        throughput.record_to_target(written);
+                    }
+                }
+                Err(_) => {
                     // error with connection to server
-                    self.close_connection_to_target(true);
+                    self.fail_target();
                     return false;
                 }
             }
-        } else if read == 0 {
-            self.close_connection();
-            return false;
         }
 
-        return true;
+        true
     }
 
-     /**
+    /**
         Forwards connected target messages to client. (Reads from target stream and writes to client stream)
+        Keeps forwarding until there is nothing more to read or client stops accepting data, as readiness events are edge-triggered
+        (up to [MAX_READS_PER_EVENT] reads, the rest is forwarded once the client is processed again)
     */
-    pub fn forward_from_target(&mut self) -> bool {
+    pub fn forward_from_target(&mut self, buffer: &mut [u8]) -> bool {
         let mut str = self.target_stream.as_ref().unwrap();
 
-        // READ FROM SERVER
-        let reads: i32 = match str.read(&mut self.buffer) {
-            Ok(r) => r as i32,
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => -1,
-            Err(_e) => {
-                // error with connection to server
-                self.close_connection_to_target(true);
-                return false;
+        // don't read more from target until client accepts the data it has not yet been able to
+        let mut reads = 0;
+        while self.to_client_pending.is_empty() && !self.target_finished {
+            if reads == MAX_READS_PER_EVENT {
+                self.unfinished = true;
+                break;
             }
-        };
+            reads += 1;
+
+            // READ FROM SERVER
+            let read = match str.read(buffer) {
+                Ok(0) => {
+                    // target won't send anything more, but client can still keep sending to it
+                    self.target_finished = true;
+                    self.shutdown_client_write();
+                    if self.client_finished {
+                        self.close_connection(CloseReason::Finished);
+                        return false;
+                    }
+                    break;
+                }
+                Ok(r) => r,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_e) => {
+                    // error with connection to server
+                    self.fail_target();
+                    return false;
+                }
+            };
 
-        // WRITE TO CLIENT
-        if reads > 0 {
-            match self.stream.write(&self.buffer[..(reads as usize)]) {
-                Ok(_) => {}
+            self.last_activity = Instant::now();
+            self.target_used = true;
+
+            // WRITE TO CLIENT
+            self.to_client_pending.extend_from_slice(&buffer[..read]);
+            match write_pending(ClientIo::new(&self.stream, &mut self.tls), &mut self.to_client_pending) {
+                // counted inline, as the target stream stays borrowed for the whole loop
+                Ok(written) => {
+                    self.bytes_from_target += written as u64;
+                    for throughput in self.shared_throughput() {
+                        throughput.record_from_target(written);
+                    }
+                }
                 Err(_) => {
                     // error with connection to client
-                    self.close_connection();
+                    self.close_connection(CloseReason::ClientError);
                     return false;
                 }
-            };
-        } else if reads == 0 {
-            self.close_connection_to_target(false);
-            return false;
+            }
+        }
+
+        true
+    }
+
+    /**
+        Returns all shared totals the forwarded bytes of this client are added to
+    */
+    fn shared_throughput(&self) -> impl Iterator<Item = &Arc<Throughput>> {
+        self.throughput.iter().chain(&self.thread_throughput).chain(&self.target_throughput)
+    }
+
+    fn record_to_target(&mut self, bytes: usize) {
+        self.bytes_to_target += bytes as u64;
+        self.target_used |= bytes > 0;
+        for throughput in self.shared_throughput() {
+            throughput.record_to_target(bytes);
+        }
+    }
+
+    fn record_from_target(&mut self, bytes: usize) {
+        self.bytes_from_target += bytes as u64;
+        self.target_used |= bytes > 0;
+        for throughput in self.shared_throughput() {
+            throughput.record_from_target(bytes);
+        }
+    }
+
+    /**
+        Shuts down writing to client, TLS clients are sent close_notify first
+    */
+    fn shutdown_client_write(&mut self) {
+        if let Some(session) = &mut self.tls {
+            session.send_close_notify();
+            ClientIo::new(&self.stream, &mut self.tls).flush().unwrap_or(());
         }
 
-        return true;
+        self.stream.shutdown(Shutdown::Write).unwrap_or(());
+    }
+
+    /**
+        Closes the errored target connection. Client connects to the next target, unless either side already half-closed
+        the connection - another target could not continue where the failed one stopped. Without [retry_mid_stream], this
+        is also the case once any data was exchanged with the target
+    */
+    fn fail_target(&mut self) {
+        if self.client_finished || self.target_finished || (self.target_used && !self.retry_mid_stream) {
+            self.close_connection(CloseReason::TargetError);
+        } else {
+            self.close_connection_to_target(true);
+        }
     }
 
     pub fn close_connection_to_target(&mut self, target_errored: bool) {
         // if connected to target, disconnect - mark last connection loss
         if self.is_connected {
-            let str = self.target_stream.as_ref().unwrap();
-            str.shutdown(Shutdown::Both).unwrap_or(());
-            drop(str);
+            // released target connection stays open
+            if let Some(str) = &self.target_stream {
+                str.shutdown(Shutdown::Both).unwrap_or(());
+            }
 
             self.last_connection_loss = Instant::now();
+            self.closed_target = self.target.clone();
         }
 
         // mark error
         if target_errored {
-            self.last_target = self.target;
+            self.last_target = self.target.clone();
             self.last_target_error = true;
         } else {
             self.last_target = None;
             self.last_target_error = false;
         }
 
-        // reset
+        // reset (data that was meant for the old target is discarded)
+        self.target_used = false;
         self.target = None;
         self.target_stream = None;
+        self.to_target_pending.clear();
 
         self.is_connected = false;
         self.is_connecting = false;
     }
 
-    pub fn close_connection(&mut self) {
+    /**
+        Target connection can be reused when nothing is left to forward in either direction and the target did not close it
+    */
+    fn can_release_target(&self) -> bool {
+        self.pool_target
+            && self.is_connected
+            && !self.target_finished
+            && self.to_target_pending.is_empty()
+            && self.to_client_pending.is_empty()
+            && matches!(self.target, Some(Endpoint::Tcp(_)))
+    }
+
+    fn write_access_log(&self, reason: CloseReason) {
+        let duration = self.accepted.elapsed().as_millis();
+
+        let record = match self.access_log {
+            AccessLog::None => return,
+            AccessLog::Text => format!(
+                "{} -> {} sent={} received={} duration={}ms reason={}",
+                self.address,
+                self.served_by.as_ref().map(|t| t.to_string()).unwrap_or_else(|| "-".to_string()),
+                self.bytes_to_target,
+                self.bytes_from_target,
+                duration,
+                reason
+            ),
+            AccessLog::Json => format!(
+                "{{\"time\":\"{}\",\"client\":\"{}\",\"target\":{},\"bytes_to_target\":{},\"bytes_to_client\":{},\"duration_ms\":{},\"reason\":\"{}\"}}",
+                access_log::timestamp(),
+                self.address,
+                self.served_by.as_ref().map(|t| format!("\"{}\"", t)).unwrap_or_else(|| "null".to_string()),
+                self.bytes_to_target,
+                self.bytes_from_target,
+                duration,
+                reason
+            ),
+        };
+
+        // logger adds its own timestamp, JSON records already have one
+        match (&self.access_log_writer, self.access_log) {
+            (Some(writer), AccessLog::Text) => writer.write(format!("{} {}", access_log::timestamp(), record)),
+            (Some(writer), _) => writer.write(record),
+            (None, AccessLog::Text) => info!("[Access] {}", record),
+            (None, _) => info!("{}", record),
+        }
+    }
+
+    /**
+        Closes both sides of the connection, [reason] is written to the access log (only the first one, if closed repeatedly)
+    */
+    pub fn close_connection(&mut self, reason: CloseReason) {
         if self.is_client_connected {
-            let str = &self.stream;
-            str.shutdown(Shutdown::Both).unwrap_or(());
-            drop(str);
+            // let TLS client know the connection is closing on purpose (unless it was already told when target finished)
+            if !self.target_finished {
+                if let Some(session) = &mut self.tls {
+                    session.send_close_notify();
+                    ClientIo::new(&self.stream, &mut self.tls).flush().unwrap_or(());
+                }
+            }
+
+            self.stream.shutdown(Shutdown::Both).unwrap_or(());
 
             self.is_client_connected = false;
 
+            // keep healthy target connection for the next client instead of closing it
+            if self.can_release_target() {
+                self.released_target = self.target.clone().zip(self.target_stream.take());
+            }
+
             // also close connection to target if connected - there is no reason to stay connected if client is not
             self.close_connection_to_target(false);
+
+            self.write_access_log(reason);
         }
     }
+
+    /**
+        Closes the client with a TCP reset instead of a regular close, nothing is written to the access log.
+        Meant for clients that were not handed to a worker thread yet (not connected to any target)
+    */
+    pub fn reset_connection(&mut self) {
+        if let Some(stream) = self.stream.as_tcp() {
+            SockRef::from(stream).set_linger(Some(Duration::ZERO)).unwrap_or(());
+        }
+
+        // socket is closed once the client is dropped, shutting it down would send a regular FIN first
+        self.is_client_connected = false;
+    }
 }
 
 impl Drop for TcpClient {
     fn drop(&mut self) {
-        self.close_connection();
+        self.close_connection(CloseReason::Stopped);
+
+        if let Some(ip_counts) = self.ip_counts.take() {
+            let mut counts = ip_counts.write().unwrap();
+            let ip = self.address.ip();
+            if let Some(count) = counts.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/**
+    Why a client connection was closed, written to the access log
+*/
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CloseReason {
+    /**
+        Both sides finished sending, or the client closed its pooled connection
+    */
+    Finished,
+    ClientError,
+    /**
+        Target failed after either side half-closed the connection, so the client could not be moved to another target
+    */
+    TargetError,
+    IdleTimeout,
+    /**
+        No target connection was established within the total connection timeout
+    */
+    ConnectTimeout,
+    /**
+        Client used up all its connection attempts
+    */
+    ConnectFailed,
+    RoutingTimeout,
+    InvalidProxyHeader,
+    /**
+        Rejected by the access list or the connection limit per IP
+    */
+    Denied,
+    NoHosts,
+    /**
+        Rejected because every host (or the balancer) was at its connection limit
+    */
+    ConnectionLimit,
+    /**
+        Target was removed from the hosts with immediate host removal
+    */
+    HostRemoved,
+    /**
+        Balancer stopped
+    */
+    Stopped,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloseReason::Finished => write!(f, "finished"),
+            CloseReason::ClientError => write!(f, "client_error"),
+            CloseReason::TargetError => write!(f, "target_error"),
+            CloseReason::IdleTimeout => write!(f, "idle_timeout"),
+            CloseReason::ConnectTimeout => write!(f, "connect_timeout"),
+            CloseReason::ConnectFailed => write!(f, "connect_failed"),
+            CloseReason::RoutingTimeout => write!(f, "routing_timeout"),
+            CloseReason::InvalidProxyHeader => write!(f, "invalid_proxy_header"),
+            CloseReason::Denied => write!(f, "denied"),
+            CloseReason::NoHosts => write!(f, "no_hosts"),
+            CloseReason::ConnectionLimit => write!(f, "connection_limit"),
+            CloseReason::HostRemoved => write!(f, "host_removed"),
+            CloseReason::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/**
+    Returns token of the target stream of the client with given token. Client tokens are always even (see [TokenAllocator]),
+    so the target token can't belong to another client
+*/
+pub fn target_token(token: Token) -> Token {
+    Token(token.0 + 1)
+}
+
+/**
+    Writes as much of the pending data as possible, written data is removed from it. Data that would block stays pending.
+    Returns the number of bytes written
+*/
+fn write_pending<W: Write>(mut stream: W, pending: &mut Vec<u8>) -> Result<usize> {
+    let mut total = 0;
+    while !pending.is_empty() {
+        match stream.write(pending) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                pending.drain(..written);
+                total += written;
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(total)
+}
+
+/**
+    Client side of the connection, either plain stream or TLS. Used with the same non-blocking semantics as the stream itself
+*/
+enum ClientIo<'a> {
+    Plain(&'a Stream),
+    Tls(TlsIo<'a>),
+}
+
+impl<'a> ClientIo<'a> {
+    fn new(stream: &'a Stream, tls: &'a mut Option<Box<ServerConnection>>) -> Self {
+        match tls {
+            Some(session) => ClientIo::Tls(TlsIo { session, stream }),
+            None => ClientIo::Plain(stream),
+        }
+    }
+}
+
+impl Read for ClientIo<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            ClientIo::Plain(mut stream) => stream.read(buf),
+            ClientIo::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for ClientIo<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            ClientIo::Plain(mut stream) => stream.write(buf),
+            ClientIo::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            ClientIo::Plain(_) => Ok(()),
+            ClientIo::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{SocketOptions, TcpClient};
+    use crate::balancer::testing::{assert_closed, backend, closed_port, hosts, temp_file, wait_for, TestBalancer};
+    use crate::balancer::{AccessLog, Algorithm, Endpoint, LoadBalancer, Stream};
+
+    #[test]
+    fn forwards_large_payload_under_backpressure() {
+        let payload: Vec<u8> = (0..8 * 1024 * 1024).map(|i: u32| (i % 251) as u8).collect();
+        let (backend, host) = backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 2, false));
+
+        // backend echoes everything back once the client finished sending
+        let (written_sender, written) = mpsc::channel::<()>();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+
+            // nothing is read until the client finished writing (or its writes are stuck on full buffers of the
+            // balancer for a while), so forwarding has to deal with partial and blocked writes
+            written.recv_timeout(Duration::from_secs(1)).unwrap_or(());
+            let mut received = vec![];
+            stream.read_to_end(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+            received.len()
+        });
+
+        let mut client = balancer.connect();
+        let writer = {
+            let mut client = client.try_clone().unwrap();
+            let payload = payload.clone();
+            thread::spawn(move || {
+                client.write_all(&payload).unwrap();
+                written_sender.send(()).unwrap();
+                client.shutdown(Shutdown::Write).unwrap();
+            })
+        };
+
+        let mut echoed = vec![];
+        client.read_to_end(&mut echoed).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(server.join().unwrap(), payload.len());
+        assert_eq!(echoed.len(), payload.len());
+        assert!(echoed == payload, "echoed data doesn't match the payload");
+        balancer.wait_for_no_clients();
+    }
+
+    #[test]
+    fn half_closed_client_still_gets_reply() {
+        let (backend, host) = backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 2, false));
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+
+            // client shutting down its writing reaches the backend as EOF
+            let mut request = vec![];
+            stream.read_to_end(&mut request).unwrap();
+            assert_eq!(request, b"ping");
+
+            stream.write_all(b"pong").unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            stream
+        });
+
+        let mut client = balancer.connect();
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"pong");
+
+        // once both sides finished, the balancer closes both connections
+        let stream: TcpStream = server.join().unwrap();
+        balancer.wait_for_no_clients();
+        wait_for("backend connection to be closed", || (&stream).write_all(b"late").is_err());
+    }
+
+    #[test]
+    fn forwards_ipv6_clients() {
+        let backend = TcpListener::bind("[::1]:0").unwrap();
+        let host = Endpoint::Tcp(backend.local_addr().unwrap());
+        let balancer = TestBalancer::start_on(LoadBalancer::new(hosts(&[&host]), 2, false), "[::1]:0");
+
+        let server = thread::spawn(move || {
+            let (mut stream, client) = backend.accept().unwrap();
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&request).unwrap();
+            client
+        });
+
+        let mut client = balancer.connect();
+        client.write_all(b"ping").unwrap();
+        let mut reply = [0; 4];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"ping");
+        assert!(server.join().unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_use_ipv4_address() {
+        // dual-stack listener
+        let listener = TcpListener::bind("[::]:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _client = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let options = SocketOptions {
+            nodelay: false,
+            keepalive: None,
+            keepalive_interval: None,
+            keepalive_probes: None,
+        };
+        let client = TcpClient::new(Stream::Tcp(mio::net::TcpStream::from_std(stream)), options);
+        assert_eq!(client.address.ip(), IpAddr::from([127, 0, 0, 1]));
+    }
+
+    #[test]
+    fn sends_proxy_header_before_client_data() {
+        let (backend, host) = backend();
+        let down = closed_port();
+        let balancer = LoadBalancer::builder().threads(2).proxy_protocol(true);
+        // first host refuses the connection, so the header is sent again (once) to the next one
+        let balancer = TestBalancer::start(balancer.build(hosts(&[&down, &host]), Algorithm::RoundRobin.factory(1)));
+
+        let mut client = balancer.connect();
+        client.write_all(b"hello").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let (mut target, _) = backend.accept().unwrap();
+        let mut received = vec![];
+        target.read_to_end(&mut received).unwrap();
+
+        let source = client.local_addr().unwrap();
+        let expected = format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello", source.port(), balancer.addr.port());
+        assert_eq!(String::from_utf8(received).unwrap(), expected);
+    }
+
+    #[test]
+    fn access_log_counts_forwarded_bytes() {
+        let (backend, host) = backend();
+        let down = closed_port();
+        let log = temp_file("access-log", "");
+        let balancer = LoadBalancer::builder()
+            .threads(2)
+            .access_log(AccessLog::Json)
+            .access_log_file(Some(log.clone()));
+        // first host refuses the connection, the record names the host that served the client
+        let balancer = TestBalancer::start(balancer.build(hosts(&[&down, &host]), Algorithm::RoundRobin.factory(1)));
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+            let mut request = vec![];
+            stream.read_to_end(&mut request).unwrap();
+            stream.write_all(&[7; 300]).unwrap();
+            request.len()
+        });
+
+        let mut client = balancer.connect();
+        client.write_all(&[1; 1000]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(server.join().unwrap(), 1000);
+        assert_eq!(reply.len(), 300);
+
+        wait_for("access log record", || std::fs::read_to_string(&log).unwrap().lines().count() == 1);
+        let record = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_file(&log).unwrap();
+        assert!(record.contains(&format!("\"client\":\"{}\"", client.local_addr().unwrap())), "{}", record);
+        assert!(record.contains(&format!("\"target\":\"{}\"", host)), "{}", record);
+        assert!(record.contains("\"bytes_to_target\":1000,\"bytes_to_client\":300,"), "{}", record);
+    }
+
+    #[test]
+    fn gives_up_after_max_connection_attempts() {
+        // listeners are only closed once all of them are bound, so the ports are different
+        let listeners = [backend(), backend(), backend()];
+        let down: Vec<Endpoint> = listeners.iter().map(|(_, h)| h.clone()).collect();
+        drop(listeners);
+        let balancer = LoadBalancer::builder().threads(2).max_connection_attempts(2);
+        let balancer = TestBalancer::start(balancer.build(hosts(&[&down[0], &down[1], &down[2]]), Algorithm::RoundRobin.factory(1)));
+
+        let mut client = balancer.connect();
+        client.write_all(b"hello").unwrap();
+        assert_closed(&client);
+        balancer.wait_for_no_clients();
+
+        let stats = balancer.balancer.host_stats();
+        assert_eq!(stats.iter().map(|h| h.connections).sum::<usize>(), 2);
+        assert_eq!(stats.iter().map(|h| h.errors).sum::<usize>(), 2);
+        assert_eq!(stats.iter().find(|h| h.host == down[2]).unwrap().connections, 0);
+    }
+
+    #[test]
+    fn half_closed_backend_still_gets_client_data() {
+        let (backend, host) = backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 2, false));
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"ping");
+
+            // backend finishes its reply first, but keeps reading what the client sends after it
+            stream.write_all(b"pong").unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest).unwrap();
+            rest
+        });
+
+        let mut client = balancer.connect();
+        client.write_all(b"ping").unwrap();
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"pong");
+
+        client.write_all(b"more").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(server.join().unwrap(), b"more");
+        balancer.wait_for_no_clients();
     }
 }
@@ -0,0 +1,890 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Result;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::{error, warn};
+
+use super::ListenerOptions;
+use super::OutlierDetection;
+use super::RateLimiter;
+
+/**
+    Decides which pool of hosts a client is balanced between
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    /**
+        Every client uses the default pool
+    */
+    None,
+    /**
+        Pool is picked by server name the client requested in the TLS ClientHello
+    */
+    Sni,
+    /**
+        Pool is picked by the [Host] header of the first HTTP request
+    */
+    Host,
+}
+
+impl FromStr for Routing {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Routing::None),
+            "sni" => Ok(Routing::Sni),
+            "host" => Ok(Routing::Host),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Routing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Routing::None => write!(f, "none"),
+            Routing::Sni => write!(f, "sni"),
+            Routing::Host => write!(f, "host"),
+        }
+    }
+}
+
+/**
+    How hosts are checked by the health checker
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HealthCheck {
+    /**
+        Host is healthy if it accepts the connection
+    */
+    Tcp,
+    /**
+        Host is healthy if it responds to a GET request of [health_check_path] with 2xx or 3xx status
+    */
+    Http,
+}
+
+impl FromStr for HealthCheck {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(HealthCheck::Tcp),
+            "http" => Ok(HealthCheck::Http),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HealthCheck::Tcp => write!(f, "tcp"),
+            HealthCheck::Http => write!(f, "http"),
+        }
+    }
+}
+
+/**
+    Protocol of balanced traffic
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    /**
+        Datagrams are balanced by client address, see [UdpProxy]
+    */
+    Udp,
+}
+
+impl FromStr for Protocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/**
+    Decides what happens to established connections to hosts that were removed by reloading the host file
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HostRemoval {
+    /**
+        Connections are left to finish on their own, but clients are never connected to the removed host again
+    */
+    Graceful,
+    /**
+        Connections are closed right away
+    */
+    Immediate,
+}
+
+impl FromStr for HostRemoval {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "graceful" => Ok(HostRemoval::Graceful),
+            "immediate" => Ok(HostRemoval::Immediate),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for HostRemoval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostRemoval::Graceful => write!(f, "graceful"),
+            HostRemoval::Immediate => write!(f, "immediate"),
+        }
+    }
+}
+
+/**
+    Decides what happens to clients when every host is on cooldown
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AllHostsDown {
+    /**
+        Client is closed right away, instead of waiting for connection timeouts of hosts that are most likely down
+    */
+    Close,
+    /**
+        Client keeps connecting to the host that failed least recently
+    */
+    Retry,
+}
+
+impl FromStr for AllHostsDown {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "close" => Ok(AllHostsDown::Close),
+            "retry" => Ok(AllHostsDown::Retry),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for AllHostsDown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllHostsDown::Close => write!(f, "close"),
+            AllHostsDown::Retry => write!(f, "retry"),
+        }
+    }
+}
+
+/**
+    Decides what happens to clients above [max_connections] or when every host is at its connection limit
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimitAction {
+    /**
+        Client is closed right away (after being sent the [connection_limit_response], if any)
+    */
+    Reject,
+    /**
+        Client waits until there is room for it, clients above [max_connections] wait in the listen backlog (not accepted yet)
+        and clients waiting for a host are closed like rejected ones once [total_connection_timeout] passes
+    */
+    Queue,
+}
+
+impl FromStr for ConnectionLimitAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(ConnectionLimitAction::Reject),
+            "queue" => Ok(ConnectionLimitAction::Queue),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionLimitAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionLimitAction::Reject => write!(f, "reject"),
+            ConnectionLimitAction::Queue => write!(f, "queue"),
+        }
+    }
+}
+
+/**
+    Decides what happens to new clients when even the least busy worker thread has [max_pending_clients] waiting for it
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PendingOverflow {
+    /**
+        New client is closed right away
+    */
+    Reject,
+    /**
+        New client is closed with a TCP reset, so it's not left waiting for the close to finish
+    */
+    Reset,
+    /**
+        New client is queued and the oldest waiting client is closed instead (clients being moved between threads are kept)
+    */
+    DropOldest,
+}
+
+impl FromStr for PendingOverflow {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(PendingOverflow::Reject),
+            "reset" => Ok(PendingOverflow::Reset),
+            "drop_oldest" => Ok(PendingOverflow::DropOldest),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for PendingOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PendingOverflow::Reject => write!(f, "reject"),
+            PendingOverflow::Reset => write!(f, "reset"),
+            PendingOverflow::DropOldest => write!(f, "drop_oldest"),
+        }
+    }
+}
+
+/**
+    Decides which clients share an entry of the stick table
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StickKey {
+    /**
+        Clients with the same IP
+    */
+    Ip,
+    /**
+        Clients of the same subnet (/24 for IPv4 and /64 for IPv6), for clients whose IP changes within their network
+    */
+    Subnet,
+}
+
+impl FromStr for StickKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(StickKey::Ip),
+            "subnet" => Ok(StickKey::Subnet),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for StickKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StickKey::Ip => write!(f, "ip"),
+            StickKey::Subnet => write!(f, "subnet"),
+        }
+    }
+}
+
+/**
+    Format of the access log record written for every closed client connection
+*/
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AccessLog {
+    None,
+    Text,
+    Json,
+}
+
+impl FromStr for AccessLog {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AccessLog::None),
+            "text" => Ok(AccessLog::Text),
+            "json" => Ok(AccessLog::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessLog::None => write!(f, "none"),
+            AccessLog::Text => write!(f, "text"),
+            AccessLog::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    /**
+        Number of worker threads handling clients
+    */
+    pub threads: u16,
+    /**
+        Clients are moved from the busiest worker thread to the least busy one when their client counts differ by more than this.
+        Disabled when 0
+    */
+    pub rebalance_threshold: usize,
+    /**
+        Logs every client connection event on debug level, ignored when log level is picked through RUST_LOG
+    */
+    pub debug: bool,
+    /**
+        Timeout to connect to a single target host
+    */
+    pub connection_timeout: Duration,
+    /**
+        Total timeout allowed to connect (through all tried hosts) before client is disconnected
+    */
+    pub total_connection_timeout: Duration,
+    /**
+        Maximum number of hosts a client tries to connect to before it is disconnected, unlimited when 0
+    */
+    pub max_connection_attempts: u32,
+    /**
+        Whether clients whose host fails after data was exchanged with it are connected to another host (instead of being closed).
+        Connections that fail before that are always retried
+    */
+    pub retry_mid_stream: bool,
+    /**
+        Host is put on cooldown once it reports this many errors within [outlier_window], so single errors can be tolerated
+    */
+    pub outlier_errors: u32,
+    pub outlier_window: Duration,
+    /**
+        How long a host is avoided once it's put on cooldown. Hosts that fail again after their cooldown has passed are avoided
+        twice as long every time (up to [max_cooldown]), until a connection or health check succeeds
+    */
+    pub cooldown: Duration,
+    pub max_cooldown: Duration,
+    /**
+        Host on cooldown is only fully restored (and its backoff reset) after this many successful connections or health checks in a row,
+        so a single lucky connection of a flapping host doesn't reset its backoff
+    */
+    pub recovery_successes: u32,
+    /**
+        Established connections with no data forwarded in either direction for this long are closed, disabled when [None]
+    */
+    pub idle_timeout: Option<Duration>,
+    /**
+        Connections to hosts are kept open for this long after their client disconnects, so they can be reused by other clients
+        instead of connecting again. Only safe when hosts keep no state between clients, disabled when [None]
+    */
+    pub connection_pool_idle: Option<Duration>,
+    /**
+        Maximum number of concurrently connected clients, new clients above the limit are rejected. Unlimited when 0
+    */
+    pub max_connections: usize,
+    /**
+        What happens to clients above [max_connections] or when every host is at its connection limit
+    */
+    pub connection_limit_action: ConnectionLimitAction,
+    /**
+        Data sent to clients rejected because of a connection limit before they are closed (e.g. an HTTP 503 response),
+        [None] if they are closed without a response
+    */
+    pub connection_limit_response: Option<Vec<u8>>,
+    /**
+        Maximum number of accepted clients waiting for a worker thread to pick them up (per thread), new clients above
+        the limit are rejected. Unlimited when 0
+    */
+    pub max_pending_clients: usize,
+    /**
+        What happens to new clients above [max_pending_clients]
+    */
+    pub pending_overflow: PendingOverflow,
+    /**
+        Maximum number of concurrently connected clients from a single source IP. Unlimited when 0
+    */
+    pub max_connections_per_ip: usize,
+    /**
+        Maximum number of new connections per second from a single source IP. Unlimited when 0
+    */
+    pub connection_rate_per_ip: u32,
+    /**
+        Number of connections a single source IP can open at once before the rate applies, same as the rate when 0
+    */
+    pub connection_rate_burst: u32,
+    /**
+        Source IPs exceeding the connection rate are rejected for this long, [None] if only connections above the rate are rejected
+    */
+    pub connection_rate_ban: Option<Duration>,
+    /**
+        Size of the buffer used when forwarding data. Bigger buffers need less syscalls for high-throughput transfers,
+        every worker thread allocates one that is shared by all of its clients
+    */
+    pub buffer_size: usize,
+    /**
+        Port to serve Prometheus metrics on, metrics are disabled when 0
+    */
+    pub metrics_port: u16,
+    /**
+        Port of the status endpoint used to health check the balancer itself, disabled when 0
+    */
+    pub admin_port: u16,
+    /**
+        Port of the HTTP API for controlling the balancer at runtime (only reachable from localhost), disabled when 0
+    */
+    pub api_port: u16,
+    /**
+        Sets TCP_NODELAY on client and target sockets, lowering latency of interactive protocols
+    */
+    pub nodelay: bool,
+    /**
+        Idle time after which TCP keepalive probes are sent on client and target sockets, keepalive is disabled when [None]
+    */
+    pub keepalive: Option<Duration>,
+    /**
+        Time between keepalive probes and number of unanswered probes after which the connection is dropped, so dead peers
+        (e.g. behind NAT) are detected. System defaults are used when [None]
+    */
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_probes: Option<u32>,
+    /**
+        Sets SO_REUSEADDR on listening sockets, so the balancer can be restarted while old connections linger in TIME_WAIT
+    */
+    pub reuse_address: bool,
+    /**
+        Sets SO_REUSEPORT on listening sockets, so multiple balancer processes can listen on the same port
+    */
+    pub reuse_port: bool,
+    /**
+        Clients are accepted by as many threads as there are worker threads, each on its own listening sockets (bound with
+        SO_REUSEPORT), instead of a single thread accepting all clients. Accepted clients are still handed to the least busy
+        worker thread. Only supported on Linux
+    */
+    pub reuse_port_acceptors: bool,
+    /**
+        Maximum number of connections waiting to be accepted on every listening socket
+    */
+    pub listen_backlog: i32,
+    /**
+        Sends PROXY protocol v1 header to hosts before forwarding client data, so hosts can see real client addresses
+    */
+    pub proxy_protocol: bool,
+    /**
+        Expects PROXY protocol header (v1 or v2) from every client, so the balancer can run behind another proxy.
+        Address from the header is used for logging, balancing, access lists and limits per IP
+    */
+    pub accept_proxy_protocol: bool,
+    /**
+        Connects to hosts from the client's IP (IP_TRANSPARENT, Linux only), so hosts see real client addresses without
+        PROXY protocol. Needs CAP_NET_ADMIN and routing that brings the replies of hosts back to the balancer
+    */
+    pub transparent_proxy: bool,
+    /**
+        Path to certificate chain (PEM) used to terminate TLS from clients, TLS is enabled when both [tls_cert] and [tls_key] are set
+    */
+    pub tls_cert: Option<String>,
+    /**
+        Path to private key (PEM) of the TLS certificate
+    */
+    pub tls_key: Option<String>,
+    /**
+        How clients are routed to named pools of hosts
+    */
+    pub routing: Routing,
+    /**
+        Adds [X-Forwarded-For] (client address) and [X-Forwarded-Proto] headers to the first HTTP request of every client
+    */
+    pub forwarded_headers: bool,
+    /**
+        Pool whose hosts are used by clients of the default pool while none of its own hosts can be picked, [None] if there is none.
+        Named pools pick their backup pool in the host file
+    */
+    pub fallback_pool: Option<String>,
+    /**
+        Path to the country database of client IPs (see [GeoIp]), countries are not looked up when [None]
+    */
+    pub geoip_file: Option<String>,
+    /**
+        Country codes of clients that are never allowed to connect (needs [geoip_file])
+    */
+    pub deny_countries: Vec<String>,
+    /**
+        Pools used by clients of countries instead of the default pool, as pairs of country code and pool (needs [geoip_file])
+    */
+    pub country_pools: Vec<(String, String)>,
+    /**
+        How long clients keep returning to the host they used last (after their last connection), before the balancing
+        algorithm picks their host again. Stick table is disabled when [None]
+    */
+    pub stick_table_ttl: Option<Duration>,
+    /**
+        Which clients share an entry of the stick table
+    */
+    pub stick_key: StickKey,
+    /**
+        Format of the access log, written for every closed client connection
+    */
+    pub access_log: AccessLog,
+    /**
+        File the access log is appended to ("-" for stdout), [None] writes it through the logger on info level
+    */
+    pub access_log_file: Option<String>,
+    /**
+        Interval of resolving hosts given as domain names again, so address changes of their DNS records are picked up.
+        Disabled when [None]
+    */
+    pub dns_refresh: Option<Duration>,
+    /**
+        Interval between active health checks (connecting to every host), so hosts that go down are put on cooldown
+        before clients try them. Disabled when [None]
+    */
+    pub health_check_interval: Option<Duration>,
+    /**
+        Timeout to connect to a host when health checking it
+    */
+    pub health_check_timeout: Duration,
+    /**
+        How hosts are checked, the whole check (including the HTTP response) has to finish within [health_check_timeout]
+    */
+    pub health_check: HealthCheck,
+    /**
+        Path requested by HTTP health checks
+    */
+    pub health_check_path: String,
+    /**
+        What happens to clients when every host is on cooldown
+    */
+    pub all_hosts_down: AllHostsDown,
+    /**
+        How established connections to hosts removed from the host file are handled
+    */
+    pub host_removal: HostRemoval,
+    /**
+        Protocol of balanced traffic, TCP options (e.g. TLS, routing, PROXY protocol) are ignored for UDP
+    */
+    pub protocol: Protocol,
+    /**
+        UDP client mappings with no datagrams in either direction for this long are removed
+    */
+    pub udp_session_timeout: Duration,
+    /**
+        Number of points every host (of weight 1) gets on the hash ring of the consistent hash algorithm
+    */
+    pub virtual_nodes: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            threads: 4,
+            rebalance_threshold: 0,
+            debug: true,
+            connection_timeout: Duration::from_millis(400),
+            total_connection_timeout: Duration::from_millis(4000),
+            max_connection_attempts: 0,
+            outlier_errors: 1,
+            outlier_window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+            max_cooldown: Duration::from_secs(480),
+            recovery_successes: 1,
+            idle_timeout: None,
+            connection_pool_idle: None,
+            max_connections: 0,
+            connection_limit_action: ConnectionLimitAction::Reject,
+            connection_limit_response: None,
+            max_pending_clients: 1024,
+            pending_overflow: PendingOverflow::Reject,
+            max_connections_per_ip: 0,
+            connection_rate_per_ip: 0,
+            connection_rate_burst: 0,
+            connection_rate_ban: None,
+            buffer_size: 4096,
+            metrics_port: 0,
+            admin_port: 0,
+            api_port: 0,
+            nodelay: false,
+            keepalive: None,
+            keepalive_interval: None,
+            keepalive_probes: None,
+            reuse_address: true,
+            reuse_port: false,
+            reuse_port_acceptors: false,
+            listen_backlog: 1024,
+            proxy_protocol: false,
+            accept_proxy_protocol: false,
+            transparent_proxy: false,
+            tls_cert: None,
+            tls_key: None,
+            routing: Routing::None,
+            forwarded_headers: false,
+            fallback_pool: None,
+            geoip_file: None,
+            deny_countries: vec![],
+            country_pools: vec![],
+            stick_table_ttl: None,
+            stick_key: StickKey::Ip,
+            access_log: AccessLog::None,
+            access_log_file: None,
+            dns_refresh: None,
+            health_check_interval: None,
+            health_check_timeout: Duration::from_millis(400),
+            health_check: HealthCheck::Tcp,
+            health_check_path: "/healthz".to_string(),
+            all_hosts_down: AllHostsDown::Close,
+            retry_mid_stream: true,
+            host_removal: HostRemoval::Graceful,
+            protocol: Protocol::Tcp,
+            udp_session_timeout: Duration::from_secs(30),
+            virtual_nodes: 160,
+        }
+    }
+}
+
+impl Config {
+    pub fn outlier_detection(&self) -> OutlierDetection {
+        OutlierDetection {
+            errors: self.outlier_errors,
+            window: self.outlier_window,
+            cooldown: self.cooldown,
+            max_cooldown: self.max_cooldown.max(self.cooldown),
+            successes: self.recovery_successes,
+        }
+    }
+
+    pub fn listener_options(&self) -> ListenerOptions {
+        ListenerOptions {
+            reuse_address: self.reuse_address,
+            reuse_port: self.reuse_port,
+            backlog: self.listen_backlog,
+            acceptors: if self.reuse_port_acceptors { self.threads } else { 1 },
+        }
+    }
+
+    /**
+        Returns [None] when connection rate is unlimited
+    */
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        if self.connection_rate_per_ip == 0 {
+            return None;
+        }
+
+        Some(RateLimiter::new(
+            self.connection_rate_per_ip,
+            self.connection_rate_burst,
+            self.connection_rate_ban,
+        ))
+    }
+
+    pub fn new(configfile: &str) -> Self {
+        // config file is optional, defaults are used if it's missing
+        if !Path::exists(Path::new(configfile)) {
+            return Config::default();
+        }
+
+        match Config::parse_config(configfile) {
+            Ok(c) => c,
+            Err(err) => {
+                error!("[Config] Failed to parse config file '{}' -> {}, using defaults", configfile, err);
+                Config::default()
+            }
+        }
+    }
+
+    fn parse_config(configfile: &str) -> Result<Config> {
+        let mut config = Config::default();
+
+        let file = File::open(configfile)?;
+        let bufreader = BufReader::new(file);
+
+        for line in bufreader.lines() {
+            let l = line?;
+            let l = l.trim();
+            if l.is_empty() || l.starts_with('#') {
+                continue;
+            }
+
+            // every line is in format "key = value"
+            let (key, value) = match l.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => {
+                    warn!("[Config] Invalid line: '{}'", l);
+                    continue;
+                }
+            };
+
+            match key {
+                "threads" => config.threads = Config::parse_value(key, value, config.threads, |t| *t >= 1),
+                "rebalance_threshold" => config.rebalance_threshold = Config::parse_value(key, value, config.rebalance_threshold, |_| true),
+                "debug" => config.debug = Config::parse_value(key, value, config.debug, |_| true),
+                "connection_timeout_ms" => config.connection_timeout = Config::parse_millis(key, value, config.connection_timeout),
+                "total_connection_timeout_ms" => config.total_connection_timeout = Config::parse_millis(key, value, config.total_connection_timeout),
+                "max_connection_attempts" => config.max_connection_attempts = Config::parse_value(key, value, config.max_connection_attempts, |_| true),
+                "outlier_errors" => config.outlier_errors = Config::parse_value(key, value, config.outlier_errors, |e| *e >= 1),
+                "outlier_window_secs" => config.outlier_window = Config::parse_secs(key, value, config.outlier_window),
+                "cooldown_secs" => config.cooldown = Config::parse_secs(key, value, config.cooldown),
+                "max_cooldown_secs" => config.max_cooldown = Config::parse_secs(key, value, config.max_cooldown),
+                "recovery_successes" => config.recovery_successes = Config::parse_value(key, value, config.recovery_successes, |s| *s >= 1),
+                "idle_timeout_secs" => config.idle_timeout = Config::parse_optional_secs(key, value, config.idle_timeout),
+                "connection_pool_idle_secs" => config.connection_pool_idle = Config::parse_optional_secs(key, value, config.connection_pool_idle),
+                "max_connections" => config.max_connections = Config::parse_value(key, value, config.max_connections, |_| true),
+                "connection_limit_action" => config.connection_limit_action = Config::parse_value(key, value, config.connection_limit_action, |_| true),
+                "connection_limit_response" => config.connection_limit_response = Some(Config::unescape(value)).filter(|r| !r.is_empty()),
+                "max_pending_clients" => config.max_pending_clients = Config::parse_value(key, value, config.max_pending_clients, |_| true),
+                "pending_overflow" => config.pending_overflow = Config::parse_value(key, value, config.pending_overflow, |_| true),
+                "max_connections_per_ip" => config.max_connections_per_ip = Config::parse_value(key, value, config.max_connections_per_ip, |_| true),
+                "connection_rate_per_ip" => config.connection_rate_per_ip = Config::parse_value(key, value, config.connection_rate_per_ip, |_| true),
+                "connection_rate_burst" => config.connection_rate_burst = Config::parse_value(key, value, config.connection_rate_burst, |_| true),
+                "connection_rate_ban_secs" => config.connection_rate_ban = Config::parse_optional_secs(key, value, config.connection_rate_ban),
+                "buffer_size" => config.buffer_size = Config::parse_value(key, value, config.buffer_size, |s| *s >= 1),
+                "metrics_port" => config.metrics_port = Config::parse_value(key, value, config.metrics_port, |_| true),
+                "admin_port" => config.admin_port = Config::parse_value(key, value, config.admin_port, |_| true),
+                "api_port" => config.api_port = Config::parse_value(key, value, config.api_port, |_| true),
+                "nodelay" => config.nodelay = Config::parse_value(key, value, config.nodelay, |_| true),
+                "keepalive_secs" => config.keepalive = Config::parse_optional_secs(key, value, config.keepalive),
+                "keepalive_interval_secs" => config.keepalive_interval = Config::parse_optional_secs(key, value, config.keepalive_interval),
+                "keepalive_probes" => {
+                    let probes = Config::parse_value(key, value, config.keepalive_probes.unwrap_or(0), |_| true);
+                    config.keepalive_probes = Some(probes).filter(|p| *p > 0);
+                }
+                "reuse_address" => config.reuse_address = Config::parse_value(key, value, config.reuse_address, |_| true),
+                "reuse_port" => config.reuse_port = Config::parse_value(key, value, config.reuse_port, |_| true),
+                "reuse_port_acceptors" => config.reuse_port_acceptors = Config::parse_value(key, value, config.reuse_port_acceptors, |_| true),
+                "listen_backlog" => config.listen_backlog = Config::parse_value(key, value, config.listen_backlog, |b| *b >= 1),
+                "proxy_protocol" => config.proxy_protocol = Config::parse_value(key, value, config.proxy_protocol, |_| true),
+                "accept_proxy_protocol" => config.accept_proxy_protocol = Config::parse_value(key, value, config.accept_proxy_protocol, |_| true),
+                "transparent_proxy" => config.transparent_proxy = Config::parse_value(key, value, config.transparent_proxy, |_| true),
+                "tls_cert" => config.tls_cert = Some(value.to_string()),
+                "tls_key" => config.tls_key = Some(value.to_string()),
+                "routing" => config.routing = Config::parse_value(key, value, config.routing, |_| true),
+                "forwarded_headers" => config.forwarded_headers = Config::parse_value(key, value, config.forwarded_headers, |_| true),
+                "access_log" => config.access_log = Config::parse_value(key, value, config.access_log, |_| true),
+                "access_log_file" => config.access_log_file = Some(value.to_string()),
+                "fallback_pool" => config.fallback_pool = Some(value.to_lowercase()),
+                "geoip_file" => config.geoip_file = Some(value.to_string()),
+                "deny_countries" => config.deny_countries = value.split(',').map(|c| c.trim().to_uppercase()).filter(|c| !c.is_empty()).collect(),
+                "country_pools" => config.country_pools = Config::parse_country_pools(value),
+                "stick_table_ttl_secs" => config.stick_table_ttl = Config::parse_optional_secs(key, value, config.stick_table_ttl),
+                "stick_key" => config.stick_key = Config::parse_value(key, value, config.stick_key, |_| true),
+                "dns_refresh_secs" => config.dns_refresh = Config::parse_optional_secs(key, value, config.dns_refresh),
+                "health_check_interval_secs" => config.health_check_interval = Config::parse_optional_secs(key, value, config.health_check_interval),
+                "health_check_timeout_ms" => config.health_check_timeout = Config::parse_millis(key, value, config.health_check_timeout),
+                "health_check" => config.health_check = Config::parse_value(key, value, config.health_check, |_| true),
+                "health_check_path" => {
+                    config.health_check_path =
+                        Config::parse_value(key, value, config.health_check_path, |p| p.starts_with('/') && !p.contains(char::is_whitespace))
+                }
+                "retry_mid_stream" => config.retry_mid_stream = Config::parse_value(key, value, config.retry_mid_stream, |_| true),
+                "all_hosts_down" => config.all_hosts_down = Config::parse_value(key, value, config.all_hosts_down, |_| true),
+                "host_removal" => config.host_removal = Config::parse_value(key, value, config.host_removal, |_| true),
+                "protocol" => config.protocol = Config::parse_value(key, value, config.protocol, |_| true),
+                "udp_session_timeout_secs" => config.udp_session_timeout = Config::parse_secs(key, value, config.udp_session_timeout),
+                "virtual_nodes" => config.virtual_nodes = Config::parse_value(key, value, config.virtual_nodes, |n| *n >= 1),
+                _ => warn!("[Config] Unknown key '{}'", key),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /**
+        Parses comma-separated pairs of country code and pool (e.g. "de=eu, fr=eu, us=us")
+    */
+    fn parse_country_pools(value: &str) -> Vec<(String, String)> {
+        value
+            .split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .filter_map(|p| match p.split_once('=') {
+                Some((country, pool)) if !country.trim().is_empty() && !pool.trim().is_empty() => {
+                    Some((country.trim().to_uppercase(), pool.trim().to_lowercase()))
+                }
+                _ => {
+                    warn!("[Config] Invalid country pool '{}', expected <country>=<pool>", p);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /**
+        Replaces escape sequences "\r", "\n", "\t" and "\\" with the characters they stand for, so values can contain line breaks
+    */
+    fn unescape(value: &str) -> Vec<u8> {
+        let mut unescaped = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some(e @ ('r' | 'n' | 't' | '\\'))) => {
+                    chars.next();
+                    unescaped.push(match e {
+                        'r' => '\r',
+                        'n' => '\n',
+                        't' => '\t',
+                        _ => '\\',
+                    });
+                }
+                // unknown escapes are kept as they are
+                _ => unescaped.push(c),
+            }
+        }
+        unescaped.into_bytes()
+    }
+
+    /**
+        Parses and validates the value, the default value is returned (and error logged) if value is invalid
+    */
+    fn parse_value<T: FromStr + std::fmt::Display>(key: &str, value: &str, default: T, is_valid: fn(&T) -> bool) -> T {
+        match value.parse::<T>() {
+            Ok(v) if is_valid(&v) => v,
+            _ => {
+                warn!("[Config] Invalid value '{}' for '{}', using default value {}", value, key, default);
+                default
+            }
+        }
+    }
+
+    fn parse_millis(key: &str, value: &str, default: Duration) -> Duration {
+        let ms = Config::parse_value(key, value, default.as_millis() as u64, |ms| *ms > 0);
+        Duration::from_millis(ms)
+    }
+
+    fn parse_secs(key: &str, value: &str, default: Duration) -> Duration {
+        let secs = Config::parse_value(key, value, default.as_secs(), |s| *s > 0);
+        Duration::from_secs(secs)
+    }
+
+    /**
+        Parses duration in seconds, where 0 means the feature is disabled
+    */
+    fn parse_optional_secs(key: &str, value: &str, default: Option<Duration>) -> Option<Duration> {
+        let secs = Config::parse_value(key, value, default.map(|d| d.as_secs()).unwrap_or(0), |_| true);
+        if secs > 0 {
+            Some(Duration::from_secs(secs))
+        } else {
+            None
+        }
+    }
+}
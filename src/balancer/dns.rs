@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use super::Endpoint;
+use super::HostManager;
+use super::SharedPools;
+
+// changed address has to be resolved this many times in a row before the host is updated (so flapping records are ignored)
+const DNS_CONFIRMATIONS: u32 = 2;
+
+pub type SharedHostManager = Arc<RwLock<HostManager>>;
+
+/**
+    Periodically resolves hosts that were given as domain names again, so balancer follows changes of their DNS records
+*/
+pub struct DnsResolver {
+    /**
+        Hosts currently used by the balancer, kept up to date when host file is reloaded
+    */
+    host_manager: SharedHostManager,
+    pools: SharedPools,
+    /**
+        Changed addresses waiting to be confirmed with the number of times they were resolved, keyed by pool name
+        (empty for the default pool) and host name
+    */
+    pending: HashMap<(String, String), (Vec<Endpoint>, u32)>,
+}
+
+impl DnsResolver {
+    pub fn new(host_manager: SharedHostManager, pools: SharedPools) -> Self {
+        DnsResolver {
+            host_manager,
+            pools,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn spawn(mut self, interval: Duration, stopped: Arc<RwLock<bool>>) {
+        thread::spawn(move || {
+            let mut next_check = Instant::now() + interval;
+
+            loop {
+                // keep checking if balancer has been stopped
+                if *stopped.read().unwrap() {
+                    break;
+                }
+
+                // sleep in short intervals, so we can exit quickly when stopped
+                if Instant::now() < next_check {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                next_check = Instant::now() + interval;
+
+                self.refresh();
+            }
+        });
+    }
+
+    fn refresh(&mut self) {
+        // resolve without holding the lock, resolving can take a while
+        let current = self.host_manager.read().unwrap().clone();
+        let mut updated = current.clone();
+
+        let mut changed = self.refresh_pool("", &mut updated);
+        for (name, pool) in updated.pools.iter_mut() {
+            changed |= self.refresh_pool(name, pool);
+        }
+
+        if !changed {
+            return;
+        }
+
+        // host file could have been reloaded meanwhile, new hosts are resolved on the next refresh
+        let mut host_manager = self.host_manager.write().unwrap();
+        if *host_manager != current {
+            return;
+        }
+
+        self.pools.write().unwrap().update_hosts(updated.clone());
+        *host_manager = updated;
+    }
+
+    /**
+        Resolves all named hosts of the pool, returns [true] if any host address was changed
+    */
+    fn refresh_pool(&mut self, pool_name: &str, pool: &mut HostManager) -> bool {
+        let mut changed = false;
+        let mut expanded: Vec<String> = vec![];
+
+        for i in 0..pool.hosts.len() {
+            let name = match &pool.names[i] {
+                Some(n) => n.clone(),
+                None => continue,
+            };
+
+            // names using all their addresses are refreshed as a whole, once all single hosts are done
+            if pool.all_addresses[i] {
+                if !expanded.contains(&name) {
+                    expanded.push(name);
+                }
+                continue;
+            }
+
+            // keep the old address if resolving fails, record could be unavailable only temporarily
+            let addr = match HostManager::resolve(&name) {
+                Some(a) => Endpoint::Tcp(a),
+                None => {
+                    debug!("[DNS] Failed to resolve host '{}', keeping address {}", name, pool.hosts[i]);
+                    continue;
+                }
+            };
+
+            // unchanged address (or address already used by another host of the pool) is not a change
+            let key = (pool_name.to_string(), name.clone());
+            if pool.hosts.contains(&addr) {
+                self.pending.remove(&key);
+                continue;
+            }
+
+            if !self.confirm(key.clone(), vec![addr.clone()]) {
+                continue;
+            }
+
+            info!("[DNS] Host '{}' changed address {} -> {}", key.1, pool.hosts[i], addr);
+            pool.hosts[i] = addr;
+            changed = true;
+        }
+
+        for name in expanded {
+            changed |= self.refresh_addresses(pool_name, pool, name);
+        }
+
+        changed
+    }
+
+    /**
+        Resolves the name of hosts using all addresses of their name, addresses that appeared are added as new hosts
+        (with the same options) and hosts whose address disappeared are removed. Returns [true] if hosts were changed
+    */
+    fn refresh_addresses(&mut self, pool_name: &str, pool: &mut HostManager, name: String) -> bool {
+        let is_host = |pool: &HostManager, i: usize| pool.all_addresses[i] && pool.names[i].as_deref() == Some(name.as_str());
+        let current: Vec<Endpoint> = (0..pool.hosts.len()).filter(|i| is_host(pool, *i)).map(|i| pool.hosts[i].clone()).collect();
+
+        // addresses already used by other hosts of the pool are left to them
+        let resolved: Vec<Endpoint> = HostManager::resolve_all(&name)
+            .into_iter()
+            .map(Endpoint::Tcp)
+            .filter(|a| current.contains(a) || !pool.hosts.contains(a))
+            .collect();
+
+        // keep the old addresses if resolving fails, record could be unavailable only temporarily
+        if resolved.is_empty() {
+            debug!("[DNS] Failed to resolve host '{}', keeping its {} addresses", name, current.len());
+            return false;
+        }
+
+        let key = (pool_name.to_string(), name.clone());
+        if resolved.len() == current.len() && resolved.iter().all(|a| current.contains(a)) {
+            self.pending.remove(&key);
+            return false;
+        }
+
+        if !self.confirm(key.clone(), resolved.clone()) {
+            return false;
+        }
+
+        let list = |addrs: &[Endpoint]| addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ");
+        info!("[DNS] Host '{}' changed addresses [{}] -> [{}]", name, list(&current), list(&resolved));
+
+        // new hosts copy options of an existing one, so they are added before the old ones are removed
+        let template = (0..pool.hosts.len()).find(|i| is_host(pool, *i)).unwrap();
+        for addr in resolved.iter().filter(|a| !current.contains(a)) {
+            pool.push_host_like(addr.clone(), template);
+        }
+        for addr in current.iter().filter(|a| !resolved.contains(a)) {
+            pool.remove_host(addr);
+        }
+
+        true
+    }
+
+    /**
+        Counts how many times in a row the name resolved to the same changed addresses, returns [true] once the change
+        is confirmed (see [DNS_CONFIRMATIONS])
+    */
+    fn confirm(&mut self, key: (String, String), addrs: Vec<Endpoint>) -> bool {
+        let confirmations = match self.pending.get(&key) {
+            Some((a, c)) if *a == addrs => c + 1,
+            _ => 1,
+        };
+        if confirmations < DNS_CONFIRMATIONS {
+            self.pending.insert(key, (addrs, confirmations));
+            return false;
+        }
+
+        self.pending.remove(&key);
+        true
+    }
+}
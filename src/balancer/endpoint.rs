@@ -0,0 +1,46 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/**
+    Address of a host or listener, either a TCP address or a path of a Unix domain socket (written as "unix:/path")
+*/
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /**
+        Parses "unix:/path" into a Unix socket endpoint, returns [None] for anything else
+    */
+    pub fn parse_unix(value: &str) -> Option<Endpoint> {
+        match value.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Some(Endpoint::Unix(PathBuf::from(path))),
+            _ => None,
+        }
+    }
+
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Endpoint::Tcp(addr) => Some(*addr),
+            Endpoint::Unix(_) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Tcp(addr)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
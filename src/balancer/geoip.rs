@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Result;
+use std::net::IpAddr;
+
+use log::{error, info, warn};
+
+use super::access_list::parse_cidr;
+use super::access_list::to_u128;
+
+/**
+    Country database of client IPs, loaded from a text file with an IP address (or CIDR range) and a country code on every line,
+    e.g. "203.0.113.0/24,DE" (country CSV from MaxMind GeoLite2 and similar databases can be converted to it). Ranges are not
+    expected to overlap, IPs that are not in any range have no country
+*/
+pub struct GeoIp {
+    /**
+        Sorted by start, every range holds the index of its country in [countries]
+    */
+    ranges: Vec<(u128, u128, usize)>,
+    countries: Vec<String>,
+    /**
+        Clients of these countries are never allowed to connect
+    */
+    deny: Vec<String>,
+    /**
+        Pools used by clients of countries (instead of the default pool)
+    */
+    pools: HashMap<String, String>,
+}
+
+impl GeoIp {
+    /**
+        Loads the database from given file, countries are case-insensitive. Database is empty if the file can't be read
+    */
+    pub fn new(file: &str, deny: &[String], pools: &[(String, String)]) -> Self {
+        let mut geoip = GeoIp {
+            ranges: vec![],
+            countries: vec![],
+            deny: deny.iter().map(|c| c.to_uppercase()).collect(),
+            pools: pools.iter().map(|(c, p)| (c.to_uppercase(), p.clone())).collect(),
+        };
+
+        if let Err(err) = geoip.load(file) {
+            error!("[Parser] Failed to parse GeoIP database '{}' -> {}", file, err);
+        }
+
+        geoip
+    }
+
+    fn load(&mut self, file: &str) -> Result<()> {
+        let f = File::open(file)?;
+        let bufreader = BufReader::new(f);
+
+        let mut indexes: HashMap<String, usize> = HashMap::new();
+        for line in bufreader.lines() {
+            let l = line?;
+            let l = l.trim();
+            if l.is_empty() || l.starts_with('#') {
+                continue;
+            }
+
+            let parsed = l
+                .split_once(|c: char| c == ',' || c.is_whitespace())
+                .map(|(r, c)| (r.trim(), c.trim().to_uppercase()))
+                .filter(|(_, c)| !c.is_empty())
+                .and_then(|(r, c)| Some((parse_cidr(r)?, c)));
+
+            match parsed {
+                Some(((start, end), country)) => {
+                    let index = *indexes.entry(country).or_insert_with_key(|c| {
+                        self.countries.push(c.clone());
+                        self.countries.len() - 1
+                    });
+                    self.ranges.push((start, end, index));
+                }
+                None => warn!("[Parser] Invalid GeoIP record: '{}'", l),
+            }
+        }
+
+        self.ranges.sort_unstable();
+
+        info!(
+            "[Parser] Registered {} IP ranges of {} countries from '{}'",
+            self.ranges.len(),
+            self.countries.len(),
+            file
+        );
+        Ok(())
+    }
+
+    /**
+        Returns country code of given IP (uppercase), [None] if it's not in the database
+    */
+    pub fn get_country(&self, ip: IpAddr) -> Option<&str> {
+        let ip = to_u128(ip);
+
+        // find the last range starting at or before the IP
+        let index = self.ranges.partition_point(|r| r.0 <= ip);
+        let (_, end, country) = self.ranges.get(index.checked_sub(1)?)?;
+        Some(self.countries[*country].as_str()).filter(|_| *end >= ip)
+    }
+
+    pub fn is_denied(&self, ip: IpAddr) -> bool {
+        match self.get_country(ip) {
+            Some(country) => self.deny.iter().any(|c| c == country),
+            None => false,
+        }
+    }
+
+    /**
+        Returns pool of the country of given IP, [None] if its country has no pool
+    */
+    pub fn get_pool(&self, ip: IpAddr) -> Option<&str> {
+        self.get_country(ip).and_then(|c| self.pools.get(c)).map(|p| p.as_str())
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::prelude::*;
+use std::io::Result;
+use std::net;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use super::http;
+use super::http::ResponseStatus;
+use super::Endpoint;
+use super::HealthCheck;
+
+// responses without a complete status line within this many bytes fail the health check
+const MAX_STATUS_LINE: usize = 8192;
+
+/**
+    Checks if host is healthy within the timeout. Unix domain sockets are local, so they are connected to without a timeout
+*/
+pub fn check_health(addr: &Endpoint, check: HealthCheck, path: &str, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    match addr {
+        Endpoint::Tcp(a) => match net::TcpStream::connect_timeout(a, timeout) {
+            Ok(stream) => check == HealthCheck::Tcp || check_http(stream, &a.to_string(), path, deadline),
+            Err(_) => false,
+        },
+        #[cfg(unix)]
+        Endpoint::Unix(p) => match std::os::unix::net::UnixStream::connect(p) {
+            Ok(stream) => check == HealthCheck::Tcp || check_http(stream, "localhost", path, deadline),
+            Err(_) => false,
+        },
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => false,
+    }
+}
+
+/**
+    Sends GET request for the path and waits for the status line of the response, host is healthy if status is 2xx or 3xx.
+    Rest of the response is ignored
+*/
+fn check_http<S: HealthStream>(mut stream: S, host: &str, path: &str, deadline: Instant) -> bool {
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+    let timeout = match remaining(deadline) {
+        Some(t) => t,
+        None => return false,
+    };
+    if stream.set_timeouts(timeout).and_then(|_| stream.write_all(request.as_bytes())).is_err() {
+        return false;
+    }
+
+    let mut response = vec![];
+    let mut buffer = [0u8; 1024];
+    loop {
+        match http::parse_status(&response) {
+            ResponseStatus::Parsed(Some(status)) => {
+                if !(200..400).contains(&status) {
+                    debug!("[Health] Host {} responded to health check with status {}", host, status);
+                }
+                return (200..400).contains(&status);
+            }
+            ResponseStatus::Parsed(None) => {
+                debug!("[Health] Host {} responded to health check with invalid HTTP response", host);
+                return false;
+            }
+            ResponseStatus::Incomplete if response.len() > MAX_STATUS_LINE => return false,
+            ResponseStatus::Incomplete => {}
+        }
+
+        // every read only waits for the time left until the deadline
+        let timeout = match remaining(deadline) {
+            Some(t) => t,
+            None => return false,
+        };
+        let read = stream.set_timeouts(timeout).and_then(|_| stream.read(&mut buffer));
+        match read {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => response.extend_from_slice(&buffer[..n]),
+        }
+    }
+}
+
+fn remaining(deadline: Instant) -> Option<Duration> {
+    let now = Instant::now();
+    // zero timeouts are rejected by sockets
+    if now >= deadline {
+        None
+    } else {
+        Some(deadline - now)
+    }
+}
+
+/**
+    Blocking stream a health check request is sent over
+*/
+trait HealthStream: Read + Write {
+    fn set_timeouts(&self, timeout: Duration) -> Result<()>;
+}
+
+impl HealthStream for net::TcpStream {
+    fn set_timeouts(&self, timeout: Duration) -> Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+#[cfg(unix)]
+impl HealthStream for std::os::unix::net::UnixStream {
+    fn set_timeouts(&self, timeout: Duration) -> Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
@@ -6,32 +6,107 @@ use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 use std::path::Path;
 use std::str;
+use std::time::Duration;
 
+use log::{error, info, warn};
+
+use super::Algorithm;
+use super::Endpoint;
+
+#[derive(Clone, PartialEq)]
 pub struct HostManager {
-    pub hosts: Vec<SocketAddr>,
+    pub hosts: Vec<Endpoint>,
+    /**
+        Original names of hosts that were given as domain names (so they can be resolved again), indexed the same as [hosts].
+        Hosts given as literal IP addresses have [None]
+    */
+    pub names: Vec<Option<String>>,
+    /**
+        Weights of hosts, indexed the same as [hosts]
+    */
+    pub weights: Vec<u32>,
+    /**
+        Priority tiers of hosts, indexed the same as [hosts]. Hosts with lower value are preferred (1 is the default)
+    */
+    pub priorities: Vec<u32>,
+    /**
+        Connection timeouts of hosts overriding the global connection timeout, indexed the same as [hosts]. [None] uses the global one
+    */
+    pub connect_timeouts: Vec<Option<Duration>>,
+    /**
+        Whether hosts are draining (not picked for new clients, but their connections are left to finish), indexed the same as [hosts]
+    */
+    pub draining: Vec<bool>,
+    /**
+        Maximum number of established connections of hosts (within their pool), indexed the same as [hosts]. [None] is unlimited
+    */
+    pub max_connections: Vec<Option<usize>>,
+    /**
+        Whether hosts given as domain names use every address the name resolves to (each address is a host of its own,
+        with the same name and options), indexed the same as [hosts]
+    */
+    pub all_addresses: Vec<bool>,
+    /**
+        Named pools of hosts (e.g. for routing by server name), hosts listed before any pool section belong to the default pool above
+    */
+    pub pools: Vec<(String, HostManager)>,
+    /**
+        Balancing algorithm of a named pool, [None] uses the algorithm picked on the command line
+    */
+    pub algorithm: Option<Algorithm>,
+    /**
+        Pool whose hosts are used while no host of this pool can be picked (all are down, on cooldown, draining or full),
+        [None] if there is no backup pool
+    */
+    pub fallback: Option<String>,
 }
 
 impl HostManager {
     pub fn new(hostfile: &str) -> Self {
         if !Path::exists(Path::new(hostfile)) {
-            println!("[Parser] Host file '{}' does not exist. Please create it and try again.", hostfile);
+            error!("[Parser] Host file '{}' does not exist. Please create it and try again.", hostfile);
 
-            return HostManager { hosts: vec![] };
+            return HostManager::empty();
         }
 
-        let hosts = match HostManager::parse_hosts(hostfile) {
+        match HostManager::parse_hosts(hostfile) {
             Ok(h) => h,
             Err(err) => {
-                println!("[Parser] Failed to parse host file '{}' -> {}", hostfile, err.to_string());
-                vec![]
+                error!("[Parser] Failed to parse host file '{}' -> {}", hostfile, err);
+                HostManager::empty()
             }
-        };
+        }
+    }
 
-        return HostManager { hosts: hosts };
+    /**
+        Creates the default pool with given hosts (default options for all of them), duplicates are ignored
+    */
+    pub fn from_hosts(hosts: Vec<Endpoint>) -> Self {
+        let mut manager = HostManager::empty();
+        for host in hosts {
+            manager.add_host(host);
+        }
+        manager
     }
 
-    fn parse_hosts(hostfile: &str) -> Result<Vec<SocketAddr>> {
-        let mut hosts: Vec<SocketAddr> = vec![];
+    fn empty() -> Self {
+        HostManager {
+            hosts: vec![],
+            names: vec![],
+            weights: vec![],
+            priorities: vec![],
+            connect_timeouts: vec![],
+            draining: vec![],
+            max_connections: vec![],
+            all_addresses: vec![],
+            pools: vec![],
+            algorithm: None,
+            fallback: None,
+        }
+    }
+
+    fn parse_hosts(hostfile: &str) -> Result<HostManager> {
+        let mut manager = HostManager::empty();
 
         let file = File::open(hostfile)?;
         let bufreader = BufReader::new(file);
@@ -39,37 +114,337 @@ impl HostManager {
         for line in bufreader.lines() {
             let l = line?;
             let l = l.trim();
-            if l.len() < 2 {
+            if l.is_empty() || l.starts_with('#') {
                 continue;
             }
 
-            // validate IP address and port - either IPv4 or IPv6 with valid port number
-            // this also accepts domains and tries to resolve them, the first resolved IP is used
-            let addr: Vec<SocketAddr> = match l.to_socket_addrs() {
-                Ok(a) => a.collect(),
-                Err(_) => {
-                    println!("[Parser] Invalid host: '{}'", l);
+            // section header starts a named pool (e.g. "[api.example.com]"), following hosts belong to it.
+            // header can be followed by options of the pool (e.g. "[api.example.com] algorithm=least_connections fallback=api-backup").
+            // IPv6 hosts start with a bracket too (e.g. "[::1]:5000"), but their bracket is followed by the port
+            let is_ipv6_host = l.split_once(']').is_some_and(|(_, rest)| rest.starts_with(':'));
+            if let Some(header) = l.strip_prefix('[').filter(|_| !is_ipv6_host) {
+                let (name, options) = header.split_once(']').unwrap_or(("", ""));
+                let name = name.trim().to_lowercase();
+                if name.is_empty() || manager.pools.iter().any(|(n, _)| *n == name) {
+                    warn!("[Parser] Invalid or duplicate pool: '{}'", l);
                     continue;
                 }
+
+                let mut pool = HostManager::empty();
+                for option in options.split_whitespace() {
+                    if let Some(f) = option.strip_prefix("fallback=") {
+                        pool.fallback = Some(f.to_lowercase());
+                        continue;
+                    }
+                    match option.strip_prefix("algorithm=").map(|a| a.parse::<Algorithm>()) {
+                        Some(Ok(a)) => pool.algorithm = Some(a),
+                        Some(Err(_)) => warn!("[Parser] Invalid algorithm '{}' for pool '{}', using the default one", option, name),
+                        None => warn!("[Parser] Unknown option '{}' for pool '{}'", option, name),
+                    }
+                }
+
+                manager.pools.push((name, pool));
+                continue;
+            }
+
+            let pool = match manager.pools.last_mut() {
+                Some((_, p)) => p,
+                None => &mut manager,
             };
 
-            let mut resolved_addr: SocketAddr = addr[0];
+            // host is followed by optional options, separated by whitespace (e.g. "localhost:5000 weight=3")
+            let mut parts = l.split_whitespace();
+            let l = parts.next().unwrap_or(l);
 
-            // if there are more than 1 IP resolved, prioritize the IPv4
-            if addr.len() > 1 {
-                for a in addr {
-                    if a.is_ipv4() {
-                        resolved_addr = a;
-                        break;
-                    }
+            let mut weight = 1;
+            let mut priority = 1;
+            let mut connect_timeout = None;
+            let mut draining = false;
+            let mut max_connections = None;
+            let mut all_addresses = false;
+            for option in parts {
+                if let Some(w) = option.strip_prefix("weight=") {
+                    weight = HostManager::parse_weight(l, w);
+                } else if let Some(p) = option.strip_prefix("priority=") {
+                    priority = HostManager::parse_priority(l, p);
+                } else if let Some(t) = option.strip_prefix("connect_timeout=") {
+                    connect_timeout = HostManager::parse_connect_timeout(l, t);
+                } else if let Some(m) = option.strip_prefix("max_connections=") {
+                    max_connections = HostManager::parse_max_connections(l, m);
+                } else if option == "drain" {
+                    draining = true;
+                } else if option == "all_addresses" {
+                    all_addresses = true;
+                } else {
+                    warn!("[Parser] Unknown option '{}' for host '{}'", option, l);
+                }
+            }
+
+            // Unix domain sockets (e.g. "unix:/run/backend.sock") are used as they are, everything else has to resolve
+            let resolved_addr = match Endpoint::parse_unix(l).or_else(|| HostManager::resolve(l).map(Endpoint::Tcp)) {
+                Some(a) => a,
+                None => {
+                    warn!("[Parser] Invalid host: '{}'", l);
+                    continue;
                 }
+            };
+
+            // same host listed twice would get double the share of clients
+            if pool.hosts.contains(&resolved_addr) {
+                warn!("[Parser] Duplicate host '{}' ({}), ignoring it", l, resolved_addr);
+                continue;
             }
 
+            // only domain names are remembered, so they can be resolved again
+            let is_name = matches!(resolved_addr, Endpoint::Tcp(_)) && l.parse::<SocketAddr>().is_err();
+
             // push the resolved IP onto hosts list
-            hosts.push(resolved_addr);
+            pool.hosts.push(resolved_addr);
+            pool.names.push(if is_name { Some(l.to_string()) } else { None });
+            pool.weights.push(weight);
+            pool.priorities.push(priority);
+            pool.connect_timeouts.push(connect_timeout);
+            pool.draining.push(draining);
+            pool.max_connections.push(max_connections);
+            pool.all_addresses.push(all_addresses && is_name);
+
+            // names can resolve to more addresses, with [all_addresses] the rest of them get the same options as the first one
+            let first = pool.hosts.len() - 1;
+            if is_name && all_addresses {
+                for addr in HostManager::resolve_all(l).into_iter().map(Endpoint::Tcp) {
+                    if !pool.hosts.contains(&addr) {
+                        pool.push_host_like(addr, first);
+                    }
+                }
+            } else if is_name {
+                let count = HostManager::resolve_all(l).len();
+                if count > 1 {
+                    info!(
+                        "[Parser] Host '{}' resolves to {} addresses, only {} is used (add 'all_addresses' to use all of them)",
+                        l, count, pool.hosts[first]
+                    );
+                }
+            }
+        }
+
+        // backup pools are picked by name, so they can be defined after the pools using them
+        let names: Vec<String> = manager.pools.iter().map(|(n, _)| n.clone()).collect();
+        for (name, pool) in &mut manager.pools {
+            match pool.fallback.as_deref() {
+                Some(f) if f == name => warn!("[Parser] Pool '{}' can't fall back to itself, ignoring it", name),
+                Some(f) if f != "default" && !names.iter().any(|n| n == f) => {
+                    warn!("[Parser] Fallback pool '{}' of pool '{}' is not in the host file, ignoring it", f, name)
+                }
+                _ => continue,
+            }
+            pool.fallback = None;
+        }
+
+        info!("[Parser] Registered {} valid hosts", manager.hosts.len());
+        for (name, pool) in &manager.pools {
+            match pool.algorithm {
+                Some(a) => info!("[Parser] Registered {} valid hosts for pool '{}' (using {})", pool.hosts.len(), name, a),
+                None => info!("[Parser] Registered {} valid hosts for pool '{}'", pool.hosts.len(), name),
+            }
+        }
+        Ok(manager)
+    }
+
+    /**
+        Returns the named pool (or this, the default pool, when [name] is [None]), [None] if there is no such pool
+    */
+    pub fn get_pool_mut(&mut self, name: Option<&str>) -> Option<&mut HostManager> {
+        match name {
+            Some(n) => self.pools.iter_mut().find(|(p, _)| p == n).map(|(_, p)| p),
+            None => Some(self),
+        }
+    }
+
+    /**
+        Returns hosts of this pool that are marked as draining (hosts of named pools are not included)
+    */
+    pub fn get_draining_hosts(&self) -> Vec<Endpoint> {
+        self.hosts.iter().zip(&self.draining).filter(|(_, d)| **d).map(|(h, _)| h.clone()).collect()
+    }
+
+    /**
+        Adds host with default weight, priority and connect timeout (not draining, no connection limit), returns [false] if host is already present
+    */
+    pub fn add_host(&mut self, host: Endpoint) -> bool {
+        if self.hosts.contains(&host) {
+            return false;
+        }
+
+        self.hosts.push(host);
+        self.names.push(None);
+        self.weights.push(1);
+        self.priorities.push(1);
+        self.connect_timeouts.push(None);
+        self.draining.push(false);
+        self.max_connections.push(None);
+        self.all_addresses.push(false);
+        true
+    }
+
+    /**
+        Adds host with the same name and options as the host at given index (e.g. another address of the same domain name)
+    */
+    pub fn push_host_like(&mut self, host: Endpoint, index: usize) {
+        self.hosts.push(host);
+        self.names.push(self.names[index].clone());
+        self.weights.push(self.weights[index]);
+        self.priorities.push(self.priorities[index]);
+        self.connect_timeouts.push(self.connect_timeouts[index]);
+        self.draining.push(self.draining[index]);
+        self.max_connections.push(self.max_connections[index]);
+        self.all_addresses.push(self.all_addresses[index]);
+    }
+
+    /**
+        Removes host (together with all its options), returns [false] if host is not present
+    */
+    pub fn remove_host(&mut self, host: &Endpoint) -> bool {
+        let index = match self.hosts.iter().position(|h| h == host) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        self.hosts.remove(index);
+        self.names.remove(index);
+        self.weights.remove(index);
+        self.priorities.remove(index);
+        self.connect_timeouts.remove(index);
+        self.draining.remove(index);
+        self.max_connections.remove(index);
+        self.all_addresses.remove(index);
+        true
+    }
+
+    /**
+        Validates IP address and port - either IPv4 or IPv6 with valid port number.
+        This also accepts domains and tries to resolve them, IPv4 is preferred when multiple IPs are resolved
+    */
+    pub fn resolve(host: &str) -> Option<SocketAddr> {
+        let addr: Vec<SocketAddr> = host.to_socket_addrs().ok()?.collect();
+
+        // if there are more than 1 IP resolved, prioritize the IPv4
+        addr.iter().find(|a| a.is_ipv4()).or_else(|| addr.first()).copied()
+    }
+
+    /**
+        Resolves all addresses of the host (without duplicates), IPv4 addresses come first. Empty if host doesn't resolve
+    */
+    pub fn resolve_all(host: &str) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = vec![];
+        for addr in host.to_socket_addrs().into_iter().flatten() {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+
+        addrs.sort_by_key(|a| !a.is_ipv4());
+        addrs
+    }
+
+    fn parse_weight(host: &str, weight: &str) -> u32 {
+        // weight has to be a positive number, otherwise default weight is used
+        match weight.parse::<u32>() {
+            Ok(w) if w > 0 => w,
+            _ => {
+                warn!("[Parser] Invalid weight '{}' for host '{}', using weight 1", weight, host);
+                1
+            }
+        }
+    }
+
+    fn parse_priority(host: &str, priority: &str) -> u32 {
+        // priority has to be a positive number, otherwise default priority is used
+        match priority.parse::<u32>() {
+            Ok(p) if p > 0 => p,
+            _ => {
+                warn!("[Parser] Invalid priority '{}' for host '{}', using priority 1", priority, host);
+                1
+            }
+        }
+    }
+
+    fn parse_max_connections(host: &str, max_connections: &str) -> Option<usize> {
+        // limit has to be a positive number, otherwise host has no limit
+        match max_connections.parse::<usize>() {
+            Ok(m) if m > 0 => Some(m),
+            _ => {
+                warn!(
+                    "[Parser] Invalid max connections '{}' for host '{}', host has no connection limit",
+                    max_connections, host
+                );
+                None
+            }
+        }
+    }
+
+    fn parse_connect_timeout(host: &str, timeout: &str) -> Option<Duration> {
+        // timeout (in milliseconds) has to be a positive number, otherwise the global connection timeout is used
+        match timeout.parse::<u64>() {
+            Ok(t) if t > 0 => Some(Duration::from_millis(t)),
+            _ => {
+                warn!(
+                    "[Parser] Invalid connect timeout '{}' for host '{}', using the global connection timeout",
+                    timeout, host
+                );
+                None
+            }
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::testing::temp_file;
+
+    fn tcp(host: &str) -> Endpoint {
+        Endpoint::Tcp(host.parse().unwrap())
+    }
+
+    #[test]
+    fn skips_comments_blanks_duplicates_and_invalid_lines() {
+        let file = temp_file(
+            "hosts-mixed",
+            "# backends of the default pool\n\
+             127.0.0.1:5000 weight=3\n\
+             \n\
+             \x20   \n\
+             \x20 # indented comment\n\
+             127.0.0.2:5000\n\
+             127.0.0.1:5000 weight=5\n\
+             999.0.0.1:5000\n\
+             not-a-host\n\
+             [::1]:5001 priority=2\n",
+        );
+        let manager = HostManager::new(&file);
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(manager.hosts, vec![tcp("127.0.0.1:5000"), tcp("127.0.0.2:5000"), tcp("[::1]:5001")]);
+        // the first one of the duplicates is kept, together with its options
+        assert_eq!(manager.weights, vec![3, 1, 1]);
+        assert_eq!(manager.priorities, vec![1, 1, 2]);
+        assert_eq!(manager.names, vec![None, None, None]);
+    }
+
+    #[test]
+    fn duplicates_are_detected_per_pool() {
+        let file = temp_file("hosts-pools", "127.0.0.1:5000\n[api]\n127.0.0.1:5000\n127.0.0.1:5000\n");
+        let manager = HostManager::new(&file);
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(manager.hosts, vec![tcp("127.0.0.1:5000")]);
+        assert_eq!(manager.pools.len(), 1);
+        assert_eq!(manager.pools[0].1.hosts, vec![tcp("127.0.0.1:5000")]);
+    }
 
-        println!("[Parser] Registered {} valid hosts", hosts.len());
-        Ok(hosts)
+    #[test]
+    fn missing_file_has_no_hosts() {
+        let manager = HostManager::new("/nonexistent/load-balancer-rust-hosts");
+        assert!(manager.hosts.is_empty());
     }
 }
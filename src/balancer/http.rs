@@ -0,0 +1,189 @@
+use std::io::prelude::*;
+use std::io::Result;
+use std::net::IpAddr;
+
+pub enum RequestHead {
+    /**
+        End of request headers was not received yet
+    */
+    Incomplete,
+    /**
+        Request headers were received, containing the host (without port) if client sent one and the path of the request
+        (empty if the request line has none)
+    */
+    Parsed(Option<String>, String),
+}
+
+/**
+    Extracts host from the [Host] header and path from the request line of the HTTP request at the start of the client data
+*/
+pub fn parse_request_head(data: &[u8]) -> RequestHead {
+    let end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(e) => e,
+        None => return RequestHead::Incomplete,
+    };
+
+    let head = String::from_utf8_lossy(&data[..end]);
+    let mut lines = head.split("\r\n");
+    let path = lines.next().and_then(|l| l.split(' ').nth(1)).map(get_path).unwrap_or_default().to_string();
+
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some(h) => h,
+            None => continue,
+        };
+
+        if name.trim().eq_ignore_ascii_case("host") {
+            return RequestHead::Parsed(strip_port(value.trim()).map(|h| h.to_lowercase()), path);
+        }
+    }
+
+    RequestHead::Parsed(None, path)
+}
+
+/**
+    Returns path of the request target, targets in absolute form (e.g. "http://example.com/api") are stripped of
+    the scheme and host
+*/
+fn get_path(target: &str) -> &str {
+    if target.starts_with('/') {
+        return target;
+    }
+
+    match target.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => "",
+    }
+}
+
+/**
+    Checks if the HTTP request at the start of the data switches the connection to another protocol, either with
+    the [Upgrade] header (e.g. WebSocket) or by being a CONNECT request. Returns [false] while request headers are incomplete
+*/
+pub fn is_upgrade_request(data: &[u8]) -> bool {
+    let end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(e) => e,
+        None => return false,
+    };
+
+    let head = String::from_utf8_lossy(&data[..end]);
+    let mut lines = head.split("\r\n");
+    if lines.next().is_some_and(|l| l.starts_with("CONNECT ")) {
+        return true;
+    }
+
+    lines
+        .filter_map(|l| l.split_once(':'))
+        .any(|(name, _)| name.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/**
+    Appends client address to the [X-Forwarded-For] header of the HTTP request at the start of the data (the header is added
+    if missing) and replaces [X-Forwarded-Proto] with given protocol. Returns [false] if end of request headers was not received yet
+*/
+pub fn add_forwarded_headers(data: &mut Vec<u8>, client: IpAddr, proto: &str) -> bool {
+    let end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(e) => e,
+        None => return false,
+    };
+
+    let mut head = Vec::with_capacity(data.len() + 64);
+    let mut forwarded_for = vec![];
+    for (i, line) in data[..end].split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let (name, value) = match line.iter().position(|b| *b == b':') {
+            // request line can contain ':' as well (e.g. in the query)
+            Some(p) if i > 0 => (line[..p].trim_ascii(), &line[p + 1..]),
+            _ => (&b""[..], &b""[..]),
+        };
+
+        // addresses of earlier proxies are kept in front of the client address, the protocol only describes the last hop
+        if name.eq_ignore_ascii_case(b"x-forwarded-for") {
+            forwarded_for.push(String::from_utf8_lossy(value.trim_ascii()).into_owned());
+            continue;
+        }
+        if name.eq_ignore_ascii_case(b"x-forwarded-proto") {
+            continue;
+        }
+
+        head.extend_from_slice(line);
+        head.extend_from_slice(b"\r\n");
+    }
+
+    forwarded_for.push(client.to_string());
+    head.extend_from_slice(format!("X-Forwarded-For: {}\r\nX-Forwarded-Proto: {}\r\n", forwarded_for.join(", "), proto).as_bytes());
+
+    // rest of the data starts with the empty line ending the headers
+    head.extend_from_slice(&data[end + 2..]);
+    *data = head;
+    true
+}
+
+fn strip_port(host: &str) -> Option<&str> {
+    // IPv6 hosts are enclosed in brackets (e.g. "[::1]:8080")
+    let host = if host.starts_with('[') {
+        &host[..host.find(']')? + 1]
+    } else {
+        host.split(':').next()?
+    };
+
+    if host.is_empty() {
+        None
+    } else {
+        Some(host)
+    }
+}
+
+pub enum ResponseStatus {
+    /**
+        Status line of the response was not received yet
+    */
+    Incomplete,
+    /**
+        Status line was received, containing the status code if it's valid
+    */
+    Parsed(Option<u16>),
+}
+
+/**
+    Extracts status code from the status line at the start of the HTTP response (e.g. "HTTP/1.1 200 OK")
+*/
+pub fn parse_status(data: &[u8]) -> ResponseStatus {
+    let end = match data.windows(2).position(|w| w == b"\r\n") {
+        Some(e) => e,
+        None => return ResponseStatus::Incomplete,
+    };
+
+    let line = String::from_utf8_lossy(&data[..end]);
+    let mut parts = line.split(' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code.parse().ok(),
+        _ => None,
+    };
+
+    ResponseStatus::Parsed(status)
+}
+
+/**
+    Reads HTTP request headers from a blocking stream (at most [max_size] bytes of them), returns method and target
+    of the request line. Both are empty if the request line is missing
+*/
+pub fn read_request_line(stream: &mut impl Read, max_size: usize) -> Result<(String, String)> {
+    // read until end of request headers
+    let mut request: Vec<u8> = vec![];
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < max_size {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    // only the request line is relevant
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("").to_string();
+    let target = request_line.next().unwrap_or("").to_string();
+    Ok((method, target))
+}
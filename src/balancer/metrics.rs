@@ -0,0 +1,243 @@
+use std::fmt::Write as FmtWrite;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+
+use super::balancer::ClientCounts;
+use super::http;
+use super::HostStats;
+use super::SharedPools;
+use super::StatsRegistry;
+use super::Throughput;
+use super::ThroughputStats;
+
+// this is used as the timeout to receive the request from a metrics client
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// maximum size of the request we are willing to read
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// name, type, help and value of every exposed per-host metric
+type HostMetric = (&'static str, &'static str, &'static str, fn(&HostStats) -> u64);
+const HOST_METRICS: [HostMetric; 8] = [
+    (
+        "load_balancer_host_cooldown",
+        "gauge",
+        "Whether host is currently on cooldown (1) or not (0)",
+        |s| s.on_cooldown as u64,
+    ),
+    ("load_balancer_host_draining", "gauge", "Whether host is draining (1) or not (0)", |s| {
+        s.draining as u64
+    }),
+    (
+        "load_balancer_host_active_connections",
+        "gauge",
+        "Number of currently established connections to host",
+        |s| s.active_connections as u64,
+    ),
+    (
+        "load_balancer_host_connections_total",
+        "counter",
+        "Number of times host was picked for a client to connect to",
+        |s| s.connections as u64,
+    ),
+    (
+        "load_balancer_host_successes_total",
+        "counter",
+        "Number of successfully established connections to host",
+        |s| s.successes as u64,
+    ),
+    ("load_balancer_host_errors_total", "counter", "Number of errors reported for host", |s| {
+        s.errors as u64
+    }),
+    ("load_balancer_host_bytes_to_host_total", "counter", "Bytes written to host", |s| {
+        s.throughput.bytes_to_targets
+    }),
+    (
+        "load_balancer_host_bytes_from_host_total",
+        "counter",
+        "Bytes read from host and written to clients",
+        |s| s.throughput.bytes_from_targets,
+    ),
+];
+
+/**
+    Minimal HTTP server that exposes balancer metrics in Prometheus text format on [GET /metrics]
+*/
+pub struct MetricsServer {
+    client_counts: ClientCounts,
+    pools: SharedPools,
+    stats: Arc<StatsRegistry>,
+    throughput: Arc<Throughput>,
+    /**
+        Bytes forwarded by clients of every worker thread (indexed by thread)
+    */
+    thread_throughput: Arc<Vec<Arc<Throughput>>>,
+    max_pending_reached: Arc<AtomicUsize>,
+}
+
+impl MetricsServer {
+    pub fn new(
+        client_counts: ClientCounts,
+        pools: SharedPools,
+        stats: Arc<StatsRegistry>,
+        throughput: Arc<Throughput>,
+        thread_throughput: Arc<Vec<Arc<Throughput>>>,
+        max_pending_reached: Arc<AtomicUsize>,
+    ) -> Self {
+        MetricsServer {
+            client_counts,
+            pools,
+            stats,
+            throughput,
+            thread_throughput,
+            max_pending_reached,
+        }
+    }
+
+    pub fn spawn(self, port: u16, stopped: Arc<RwLock<bool>>) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        listener.set_nonblocking(true)?;
+
+        info!("[Metrics] Serving metrics on port {}", port);
+
+        thread::spawn(move || loop {
+            // keep checking if balancer has been stopped
+            if *stopped.read().unwrap() {
+                break;
+            }
+
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.handle_client(stream) {
+                        error!("[Metrics] Failed to handle metrics request! {}", e);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+                Err(e) => error!("[Metrics] Failed to accept socket! {}", e),
+            }
+        });
+
+        Ok(())
+    }
+
+    fn handle_client(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let (method, path) = http::read_request_line(&mut stream, MAX_REQUEST_SIZE)?;
+
+        let response = if method == "GET" && path == "/metrics" {
+            let body = self.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        };
+
+        stream.write_all(response.as_bytes())
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counts: Vec<usize> = self.client_counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+
+        write_header(&mut out, "load_balancer_active_clients", "gauge", "Number of currently connected clients");
+        writeln!(out, "load_balancer_active_clients {}", counts.iter().sum::<usize>()).unwrap();
+
+        write_header(
+            &mut out,
+            "load_balancer_thread_clients",
+            "gauge",
+            "Number of currently connected clients per worker thread",
+        );
+        for (thread, count) in counts.iter().enumerate() {
+            writeln!(out, "load_balancer_thread_clients{{thread=\"{}\"}} {}", thread, count).unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "load_balancer_max_pending_clients",
+            "gauge",
+            "Highest number of accepted clients that were waiting for a single worker thread at once",
+        );
+        writeln!(out, "load_balancer_max_pending_clients {}", self.max_pending_reached.load(Ordering::Relaxed)).unwrap();
+
+        write_header(
+            &mut out,
+            "load_balancer_denied_clients_total",
+            "counter",
+            "Clients rejected by the allow and deny lists",
+        );
+        writeln!(out, "load_balancer_denied_clients_total {}", self.stats.denied()).unwrap();
+
+        let throughput = self.throughput.snapshot();
+        write_header(&mut out, "load_balancer_bytes_to_targets_total", "counter", "Bytes written to targets");
+        writeln!(out, "load_balancer_bytes_to_targets_total {}", throughput.bytes_to_targets).unwrap();
+        write_header(
+            &mut out,
+            "load_balancer_bytes_from_targets_total",
+            "counter",
+            "Bytes read from targets and written to clients",
+        );
+        writeln!(out, "load_balancer_bytes_from_targets_total {}", throughput.bytes_from_targets).unwrap();
+
+        let thread_throughput: Vec<ThroughputStats> = self.thread_throughput.iter().map(|t| t.snapshot()).collect();
+        write_header(
+            &mut out,
+            "load_balancer_thread_bytes_to_targets_total",
+            "counter",
+            "Bytes written to targets per worker thread",
+        );
+        for (thread, t) in thread_throughput.iter().enumerate() {
+            writeln!(
+                out,
+                "load_balancer_thread_bytes_to_targets_total{{thread=\"{}\"}} {}",
+                thread, t.bytes_to_targets
+            )
+            .unwrap();
+        }
+        write_header(
+            &mut out,
+            "load_balancer_thread_bytes_from_targets_total",
+            "counter",
+            "Bytes read from targets and written to clients per worker thread",
+        );
+        for (thread, t) in thread_throughput.iter().enumerate() {
+            writeln!(
+                out,
+                "load_balancer_thread_bytes_from_targets_total{{thread=\"{}\"}} {}",
+                thread, t.bytes_from_targets
+            )
+            .unwrap();
+        }
+
+        let stats = self.stats.snapshot(&self.pools.read().unwrap());
+        for (name, kind, help, value) in HOST_METRICS.iter() {
+            write_header(&mut out, name, kind, help);
+            for s in &stats {
+                writeln!(out, "{}{{host=\"{}\"}} {}", name, s.host, value(s)).unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
@@ -1,13 +1,80 @@
-mod client;
+mod access_list;
+mod access_log;
+mod admin;
+pub mod algorithms;
+#[allow(clippy::module_inception)]
 mod balancer;
-mod host_manager;
 mod balancing_algorithm;
-mod algorithms;
+mod client;
+mod config;
+mod dns;
+mod endpoint;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod health;
+mod host_manager;
+mod http;
+mod metrics;
 mod poller;
+mod pools;
+mod proxy_protocol;
+mod rate_limiter;
+mod sni;
+mod stats;
+mod stick_table;
+mod stream;
+mod target_pool;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod udp;
 
-pub use client::TcpClient;
+pub use access_list::AccessList;
+pub use access_log::AccessLogWriter;
+pub use admin::AdminApi;
+pub use algorithms::Algorithm;
+pub use algorithms::OutlierDetection;
 pub use balancer::LoadBalancer;
-pub use host_manager::HostManager;
+pub use balancer::LoadBalancerBuilder;
+pub use balancing_algorithm::AlgorithmFactory;
 pub use balancing_algorithm::BalancingAlgorithm;
-pub use algorithms::RoundRobin;
-pub use poller::Poller;
\ No newline at end of file
+pub use client::CloseReason;
+pub use client::IpConnectionCounts;
+pub use client::SocketOptions;
+pub use client::TcpClient;
+pub use config::AccessLog;
+pub use config::AllHostsDown;
+pub use config::Config;
+pub use config::ConnectionLimitAction;
+pub use config::HealthCheck;
+pub use config::HostRemoval;
+pub use config::PendingOverflow;
+pub use config::Protocol;
+pub use config::Routing;
+pub use config::StickKey;
+pub use dns::DnsResolver;
+pub use dns::SharedHostManager;
+pub use endpoint::Endpoint;
+#[cfg(feature = "geoip")]
+pub use geoip::GeoIp;
+pub use host_manager::HostManager;
+pub use metrics::MetricsServer;
+pub use poller::Poller;
+pub use pools::Pools;
+pub use pools::SharedPools;
+pub use rate_limiter::RateLimit;
+pub use rate_limiter::RateLimiter;
+pub use stats::BalancerStats;
+pub use stats::HostStats;
+pub use stats::StatsHandle;
+pub use stats::StatsRegistry;
+pub use stats::ThreadStats;
+pub use stats::Throughput;
+pub use stats::ThroughputStats;
+pub use stick_table::StickTable;
+pub use stream::Listener;
+pub use stream::ListenerOptions;
+pub use stream::Stream;
+pub use target_pool::TargetPool;
+pub use tls::load_server_config;
+pub use udp::UdpProxy;
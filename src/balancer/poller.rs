@@ -1,26 +1,65 @@
-use std::io::{ErrorKind, Result};
-use std::sync::{Arc, RwLock};
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::Duration;
 
-use mio::net::{TcpListener};
-use mio::{Events, Interest, Poll, Token};
+use log::{debug, error, info, warn};
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Registry, Token};
 
+use super::balancer::WAKE_TOKEN;
+use super::Endpoint;
+use super::HostManager;
+use super::Listener;
+use super::ListenerOptions;
 use super::LoadBalancer;
+use super::RateLimit;
+use super::RateLimiter;
+use super::StatsHandle;
+use super::Stream;
+
+// this token is used by the admin listener, listeners of clients use tokens equal to their index
+const ADMIN_TOKEN: Token = Token(usize::MAX - 1);
 
 pub struct Poller {
     balancer: LoadBalancer,
+    hostfile: String,
+    /**
+        Port of the status endpoint, disabled when 0
+    */
+    admin_port: u16,
+    /**
+        Socket options of TCP listeners (including the admin listener)
+    */
+    listener_options: ListenerOptions,
+    /**
+        Limits new connections per source IP (shared by all accepting threads), [None] when unlimited
+    */
+    rate_limiter: Option<Mutex<RateLimiter>>,
     should_cancel: Arc<RwLock<bool>>,
+    should_reload: Arc<AtomicBool>,
+    should_dump: Arc<AtomicBool>,
 }
 
 impl Poller {
-    pub fn new(mut balancer: LoadBalancer) -> Self {
+    pub fn new(mut balancer: LoadBalancer, hostfile: &str, admin_port: u16, listener_options: ListenerOptions, rate_limiter: Option<RateLimiter>) -> Self {
         let should_cancel = Arc::new(RwLock::new(false));
+        let should_reload = Arc::new(AtomicBool::new(false));
+        let should_dump = Arc::new(AtomicBool::new(false));
         balancer.start();
 
         let mut p = Poller {
             balancer,
+            hostfile: hostfile.to_string(),
+            admin_port,
+            listener_options,
+            rate_limiter: rate_limiter.map(Mutex::new),
             should_cancel,
+            should_reload,
+            should_dump,
         };
 
         p.initialize().unwrap();
@@ -29,74 +68,322 @@ impl Poller {
     }
 
     fn initialize(&mut self) -> Result<()> {
-        // prepare the ctrl+c handler for graceful stop
+        // prepare the ctrl+c handler for graceful stop (a program embedding the balancer may already have its own)
         let cancel = Arc::clone(&self.should_cancel);
-        ctrlc::set_handler(move || {
+        if let Err(e) = ctrlc::set_handler(move || {
             *cancel.write().unwrap() = true;
-        })
-        .expect("Failed to set Ctrl+C handler!");
+        }) {
+            warn!(
+                "[Listener] Failed to set Ctrl+C handler, listening can only be stopped with the stop handle! {}",
+                e
+            );
+        }
+
+        // prepare the SIGHUP handler for reloading the host file
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&self.should_reload))?;
+
+        // prepare the SIGUSR1 handler for logging the state of the balancer
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&self.should_dump))?;
+        #[cfg(not(unix))]
+        warn!("[Listener] State dump on SIGUSR1 is not supported on this platform");
 
         Ok(())
     }
 
-    pub fn start_listening(&mut self, listening_port: i32) -> Result<()> {
-        let addr = format!("0.0.0.0:{}", listening_port).parse().unwrap();
-        let mut listener = TcpListener::bind(addr)?;
+    /**
+        Returns the flag that stops listening (and the balancer) once set to [true], the same way Ctrl+C does
+    */
+    pub fn stop_handle(&self) -> Arc<RwLock<bool>> {
+        Arc::clone(&self.should_cancel)
+    }
+
+    /**
+        Returns a handle for taking snapshots of the balancer while it's listening, see [LoadBalancer::stats]
+    */
+    pub fn stats_handle(&self) -> StatsHandle {
+        self.balancer.stats_handle()
+    }
+
+    fn reload_hosts(&self) {
+        info!("[Listener] Reloading host file '{}'", self.hostfile);
+
+        let host_manager = HostManager::new(&self.hostfile);
+        if host_manager.hosts.is_empty() {
+            warn!("[Listener] Reloaded host file has no valid hosts, keeping the old hosts");
+            return;
+        }
+
+        self.balancer.update_hosts(host_manager);
+    }
+
+    /**
+        Returns [false] if the client opens connections too quickly. Clients without an IP (Unix domain sockets) are not limited,
+        clients behind a proxy are limited by the address of the proxy
+    */
+    fn check_rate(&self, stream: &Stream) -> bool {
+        let (limiter, addr) = match (self.rate_limiter.as_ref(), stream.peer_addr()) {
+            (Some(l), Some(a)) => (l, a),
+            _ => return true,
+        };
+
+        let ip = addr.ip().to_canonical();
+        let limit = limiter.lock().unwrap().check(ip);
+        match limit {
+            RateLimit::Allowed => true,
+            RateLimit::Banned => {
+                warn!("[Listener] Client {} exceeded the connection rate, banning it", ip);
+                false
+            }
+            RateLimit::Rejected => {
+                debug!("[Listener] Client {} exceeded the connection rate, rejecting it", addr);
+                false
+            }
+        }
+    }
+
+    /**
+        Responds to the status request with [OK] if balancer has hosts to balance clients to (or [UNAVAILABLE] if all hosts
+        are on cooldown), followed by the number of connected clients. Nothing is read from the client
+    */
+    fn respond_status(&self, mut stream: TcpStream) {
+        let status = if self.balancer.is_ready() { "OK" } else { "UNAVAILABLE" };
+        let response = format!("{} {}\n", status, self.balancer.get_client_count());
+
+        // response is tiny, so it fits into the send buffer of a fresh socket
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            warn!("[Listener] Failed to respond to status request! {}", e);
+        }
+    }
 
+    /**
+        Binds all addresses and accepts clients until stopped, clients of every address use the pool given with it
+        (or the default pool)
+    */
+    pub fn start_listening(&mut self, addrs: &[(Endpoint, Option<String>)]) -> Result<()> {
         let mut poll = Poll::new().unwrap();
         let mut events = Events::with_capacity(512);
-        poll.registry().register(&mut listener, Token(0), Interest::READABLE)?;
-        
+
+        // kernel only spreads connections between sockets bound with SO_REUSEPORT on Linux, elsewhere one of them would get all
+        let mut options = self.listener_options;
+        if options.acceptors > 1 && !cfg!(target_os = "linux") {
+            warn!("[Listener] Accepting clients on every worker thread is only supported on Linux, using a single thread");
+            options.acceptors = 1;
+        }
+        if options.acceptors > 1 {
+            options.reuse_port = true;
+        }
+
+        let mut listeners = Poller::bind_listeners(&poll, addrs, &options)?;
+
+        // other accepting threads bind TCP addresses again, Unix domain sockets are only accepted on this thread
+        let tcp_addrs: Vec<(Endpoint, Option<String>)> = addrs.iter().filter(|(a, _)| a.tcp_addr().is_some()).cloned().collect();
+        let mut acceptors = vec![];
+        if !tcp_addrs.is_empty() {
+            for _ in 1..options.acceptors {
+                let acceptor_poll = Poll::new()?;
+                let acceptor_listeners = Poller::bind_listeners(&acceptor_poll, &tcp_addrs, &options)?;
+                acceptors.push((acceptor_poll, acceptor_listeners));
+            }
+        }
+
+        // admin listener responds with the status of the balancer
+        let mut admin_listener = None;
+        if self.admin_port > 0 {
+            let addr = SocketAddr::from(([0, 0, 0, 0], self.admin_port));
+            let mut listener = self
+                .listener_options
+                .bind_tcp(addr)
+                .map_err(|e| Error::new(e.kind(), format!("{} ({})", e, addr)))?;
+            poll.registry().register(&mut listener, ADMIN_TOKEN, Interest::READABLE)?;
+            info!("[Listener] Serving status on port {}", self.admin_port);
+            admin_listener = Some(listener);
+        }
+
+        // balancer wakes us up when stopped
+        self.balancer.register_poll(poll.registry(), WAKE_TOKEN)?;
+
         // START LISTENING
-        println!("[Listener] Started listening on port {}", listening_port);
-        loop {
-            if *self.should_cancel.read().unwrap() {
-                self.balancer.stop();
-                println!("[Listener] Listening stopped");
-
-                // sleep a bit to allow all threads to exit gracefully
-                thread::sleep(Duration::from_millis(10));
+        for (addr, pool) in addrs {
+            match pool {
+                Some(p) if !self.balancer.has_pool(p) => {
+                    warn!("[Listener] Pool '{}' of {} is not in the host file, its clients use the default pool", p, addr);
+                    info!("[Listener] Started listening on {}", addr);
+                }
+                Some(p) => info!("[Listener] Started listening on {} (pool '{}')", addr, p),
+                None => info!("[Listener] Started listening on {}", addr),
+            }
+        }
+        if !acceptors.is_empty() {
+            info!("[Listener] Accepting clients on {} threads", acceptors.len() + 1);
+        }
+
+        let poller = &*self;
+        thread::scope(|scope| {
+            for (acceptor_poll, acceptor_listeners) in acceptors {
+                let tcp_addrs = &tcp_addrs;
+                scope.spawn(move || poller.run_acceptor(acceptor_poll, acceptor_listeners, tcp_addrs));
+            }
+
+            // listeners whose clients are left in the listen backlog while the connection limit is reached
+            let mut paused: Vec<usize> = vec![];
+
+            let result = loop {
+                if *poller.should_cancel.read().unwrap() {
+                    poller.balancer.stop();
+                    info!("[Listener] Listening stopped");
+
+                    // sleep a bit to allow all threads to exit gracefully
+                    thread::sleep(Duration::from_millis(10));
+                    break Ok(());
+                }
+
+                if poller.should_reload.swap(false, Ordering::Relaxed) {
+                    poller.reload_hosts();
+                }
+
+                if poller.should_dump.swap(false, Ordering::Relaxed) {
+                    poller.balancer.dump_state();
+                }
+
+                if let Err(e) = poller.resume_listeners(poll.registry(), &mut listeners, &mut paused) {
+                    break Err(e);
+                }
+
+                // poll for events here (with timeout to check of [should_cancel])
+                match poll.poll(&mut events, Some(Duration::from_millis(5))) {
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {
+                        // polling can be interrupted by any signal (e.g. SIGHUP), stopping is handled by the Ctrl+C handler
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("[Listener] Failed to poll for events! {}", e);
+                        break Ok(());
+                    }
+                };
+
+                for event in events.iter() {
+                    let token = event.token();
+                    if token == ADMIN_TOKEN {
+                        if let Some(listener) = admin_listener.as_mut() {
+                            match listener.accept() {
+                                Ok((stream, _)) => poller.respond_status(stream),
+                                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                                Err(e) => error!("[Listener] Failed to accept status request! {}", e),
+                            }
+                            poll.registry().reregister(listener, token, Interest::READABLE).unwrap();
+                        }
+                        continue;
+                    }
+
+                    poller.accept_client(poll.registry(), &mut listeners, token, addrs, &mut paused);
+                }
+            };
+
+            // other accepting threads stop together with this one
+            *poller.should_cancel.write().unwrap() = true;
+            result
+        })
+    }
+
+    /**
+        Binds all addresses and registers them with the poll, every listener is registered with token equal to its index
+    */
+    fn bind_listeners(poll: &Poll, addrs: &[(Endpoint, Option<String>)], options: &ListenerOptions) -> Result<Vec<Listener>> {
+        let mut listeners: Vec<Listener> = vec![];
+        for (i, (addr, _)) in addrs.iter().enumerate() {
+            let mut listener = Listener::bind(addr, options).map_err(|e| Error::new(e.kind(), format!("{} ({})", e, addr)))?;
+            poll.registry().register(&mut listener, Token(i), Interest::READABLE)?;
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /**
+        Accepts clients of its own listeners (bound to the same addresses as the listeners of the main thread) until stopped
+    */
+    fn run_acceptor(&self, mut poll: Poll, mut listeners: Vec<Listener>, addrs: &[(Endpoint, Option<String>)]) {
+        let mut events = Events::with_capacity(512);
+        let mut paused: Vec<usize> = vec![];
+
+        while !*self.should_cancel.read().unwrap() {
+            if let Err(e) = self.resume_listeners(poll.registry(), &mut listeners, &mut paused) {
+                error!("[Listener] Failed to accept clients again! {}", e);
                 break;
             }
 
-            // poll for events here (with timeout to check of [should_cancel])
             match poll.poll(&mut events, Some(Duration::from_millis(5))) {
                 Ok(_) => {}
-                Err(ref e) if e.kind() == ErrorKind::Interrupted => {
-                    // this handler does not get called on Windows, so we use timeout and check it outside
-                    *self.should_cancel.write().unwrap() = true;  
-                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
                 Err(e) => {
-                    println!("Failed to poll for events! {}", e.to_string());
+                    error!("[Listener] Failed to poll for events! {}", e);
                     break;
                 }
             };
 
-            if events.is_empty() {
-                continue;
-            }
-
             for event in events.iter() {
-                match event.token() {
-                    _ => {
-                        // accept a new client   
-                        let connection = match listener.accept() {
-                            Ok(c) => c,  
-                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => { continue; },
-                            Err(e) => {
-                                println!("Failed to accept socket! {}", e.to_string());
-                                continue;
-                            }
-                        };
-                        
-                        // we need to reregister to set the Interest again, othewise we won't get any more readiness events (only on Windows)
-                        poll.registry().reregister(&mut listener, Token(0), Interest::READABLE).unwrap();
-                        self.balancer.add_client(connection.0);                    
-                    }
-                }
+                self.accept_client(poll.registry(), &mut listeners, event.token(), addrs, &mut paused);
             }
         }
+    }
 
+    /**
+        Registers paused listeners again once there is room for new clients. Reregistering reports clients waiting
+        in the backlog again, as the listener is still readable
+    */
+    fn resume_listeners(&self, registry: &Registry, listeners: &mut [Listener], paused: &mut Vec<usize>) -> Result<()> {
+        if !paused.is_empty() && !self.balancer.is_queueing_clients() {
+            info!("[Listener] Accepting clients again");
+            for i in paused.drain(..) {
+                registry.reregister(&mut listeners[i], Token(i), Interest::READABLE)?;
+            }
+        }
         Ok(())
     }
+
+    /**
+        Accepts a client of the listener with given token and hands it over to the balancer. While the connection limit
+        is reached, the listener is paused instead and its clients are left in the listen backlog
+    */
+    fn accept_client(&self, registry: &Registry, listeners: &mut [Listener], token: Token, addrs: &[(Endpoint, Option<String>)], paused: &mut Vec<usize>) {
+        let listener = match listeners.get_mut(token.0) {
+            Some(l) => l,
+            None => return,
+        };
+
+        // clients are only accepted once there is room for them
+        if self.balancer.is_queueing_clients() {
+            if paused.is_empty() {
+                info!("[Listener] Connection limit reached, new clients wait until there is room for them");
+            }
+            if !paused.contains(&token.0) {
+                paused.push(token.0);
+            }
+            return;
+        }
+
+        // accept a new client
+        let stream = match listener.accept() {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                return;
+            }
+            Err(e) => {
+                error!("[Listener] Failed to accept socket! {}", e);
+                return;
+            }
+        };
+
+        // we need to reregister to set the Interest again, othewise we won't get any more readiness events (only on Windows)
+        registry.reregister(listener, token, Interest::READABLE).unwrap();
+        // rejected connections are closed once dropped
+        if !self.check_rate(&stream) {
+            return;
+        }
+
+        let pool = addrs.get(token.0).and_then(|(_, p)| p.as_deref());
+        self.balancer.add_client(stream, pool);
+    }
 }
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use super::balancer::SharedAlgorithm;
+use super::balancing_algorithm::AlgorithmFactory;
+use super::Algorithm;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+use super::StickTable;
+
+pub type SharedPools = Arc<RwLock<Pools>>;
+
+/**
+    Holds a separate balancing algorithm for the default pool and every named pool of hosts
+*/
+pub struct Pools {
+    default: SharedAlgorithm,
+    /**
+        Algorithms of named pools, together with the algorithm the pool picked in the host file
+    */
+    named: HashMap<String, (Option<Algorithm>, SharedAlgorithm)>,
+    factory: AlgorithmFactory,
+    outlier_detection: OutlierDetection,
+    /**
+        Hosts that were removed from all pools, workers drain (or close) established connections to them.
+        Host is dropped from the list when it's added back
+    */
+    removed: Vec<Endpoint>,
+    /**
+        Incremented every time hosts are removed, so workers know when to check their connections again
+    */
+    removed_generation: u64,
+    /**
+        Connection timeouts of hosts that override the global one (from any pool)
+    */
+    connect_timeouts: HashMap<Endpoint, Duration>,
+    /**
+        Hosts that were set to drain at runtime, they are not picked for new clients (in any pool) until they stop
+        draining or are removed
+    */
+    draining: Vec<Endpoint>,
+    /**
+        Hosts marked as draining in the host file (in any pool), they drain in every pool
+    */
+    host_file_draining: Vec<Endpoint>,
+    /**
+        Backup pools of named pools (and of the default pool, under [None])
+    */
+    fallbacks: HashMap<Option<String>, String>,
+    /**
+        Hosts clients used last in every pool, [None] when clients are not sticky
+    */
+    stick_table: Option<StickTable>,
+}
+
+impl Pools {
+    pub fn new(mut host_manager: HostManager, factory: AlgorithmFactory, outlier_detection: OutlierDetection) -> Self {
+        let connect_timeouts = Pools::get_connect_timeouts(&host_manager);
+        let host_file_draining = Pools::get_draining(&host_manager);
+        let fallbacks = Pools::get_fallbacks(&host_manager);
+        let named_pools = std::mem::take(&mut host_manager.pools);
+
+        let mut named = HashMap::new();
+        for (name, pool) in named_pools {
+            named.insert(name, (pool.algorithm, Pools::create(&factory, outlier_detection, pool)));
+        }
+
+        let pools = Pools {
+            default: Pools::create(&factory, outlier_detection, host_manager),
+            named,
+            factory,
+            outlier_detection,
+            removed: vec![],
+            removed_generation: 0,
+            connect_timeouts,
+            draining: vec![],
+            host_file_draining,
+            fallbacks,
+            stick_table: None,
+        };
+        pools.apply_draining();
+        pools
+    }
+
+    fn get_draining(host_manager: &HostManager) -> Vec<Endpoint> {
+        let mut draining = vec![];
+        for pool in std::iter::once(host_manager).chain(host_manager.pools.iter().map(|(_, p)| p)) {
+            for host in pool.get_draining_hosts() {
+                if !draining.contains(&host) {
+                    draining.push(host);
+                }
+            }
+        }
+        draining
+    }
+
+    /**
+        Lets algorithms of all pools know which of their hosts are draining
+    */
+    fn apply_draining(&self) {
+        for pool in self.all() {
+            let mut algorithm = pool.write().unwrap();
+            for host in algorithm.get_hosts() {
+                algorithm.set_draining(&host, self.is_draining(&host));
+            }
+        }
+    }
+
+    fn get_fallbacks(host_manager: &HostManager) -> HashMap<Option<String>, String> {
+        let mut fallbacks = HashMap::new();
+        if let Some(f) = &host_manager.fallback {
+            fallbacks.insert(None, f.clone());
+        }
+        for (name, pool) in &host_manager.pools {
+            if let Some(f) = &pool.fallback {
+                fallbacks.insert(Some(name.clone()), f.clone());
+            }
+        }
+        fallbacks
+    }
+
+    /**
+        Picks a host from the backup pools of given pool (following backup pools of backup pools), returns the name
+        of the pool the host was picked from. Used when no host of the pool itself can be picked
+    */
+    pub fn get_fallback_host(&self, name: Option<&str>, client_addr: SocketAddr) -> Option<(String, Endpoint)> {
+        // clients of unknown pools use the default pool, so they use its backup too
+        let mut pool = self.get_pool_name(name);
+        let mut visited = vec![pool.clone()];
+
+        while let Some(fallback) = self.fallbacks.get(&pool) {
+            let next = Some(fallback.clone()).filter(|f| self.named.contains_key(f));
+            if visited.contains(&next) {
+                break;
+            }
+
+            if let Some(host) = self.get(next.as_deref()).write().unwrap().get_next_host(client_addr) {
+                return Some((fallback.clone(), host));
+            }
+            visited.push(next.clone());
+            pool = next;
+        }
+        None
+    }
+
+    /**
+        Makes clients return to the host they used last, see [Pools::get_sticky_host]
+    */
+    pub fn set_stick_table(&mut self, stick_table: StickTable) {
+        self.stick_table = Some(stick_table);
+    }
+
+    /**
+        Returns host the client used last in given pool, as long as it can still be picked (it's in the pool and it's not
+        on cooldown, draining or at its connection limit). [None] also when there is no stick table
+    */
+    pub fn get_sticky_host(&self, name: Option<&str>, client_addr: SocketAddr) -> Option<Endpoint> {
+        // clients without an IP (Unix domain sockets) can't be told apart
+        let table = self.stick_table.as_ref().filter(|_| !client_addr.ip().is_unspecified())?;
+        let pool = self.get_pool_name(name);
+        let host = table.get(pool.as_deref(), client_addr.ip())?;
+
+        let algorithm = self.get(pool.as_deref());
+        let algorithm = algorithm.read().unwrap();
+        let available = algorithm.get_hosts().contains(&host) && !algorithm.is_on_cooldown(&host) && !algorithm.is_full(&host) && !self.is_draining(&host);
+        Some(host).filter(|_| available)
+    }
+
+    /**
+        Remembers the host picked for the client in given pool (when there is a stick table)
+    */
+    pub fn stick(&self, name: Option<&str>, client_addr: SocketAddr, host: &Endpoint) {
+        if let Some(table) = self.stick_table.as_ref().filter(|_| !client_addr.ip().is_unspecified()) {
+            table.insert(self.get_pool_name(name).as_deref(), client_addr.ip(), host.clone());
+        }
+    }
+
+    /**
+        Returns name of the named pool matching given name, clients of unknown pools use the default pool ([None])
+    */
+    fn get_pool_name(&self, name: Option<&str>) -> Option<String> {
+        name.map(|n| n.to_lowercase()).filter(|n| self.named.contains_key(n))
+    }
+
+    fn get_connect_timeouts(host_manager: &HostManager) -> HashMap<Endpoint, Duration> {
+        let mut timeouts = HashMap::new();
+        for pool in std::iter::once(host_manager).chain(host_manager.pools.iter().map(|(_, p)| p)) {
+            for (host, timeout) in pool.hosts.iter().zip(&pool.connect_timeouts) {
+                if let Some(t) = timeout {
+                    timeouts.entry(host.clone()).or_insert(*t);
+                }
+            }
+        }
+        timeouts
+    }
+
+    /**
+        Returns connection timeout configured for given host, [None] if the global connection timeout applies
+    */
+    pub fn get_connect_timeout(&self, host: &Endpoint) -> Option<Duration> {
+        self.connect_timeouts.get(host).copied()
+    }
+
+    fn create(factory: &AlgorithmFactory, outlier_detection: OutlierDetection, host_manager: HostManager) -> SharedAlgorithm {
+        let mut algorithm = factory(host_manager);
+        algorithm.set_outlier_detection(outlier_detection);
+        Arc::new(RwLock::new(algorithm))
+    }
+
+    /**
+        Returns algorithm of the pool matching given name, the default pool is used when there is no match
+    */
+    pub fn get(&self, name: Option<&str>) -> SharedAlgorithm {
+        let pool = name.and_then(|n| self.named.get(&n.to_lowercase())).map(|(_, a)| a);
+        Arc::clone(pool.unwrap_or(&self.default))
+    }
+
+    /**
+        Returns name of the pool given route belongs to, [None] if there is none. Routes of HTTP requests contain the path
+        (e.g. "example.com/api/users"), pools named with a path prefix (e.g. "example.com/api", or "/api" for any host)
+        take precedence over the pool of the host. The longest matching prefix is picked, the one naming the host on ties
+    */
+    pub fn find_route(&self, route: &str) -> Option<String> {
+        let route = route.to_lowercase();
+        let (host, path) = route.split_at(route.find('/').unwrap_or(route.len()));
+
+        let mut best: Option<(&String, (usize, bool))> = None;
+        for name in self.named.keys() {
+            let (pool_host, prefix) = match name.find('/') {
+                Some(i) => name.split_at(i),
+                None => continue,
+            };
+            if (!pool_host.is_empty() && pool_host != host) || !path.starts_with(prefix) {
+                continue;
+            }
+
+            let rank = (prefix.len(), !pool_host.is_empty());
+            if best.is_none_or(|(_, r)| rank > r) {
+                best = Some((name, rank));
+            }
+        }
+
+        match best {
+            Some((name, _)) => Some(name.clone()),
+            None if self.named.contains_key(host) => Some(host.to_string()),
+            None => None,
+        }
+    }
+
+    /**
+        Returns [true] if there is a named pool matching given name
+    */
+    pub fn contains(&self, name: &str) -> bool {
+        self.named.contains_key(&name.to_lowercase())
+    }
+
+    /**
+        Returns algorithms of all pools, default pool is always first
+    */
+    pub fn all(&self) -> Vec<SharedAlgorithm> {
+        let mut pools = vec![Arc::clone(&self.default)];
+        pools.extend(self.named.values().map(|(_, a)| Arc::clone(a)));
+        pools
+    }
+
+    /**
+        Returns algorithms of all pools together with pool names, default pool is always first and has no name
+    */
+    pub fn all_named(&self) -> Vec<(Option<String>, SharedAlgorithm)> {
+        let mut pools = vec![(None, Arc::clone(&self.default))];
+        pools.extend(self.named.iter().map(|(n, (_, a))| (Some(n.clone()), Arc::clone(a))));
+        pools
+    }
+
+    /**
+        Returns all hosts from all pools (without duplicates)
+    */
+    pub fn get_hosts(&self) -> Vec<Endpoint> {
+        let mut hosts: Vec<Endpoint> = vec![];
+        for pool in self.all() {
+            for host in pool.read().unwrap().get_hosts() {
+                if !hosts.contains(&host) {
+                    hosts.push(host);
+                }
+            }
+        }
+        hosts
+    }
+
+    /**
+        Starts (or stops) draining the host in every pool, returns [false] if no pool has the host.
+        Hosts marked as draining in the host file keep draining until they are unmarked there
+    */
+    pub fn set_draining(&mut self, host: &Endpoint, draining: bool) -> bool {
+        if !self.get_hosts().contains(host) {
+            return false;
+        }
+
+        self.draining.retain(|h| h != host);
+        if draining {
+            self.draining.push(host.clone());
+        }
+        self.apply_draining();
+        true
+    }
+
+    /**
+        Returns [true] if host is draining, either because it was set to drain at runtime or because of the host file
+    */
+    pub fn is_draining(&self, host: &Endpoint) -> bool {
+        self.draining.contains(host) || self.host_file_draining.contains(host)
+    }
+
+    /**
+        Returns [true] if host is draining because it's marked as draining in the host file
+    */
+    pub fn is_draining_by_host_file(&self, host: &Endpoint) -> bool {
+        self.host_file_draining.contains(host)
+    }
+
+    /**
+        Returns hosts removed from all pools, together with the number of times hosts were removed
+    */
+    pub fn get_removed_hosts(&self) -> (u64, &[Endpoint]) {
+        (self.removed_generation, &self.removed)
+    }
+
+    /**
+        Swaps hosts of all pools. Pools that no longer exist are removed, but clients already routed to them keep their connections
+    */
+    pub fn update_hosts(&mut self, mut host_manager: HostManager) {
+        let old_hosts = self.get_hosts();
+        self.connect_timeouts = Pools::get_connect_timeouts(&host_manager);
+        self.host_file_draining = Pools::get_draining(&host_manager);
+        self.fallbacks = Pools::get_fallbacks(&host_manager);
+        let named_pools = std::mem::take(&mut host_manager.pools);
+
+        self.default.write().unwrap().update_hosts(host_manager);
+
+        let mut named = HashMap::new();
+        for (name, pool) in named_pools {
+            // pools that picked a different algorithm are created again
+            let picked = pool.algorithm;
+            let algorithm = match self.named.remove(&name) {
+                Some((p, a)) if p == picked => {
+                    a.write().unwrap().update_hosts(pool);
+                    a
+                }
+                _ => Pools::create(&self.factory, self.outlier_detection, pool),
+            };
+            named.insert(name, (picked, algorithm));
+        }
+        self.named = named;
+
+        // hosts still used by any pool keep their connections
+        let hosts = self.get_hosts();
+        self.removed.retain(|h| !hosts.contains(h));
+
+        // hosts could have been marked (or unmarked) as draining, recreated and new pools don't know about them at all
+        self.draining.retain(|h| hosts.contains(h));
+        self.apply_draining();
+
+        let removed: Vec<Endpoint> = old_hosts.into_iter().filter(|h| !hosts.contains(h) && !self.removed.contains(h)).collect();
+        if !removed.is_empty() {
+            self.removed.extend(removed);
+            self.removed_generation += 1;
+        }
+    }
+}
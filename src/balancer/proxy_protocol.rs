@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+// every version 2 header starts with this signature
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// version 1 headers (including the final CRLF) are never longer than this
+const V1_MAX_LENGTH: usize = 107;
+
+pub enum ProxyHeader {
+    /**
+        Header was not fully received yet
+    */
+    Incomplete,
+    /**
+        Header of given length (in bytes) was received, containing the source address of the client.
+        Address is [None] when the proxy did not provide one (e.g. for its own health checks)
+    */
+    Parsed(usize, Option<SocketAddr>),
+    Invalid,
+}
+
+/**
+    Parses PROXY protocol header (version 1 or 2) at the start of the data sent by the upstream proxy
+*/
+pub fn parse_header(data: &[u8]) -> ProxyHeader {
+    if data.starts_with(b"PROXY ") {
+        return parse_v1(data);
+    }
+
+    if data.starts_with(V2_SIGNATURE) {
+        return parse_v2(data);
+    }
+
+    // data could still turn into a header once more of it is received
+    if b"PROXY ".starts_with(data) || V2_SIGNATURE.starts_with(data) {
+        ProxyHeader::Incomplete
+    } else {
+        ProxyHeader::Invalid
+    }
+}
+
+/**
+    Parses human-readable header, e.g. "PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n"
+*/
+fn parse_v1(data: &[u8]) -> ProxyHeader {
+    let end = match data.windows(2).position(|w| w == b"\r\n") {
+        Some(e) if e + 2 <= V1_MAX_LENGTH => e,
+        None if data.len() < V1_MAX_LENGTH => return ProxyHeader::Incomplete,
+        _ => return ProxyHeader::Invalid,
+    };
+
+    let line = match std::str::from_utf8(&data[..end]) {
+        Ok(l) => l,
+        Err(_) => return ProxyHeader::Invalid,
+    };
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    let source = match parts.as_slice() {
+        // rest of the line is ignored for unknown connections
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip = match source.parse::<IpAddr>() {
+                Ok(ip) if ip.is_ipv4() == (*protocol == "TCP4") => ip,
+                _ => return ProxyHeader::Invalid,
+            };
+            match port.parse::<u16>() {
+                Ok(p) => Some(SocketAddr::new(ip, p)),
+                Err(_) => return ProxyHeader::Invalid,
+            }
+        }
+        _ => return ProxyHeader::Invalid,
+    };
+
+    ProxyHeader::Parsed(end + 2, source)
+}
+
+/**
+    Parses binary header: signature, version and command, address family, length of addresses and the addresses
+*/
+fn parse_v2(data: &[u8]) -> ProxyHeader {
+    if data.len() < 16 {
+        return ProxyHeader::Incomplete;
+    }
+
+    let version = data[12] >> 4;
+    let command = data[12] & 0x0F;
+    let family = data[13] >> 4;
+    let length = u16::from_be_bytes([data[14], data[15]]) as usize;
+    if version != 2 || command > 1 {
+        return ProxyHeader::Invalid;
+    }
+
+    if data.len() < 16 + length {
+        return ProxyHeader::Incomplete;
+    }
+    let addresses = &data[16..16 + length];
+
+    // LOCAL command is used for connections of the proxy itself, they have no client address
+    if command == 0 {
+        return ProxyHeader::Parsed(16 + length, None);
+    }
+
+    // addresses are followed by optional TLVs, which are skipped
+    let source = match family {
+        // IPv4: source address, destination address, source port, destination port
+        1 if length >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([addresses[8], addresses[9]])))
+        }
+        // IPv6: same as IPv4, but with 16 byte addresses
+        2 if length >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                u16::from_be_bytes([addresses[32], addresses[33]]),
+            ))
+        }
+        1 | 2 => return ProxyHeader::Invalid,
+        // unspecified and Unix socket sources have no IP address
+        _ => None,
+    };
+
+    ProxyHeader::Parsed(16 + length, source)
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use std::time::Instant;
+
+// this is used as the interval between removals of IPs that are not limited anymore
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+
+/**
+    Limits the rate of new connections per source IP. Every IP gets a bucket of [burst] tokens that refills with [rate]
+    tokens per second, every connection takes one token. IPs connecting with an empty bucket are banned for [ban] (if set)
+*/
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    ban: Option<Duration>,
+    buckets: HashMap<IpAddr, Bucket>,
+    next_cleanup: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    banned_until: Option<Instant>,
+}
+
+/**
+    Outcome of a new connection, see [RateLimiter::check]
+*/
+#[derive(PartialEq, Eq, Debug)]
+pub enum RateLimit {
+    Allowed,
+    /**
+        Connection exceeds the rate and the IP is banned from now on
+    */
+    Banned,
+    /**
+        Connection exceeds the rate (or the IP is still banned)
+    */
+    Rejected,
+}
+
+impl RateLimiter {
+    /**
+        Burst of 0 means the bucket holds one second worth of connections
+    */
+    pub fn new(rate: u32, burst: u32, ban: Option<Duration>) -> Self {
+        let burst = if burst == 0 { rate } else { burst };
+
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            ban,
+            buckets: HashMap::new(),
+            next_cleanup: Instant::now() + CLEANUP_INTERVAL,
+        }
+    }
+
+    /**
+        Takes a token from the bucket of given IP
+    */
+    pub fn check(&mut self, ip: IpAddr) -> RateLimit {
+        let now = Instant::now();
+        if now >= self.next_cleanup {
+            self.remove_idle(now);
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            banned_until: None,
+        });
+
+        // tokens keep refilling while banned, so the IP starts with a full bucket once the ban expires
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        match bucket.banned_until {
+            Some(until) if now < until => return RateLimit::Rejected,
+            Some(_) => bucket.banned_until = None,
+            None => {}
+        }
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return RateLimit::Allowed;
+        }
+
+        match self.ban {
+            Some(ban) => {
+                bucket.banned_until = Some(now + ban);
+                RateLimit::Banned
+            }
+            None => RateLimit::Rejected,
+        }
+    }
+
+    /**
+        Forgets IPs that are not banned and whose bucket has refilled, they would start with a full bucket anyway
+    */
+    fn remove_idle(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, b| {
+            let tokens = b.tokens + now.duration_since(b.updated).as_secs_f64() * rate;
+            b.banned_until.is_some_and(|until| now < until) || tokens < burst
+        });
+
+        self.next_cleanup = now + CLEANUP_INTERVAL;
+    }
+}
@@ -0,0 +1,148 @@
+// TLS record content type of handshake messages
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+
+// handshake message type of ClientHello
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+
+// extension type of server name indication
+const EXTENSION_SERVER_NAME: u16 = 0;
+
+// server name type of DNS hostnames
+const NAME_TYPE_HOSTNAME: u8 = 0;
+
+pub enum ClientHello {
+    /**
+        Not enough data was received yet to parse the whole ClientHello
+    */
+    Incomplete,
+    /**
+        ClientHello was parsed, containing the server name if client sent one
+    */
+    Parsed(Option<String>),
+    /**
+        Data is not a TLS ClientHello
+    */
+    Invalid,
+}
+
+/**
+    Extracts server name (SNI) from the ClientHello at the start of the client data, without terminating TLS.
+
+    ClientHello may span multiple reads and even multiple TLS records (e.g. when it carries big key shares), so handshake
+    data of all received records is joined together and [Incomplete] is returned until the whole message was received.
+    Caller is expected to keep the data and try again once more data arrives.
+*/
+pub fn parse_client_hello(data: &[u8]) -> ClientHello {
+    let mut handshake: Vec<u8> = vec![];
+    let mut records = data;
+
+    loop {
+        // record header: content type (1), version (2), length (2)
+        if records.len() < 5 {
+            return ClientHello::Incomplete;
+        }
+        if records[0] != CONTENT_TYPE_HANDSHAKE {
+            return ClientHello::Invalid;
+        }
+
+        let length = u16::from_be_bytes([records[3], records[4]]) as usize;
+        if records.len() < 5 + length {
+            return ClientHello::Incomplete;
+        }
+        handshake.extend_from_slice(&records[5..5 + length]);
+        records = &records[5 + length..];
+
+        // handshake header: message type (1), length (3)
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+            return ClientHello::Invalid;
+        }
+
+        let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if handshake.len() < 4 + length {
+            continue;
+        }
+
+        return match parse_server_name(&handshake[4..4 + length]) {
+            Some(name) => ClientHello::Parsed(name),
+            None => ClientHello::Invalid,
+        };
+    }
+}
+
+/**
+    Parses the ClientHello message body, returns [None] if it's malformed
+*/
+fn parse_server_name(hello: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader { data: hello };
+
+    // version and random
+    reader.skip(2 + 32)?;
+
+    // session id, cipher suites and compression methods
+    let len = reader.u8()? as usize;
+    reader.skip(len)?;
+    let len = reader.u16()? as usize;
+    reader.skip(len)?;
+    let len = reader.u8()? as usize;
+    reader.skip(len)?;
+
+    // extensions are optional
+    if reader.data.is_empty() {
+        return Some(None);
+    }
+
+    let len = reader.u16()? as usize;
+    let mut extensions = Reader { data: reader.take(len)? };
+    while !extensions.data.is_empty() {
+        let extension_type = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut extension = Reader { data: extensions.take(len)? };
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let len = extension.u16()? as usize;
+        let mut names = Reader { data: extension.take(len)? };
+        while !names.data.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == NAME_TYPE_HOSTNAME {
+                return Some(std::str::from_utf8(name).ok().map(|n| n.to_lowercase()));
+            }
+        }
+    }
+
+    Some(None)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
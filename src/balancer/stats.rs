@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use super::balancer::ClientCounts;
+use super::balancer::PendingClientLists;
+use super::Endpoint;
+use super::Pools;
+use super::SharedPools;
+
+/**
+    Snapshot of statistics for a single host
+*/
+pub struct HostStats {
+    pub host: Endpoint,
+    /**
+        Number of times the host was picked for a client to connect to
+    */
+    pub connections: usize,
+    pub errors: usize,
+    pub successes: usize,
+    /**
+        Number of currently established connections to the host, a draining host is drained once it reaches 0
+    */
+    pub active_connections: usize,
+    pub on_cooldown: bool,
+    pub draining: bool,
+    /**
+        Bytes forwarded to and from the host (by all clients connected to it)
+    */
+    pub throughput: ThroughputStats,
+}
+
+#[derive(Default)]
+struct HostCounters {
+    connections: AtomicUsize,
+    errors: AtomicUsize,
+    successes: AtomicUsize,
+    active_connections: AtomicUsize,
+    throughput: Arc<Throughput>,
+}
+
+/**
+    Holds statistics for all hosts (and totals of the whole balancer). Counters are atomic, so workers only need a read lock to update them
+*/
+pub struct StatsRegistry {
+    hosts: RwLock<HashMap<Endpoint, Arc<HostCounters>>>,
+    /**
+        Clients handed over to worker threads
+    */
+    accepted: AtomicU64,
+    /**
+        Errors of all hosts, including hosts that were removed since
+    */
+    errors: AtomicU64,
+    /**
+        Clients rejected by the access list
+    */
+    denied: AtomicU64,
+}
+
+impl StatsRegistry {
+    pub fn new() -> Self {
+        StatsRegistry {
+            hosts: RwLock::new(HashMap::new()),
+            accepted: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_denied(&self) {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+
+    fn get_counters(&self, addr: &Endpoint) -> Arc<HostCounters> {
+        if let Some(c) = self.hosts.read().unwrap().get(addr) {
+            return Arc::clone(c);
+        }
+
+        // first time seeing this host, add it
+        Arc::clone(self.hosts.write().unwrap().entry(addr.clone()).or_default())
+    }
+
+    pub fn record_connection(&self, addr: &Endpoint) {
+        self.get_counters(addr).connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, addr: &Endpoint) {
+        self.get_counters(addr).errors.fetch_add(1, Ordering::Relaxed);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_success(&self, addr: &Endpoint) {
+        self.get_counters(addr).successes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connected(&self, addr: &Endpoint) {
+        self.get_counters(addr).active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_disconnected(&self, addr: &Endpoint) {
+        // never goes below 0, even if a disconnect was reported without a connect
+        let active = &self.get_counters(addr).active_connections;
+        active.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_sub(1)).unwrap_or(0);
+    }
+
+    /**
+        Returns totals of bytes forwarded to and from given host, clients connected to it add their bytes to them
+    */
+    pub fn get_throughput(&self, addr: &Endpoint) -> Arc<Throughput> {
+        Arc::clone(&self.get_counters(addr).throughput)
+    }
+
+    /**
+        Returns statistics for all hosts the pools are currently choosing from
+    */
+    pub fn snapshot(&self, pools: &Pools) -> Vec<HostStats> {
+        let hosts = self.hosts.read().unwrap();
+        let algorithms = pools.all();
+
+        let mut stats = vec![];
+        for host in pools.get_hosts() {
+            let counters = hosts.get(&host);
+            let get = |f: fn(&HostCounters) -> &AtomicUsize| counters.map(|c| f(c).load(Ordering::Relaxed)).unwrap_or(0);
+
+            let on_cooldown = algorithms.iter().any(|a| a.read().unwrap().is_on_cooldown(&host));
+            stats.push(HostStats {
+                connections: get(|c| &c.connections),
+                errors: get(|c| &c.errors),
+                successes: get(|c| &c.successes),
+                active_connections: get(|c| &c.active_connections),
+                on_cooldown,
+                draining: pools.is_draining(&host),
+                throughput: counters.map(|c| c.throughput.snapshot()).unwrap_or_default(),
+                host,
+            });
+        }
+
+        stats
+    }
+}
+
+/**
+    Snapshot of bytes forwarded through the balancer (or a part of it) since it was started
+*/
+#[derive(Clone, Copy, Default)]
+pub struct ThroughputStats {
+    /**
+        Bytes written to targets (client data and PROXY protocol headers)
+    */
+    pub bytes_to_targets: u64,
+    /**
+        Bytes read from targets and written to clients
+    */
+    pub bytes_from_targets: u64,
+}
+
+/**
+    Total bytes forwarded by a group of clients (all clients, clients of a worker thread or clients of a host).
+    Counters are atomic, as they are updated by every write on worker threads
+*/
+#[derive(Default)]
+pub struct Throughput {
+    bytes_to_targets: AtomicU64,
+    bytes_from_targets: AtomicU64,
+}
+
+impl Throughput {
+    pub fn record_to_target(&self, bytes: usize) {
+        self.bytes_to_targets.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_from_target(&self, bytes: usize) {
+        self.bytes_from_targets.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ThroughputStats {
+        ThroughputStats {
+            bytes_to_targets: self.bytes_to_targets.load(Ordering::Relaxed),
+            bytes_from_targets: self.bytes_from_targets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/**
+    Snapshot of the state of the whole balancer, see [StatsHandle::snapshot]
+*/
+pub struct BalancerStats {
+    /**
+        Worker threads, indexed by thread
+    */
+    pub threads: Vec<ThreadStats>,
+    /**
+        Clients handed over to worker threads since the balancer was started, clients rejected before that are not counted
+    */
+    pub accepted: u64,
+    /**
+        Errors of hosts since the balancer was started (failed connections, timeouts and connections lost because of the host)
+    */
+    pub errors: u64,
+    /**
+        Clients rejected by the access list (allow and deny files) since the balancer was started
+    */
+    pub denied: u64,
+    pub throughput: ThroughputStats,
+    pub hosts: Vec<HostStats>,
+}
+
+/**
+    Snapshot of a single worker thread
+*/
+pub struct ThreadStats {
+    /**
+        Clients the thread is handling
+    */
+    pub clients: usize,
+    /**
+        Clients handed over to the thread that it has not picked up yet
+    */
+    pub pending: usize,
+    pub throughput: ThroughputStats,
+}
+
+/**
+    Takes snapshots of the balancer state, it can be cloned and kept by other threads (e.g. while the poller is listening)
+*/
+#[derive(Clone)]
+pub struct StatsHandle {
+    pub(crate) client_counts: ClientCounts,
+    pub(crate) client_lists_pending: PendingClientLists,
+    pub(crate) pools: SharedPools,
+    pub(crate) stats: Arc<StatsRegistry>,
+    pub(crate) throughput: Arc<Throughput>,
+    pub(crate) thread_throughput: Arc<Vec<Arc<Throughput>>>,
+}
+
+impl StatsHandle {
+    /**
+        Only read locks are taken, so workers are not disturbed
+    */
+    pub fn snapshot(&self) -> BalancerStats {
+        let threads = self
+            .client_counts
+            .iter()
+            .zip(self.client_lists_pending.iter())
+            .zip(self.thread_throughput.iter())
+            .map(|((count, pending), throughput)| ThreadStats {
+                clients: count.load(Ordering::Relaxed),
+                pending: pending.len(),
+                throughput: throughput.snapshot(),
+            })
+            .collect();
+
+        BalancerStats {
+            threads,
+            accepted: self.stats.accepted.load(Ordering::Relaxed),
+            errors: self.stats.errors.load(Ordering::Relaxed),
+            denied: self.stats.denied(),
+            throughput: self.throughput.snapshot(),
+            hosts: self.stats.snapshot(&self.pools.read().unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::thread;
+
+    use crate::balancer::testing::{backend, hosts, wait_for, TestBalancer};
+    use crate::balancer::LoadBalancer;
+
+    #[test]
+    fn counts_connections_of_hosts() {
+        let (backend, host) = backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 2, false));
+        let server = thread::spawn(move || (0..5).map(|_| backend.accept().unwrap().0).collect::<Vec<_>>());
+
+        // target connection is started once the client sends something
+        let clients: Vec<_> = (0..5).map(|_| balancer.connect()).collect();
+        for mut client in &clients {
+            client.write_all(b"hello").unwrap();
+        }
+        let targets = server.join().unwrap();
+        wait_for("clients to connect", || balancer.balancer.host_stats()[0].active_connections == 5);
+
+        let stats = &balancer.balancer.host_stats()[0];
+        assert!(stats.host == host);
+        assert_eq!(stats.connections, 5);
+        assert_eq!(stats.successes, 5);
+        assert_eq!(stats.errors, 0);
+
+        drop(clients);
+        drop(targets);
+        balancer.wait_for_no_clients();
+        wait_for("connections to close", || balancer.balancer.host_stats()[0].active_connections == 0);
+        assert_eq!(balancer.balancer.host_stats()[0].connections, 5);
+        assert_eq!(balancer.balancer.stats().accepted, 5);
+    }
+
+    #[test]
+    fn throughput_matches_forwarded_bytes() {
+        let (backend, host) = backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 2, false));
+
+        // every client sends 10 KB and gets 3 KB back
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = backend.accept().unwrap();
+                let mut request = vec![];
+                stream.read_to_end(&mut request).unwrap();
+                assert_eq!(request.len(), 10_000);
+                stream.write_all(&[1; 3_000]).unwrap();
+            }
+        });
+
+        // both clients are connected at once, so they go to different threads
+        let clients = [balancer.connect(), balancer.connect()];
+        for mut client in &clients {
+            client.write_all(&[7; 10_000]).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut reply = vec![];
+            client.read_to_end(&mut reply).unwrap();
+            assert_eq!(reply.len(), 3_000);
+        }
+        server.join().unwrap();
+        balancer.wait_for_no_clients();
+
+        let total = balancer.balancer.throughput();
+        assert_eq!(total.bytes_to_targets, 20_000);
+        assert_eq!(total.bytes_from_targets, 6_000);
+
+        for thread in balancer.balancer.thread_throughput() {
+            assert_eq!(thread.bytes_to_targets, 10_000);
+            assert_eq!(thread.bytes_from_targets, 3_000);
+        }
+
+        let host = balancer.balancer.host_stats()[0].throughput;
+        assert_eq!(host.bytes_to_targets, 20_000);
+        assert_eq!(host.bytes_from_targets, 6_000);
+    }
+}
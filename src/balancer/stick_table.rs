@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::Endpoint;
+use super::StickKey;
+
+// this is used as the interval between removals of expired entries
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+
+/**
+    Remembers the host every client used last in each pool, so reconnecting clients get the same host again.
+    Entries expire [ttl] after the last connection of the client, they are shared by all worker threads
+*/
+pub struct StickTable {
+    ttl: Duration,
+    key: StickKey,
+    entries: RwLock<Entries>,
+}
+
+struct Entries {
+    /**
+        Host and expiry of every client key, per pool ([None] is the default pool)
+    */
+    hosts: HashMap<(Option<String>, IpAddr), (Endpoint, Instant)>,
+    next_cleanup: Instant,
+}
+
+impl StickTable {
+    pub fn new(ttl: Duration, key: StickKey) -> Self {
+        StickTable {
+            ttl,
+            key,
+            entries: RwLock::new(Entries {
+                hosts: HashMap::new(),
+                next_cleanup: Instant::now() + CLEANUP_INTERVAL,
+            }),
+        }
+    }
+
+    /**
+        Returns host the client used last in given pool, [None] if there is none or it expired
+    */
+    pub fn get(&self, pool: Option<&str>, ip: IpAddr) -> Option<Endpoint> {
+        let key = (pool.map(|p| p.to_string()), self.get_key(ip));
+        let entries = self.entries.read().unwrap();
+        let (host, expires) = entries.hosts.get(&key)?;
+        Some(host.clone()).filter(|_| Instant::now() < *expires)
+    }
+
+    /**
+        Remembers host of the client (or refreshes its expiry)
+    */
+    pub fn insert(&self, pool: Option<&str>, ip: IpAddr, host: Endpoint) {
+        let now = Instant::now();
+        let key = (pool.map(|p| p.to_string()), self.get_key(ip));
+
+        let mut entries = self.entries.write().unwrap();
+        if now >= entries.next_cleanup {
+            entries.hosts.retain(|_, (_, expires)| now < *expires);
+            entries.next_cleanup = now + CLEANUP_INTERVAL;
+        }
+
+        entries.hosts.insert(key, (host, now + self.ttl));
+    }
+
+    /**
+        Clients of the same subnet share the key of its first address (/24 for IPv4 and /64 for IPv6)
+    */
+    fn get_key(&self, ip: IpAddr) -> IpAddr {
+        // clients of dual-stack listeners have IPv4-mapped addresses
+        let ip = ip.to_canonical();
+        match (self.key, ip) {
+            (StickKey::Ip, ip) => ip,
+            (StickKey::Subnet, IpAddr::V4(ip)) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & !0xff)),
+            (StickKey::Subnet, IpAddr::V6(ip)) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))),
+        }
+    }
+}
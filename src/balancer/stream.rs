@@ -0,0 +1,408 @@
+use std::io::prelude::*;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+#[cfg(target_os = "linux")]
+use std::net::IpAddr;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener as StdTcpListener;
+#[cfg(target_os = "linux")]
+use std::net::TcpStream as StdTcpStream;
+
+use log::warn;
+use mio::event::Source;
+use mio::net::TcpListener;
+use mio::net::TcpStream;
+#[cfg(unix)]
+use mio::net::UnixListener;
+#[cfg(unix)]
+use mio::net::UnixStream;
+use mio::{Interest, Registry, Token};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::Endpoint;
+
+/**
+    Non-blocking stream of a client or target connection, either TCP or Unix domain socket
+*/
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /**
+        Starts connecting to the endpoint, connection is established once the stream becomes writable
+    */
+    pub fn connect(endpoint: &Endpoint) -> Result<Stream> {
+        match endpoint {
+            Endpoint::Tcp(addr) => TcpStream::connect(*addr).map(Stream::Tcp),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(Error::new(ErrorKind::Unsupported, "Unix domain sockets are not supported")),
+        }
+    }
+
+    /**
+        Starts connecting to the TCP address from given source IP, which can be the IP of another host (e.g. the client)
+        thanks to IP_TRANSPARENT. Source IP has to be of the same version as the address
+    */
+    #[cfg(target_os = "linux")]
+    pub fn connect_transparent(addr: SocketAddr, source: IpAddr) -> Result<Stream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        socket.set_ip_transparent(true)?;
+        socket.bind(&SocketAddr::new(source, 0).into())?;
+
+        match socket.connect(&addr.into()) {
+            Ok(_) => {}
+            Err(ref e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) => return Err(e),
+        }
+
+        let stream: StdTcpStream = socket.into();
+        Ok(Stream::Tcp(TcpStream::from_std(stream)))
+    }
+
+    /**
+        Returns an error if sockets can't use IP_TRANSPARENT (it needs CAP_NET_ADMIN)
+    */
+    #[cfg(target_os = "linux")]
+    pub fn check_transparent() -> Result<()> {
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_ip_transparent(true)
+    }
+
+    /**
+        Returns [true] once the connection was established, [false] while it is still connecting
+    */
+    pub fn is_connected(&self) -> Result<bool> {
+        match self {
+            Stream::Tcp(stream) => {
+                let mut buf: [u8; 1] = [0; 1];
+                match stream.peek(&mut buf) {
+                    Ok(_) => Ok(true),
+                    Err(ref e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(true),
+                    Err(e) => Err(e),
+                }
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                if let Some(e) = stream.take_error()? {
+                    return Err(e);
+                }
+
+                match stream.peer_addr() {
+                    Ok(_) => Ok(true),
+                    Err(ref e) if e.kind() == ErrorKind::NotConnected => Ok(false),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    /**
+        Returns [true] if established connection has nothing to read and was not closed by the peer, so it can be
+        used by another client. Only TCP connections are reused
+    */
+    pub fn is_idle(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => {
+                let mut buf: [u8; 1] = [0; 1];
+                matches!(stream.peek(&mut buf), Err(ref e) if e.kind() == ErrorKind::WouldBlock)
+            }
+            #[cfg(unix)]
+            Stream::Unix(_) => false,
+        }
+    }
+
+    /**
+        Returns the TCP address of the peer, Unix domain socket peers don't have one
+    */
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr(),
+            #[cfg(unix)]
+            Stream::Unix(_) => Err(Error::new(ErrorKind::Unsupported, "Unix domain socket has no TCP address")),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /**
+        Returns the TCP stream, so TCP specific socket options can be set on it
+    */
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Stream::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.register(registry, token, interests),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.deregister(registry),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+/**
+    Options applied to TCP listening sockets before they are bound
+*/
+#[derive(Clone, Copy)]
+pub struct ListenerOptions {
+    /**
+        Sets SO_REUSEADDR, so the address can be bound again while connections of a previous run linger in TIME_WAIT.
+        Ignored on Windows, where it would allow other processes to take over the address
+    */
+    pub reuse_address: bool,
+    /**
+        Sets SO_REUSEPORT, so multiple processes can listen on the same address (only supported on some Unix platforms)
+    */
+    pub reuse_port: bool,
+    /**
+        Maximum number of connections waiting to be accepted, the system may limit it further
+    */
+    pub backlog: i32,
+    /**
+        Number of threads accepting clients on TCP addresses. With more than one, every thread binds the addresses itself
+        with SO_REUSEPORT and the kernel spreads new connections between their sockets (only supported on Linux)
+    */
+    pub acceptors: u16,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        ListenerOptions {
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 1024,
+            acceptors: 1,
+        }
+    }
+}
+
+impl ListenerOptions {
+    /**
+        Creates a non-blocking TCP listener bound to the address. Requested reuse options that are not supported
+        on this platform are only logged, binding continues without them
+    */
+    pub fn bind_tcp(&self, addr: SocketAddr) -> Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+
+        if self.reuse_address {
+            #[cfg(not(windows))]
+            if let Err(e) = socket.set_reuse_address(true) {
+                warn!("[Listener] Failed to set SO_REUSEADDR on {} -> {}", addr, e);
+            }
+            #[cfg(windows)]
+            log::debug!("[Listener] SO_REUSEADDR is not set on Windows ({})", addr);
+        }
+
+        if self.reuse_port {
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            if let Err(e) = socket.set_reuse_port(true) {
+                warn!("[Listener] Failed to set SO_REUSEPORT on {} -> {}", addr, e);
+            }
+            #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+            warn!("[Listener] SO_REUSEPORT is not supported on this platform, listening on {} without it", addr);
+        }
+
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog)?;
+
+        let listener: StdTcpListener = socket.into();
+        Ok(TcpListener::from_std(listener))
+    }
+}
+
+/**
+    Non-blocking listener accepting clients, either on a TCP address or on a Unix domain socket path
+*/
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /**
+        Binds the listener, options only apply to TCP listeners. Socket file left behind by a previous run is removed first,
+        otherwise binding the path would fail
+    */
+    pub fn bind(endpoint: &Endpoint, options: &ListenerOptions) -> Result<Listener> {
+        match endpoint {
+            Endpoint::Tcp(addr) => options.bind_tcp(*addr).map(Listener::Tcp),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // only remove sockets, so a wrong path can't delete a regular file
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(Error::new(ErrorKind::Unsupported, "Unix domain sockets are not supported")),
+        }
+    }
+
+    pub fn accept(&self) -> Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(s, _)| Stream::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.register(registry, token, interests),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.deregister(registry),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net;
+    use std::thread;
+
+    use super::*;
+    use crate::balancer::testing::{assert_echoed, echo_backend, hosts, temp_path, TestBalancer};
+    use crate::balancer::LoadBalancer;
+
+    #[test]
+    fn forwards_tcp_clients_to_unix_socket_hosts() {
+        let path = temp_path("unix-host.sock");
+        std::fs::remove_file(&path).unwrap_or(());
+        let backend = net::UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            std::io::copy(&mut reader, &mut stream).unwrap_or(0);
+        });
+
+        let host = Endpoint::Unix(path.clone());
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 2, false));
+        let client = balancer.connect();
+        assert_echoed(&client, b"hello over unix socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn accepts_unix_socket_clients() {
+        let path = temp_path("unix-listener.sock");
+        // socket left behind by a previous run doesn't prevent binding
+        std::fs::remove_file(&path).unwrap_or(());
+        drop(net::UnixListener::bind(&path).unwrap());
+        let endpoint = Endpoint::Unix(path.clone());
+        let options = ListenerOptions::default();
+        let listener = Listener::bind(&endpoint, &options).unwrap();
+
+        let host = echo_backend();
+        let balancer = TestBalancer::start(LoadBalancer::new(hosts(&[&host]), 2, false));
+
+        // connection is already waiting in the backlog once connecting returns
+        let mut client = net::UnixStream::connect(&path).unwrap();
+        balancer.balancer.add_client(listener.accept().unwrap(), None);
+
+        client.write_all(b"ping").unwrap();
+        let mut reply = [0; 4];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"ping");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::Endpoint;
+use super::Stream;
+
+// maximum number of idle connections kept for a single host, oldest connection is closed when exceeded
+const MAX_IDLE_PER_HOST: usize = 32;
+
+/**
+    Idle connections to targets kept by a worker thread, so its next clients can reuse them instead of connecting again.
+    Pooled connections are not registered with any poll
+*/
+pub struct TargetPool {
+    idle_timeout: Duration,
+    connections: HashMap<Endpoint, Vec<(Stream, Instant)>>,
+}
+
+impl TargetPool {
+    pub fn new(idle_timeout: Duration) -> Self {
+        TargetPool {
+            idle_timeout,
+            connections: HashMap::new(),
+        }
+    }
+
+    /**
+        Keeps established connection to target until it's taken by another client or it expires
+    */
+    pub fn put(&mut self, target: Endpoint, stream: Stream) {
+        let idle = self.connections.entry(target).or_default();
+        if idle.len() >= MAX_IDLE_PER_HOST {
+            idle.remove(0);
+        }
+        idle.push((stream, Instant::now()));
+    }
+
+    /**
+        Returns the most recently pooled connection to target that is still usable, [None] if there is none.
+        Connections that expired, were closed by the target or have unexpected data waiting are closed
+    */
+    pub fn take(&mut self, target: &Endpoint) -> Option<Stream> {
+        let idle = self.connections.get_mut(target)?;
+        while let Some((stream, pooled)) = idle.pop() {
+            if pooled.elapsed() <= self.idle_timeout && stream.is_idle() {
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.values().all(|idle| idle.is_empty())
+    }
+
+    /**
+        Closes connections that were idle for longer than the idle timeout
+    */
+    pub fn remove_expired(&mut self) {
+        let idle_timeout = self.idle_timeout;
+        for idle in self.connections.values_mut() {
+            idle.retain(|(_, pooled)| pooled.elapsed() <= idle_timeout);
+        }
+        self.connections.retain(|_, idle| !idle.is_empty());
+    }
+
+    /**
+        Closes all idle connections to target (e.g. when it was removed)
+    */
+    pub fn remove_host(&mut self, target: &Endpoint) {
+        self.connections.remove(target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread::sleep;
+
+    use super::*;
+
+    /**
+        Returns connected pair of the pooled (non-blocking) stream and the target side of it
+    */
+    fn connect(listener: &TcpListener) -> (Stream, TcpStream) {
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (target, _) = listener.accept().unwrap();
+        (Stream::Tcp(mio::net::TcpStream::from_std(stream)), target)
+    }
+
+    fn target(listener: &TcpListener) -> Endpoint {
+        Endpoint::Tcp(listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn reuses_pooled_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = TargetPool::new(Duration::from_secs(10));
+        let (stream, _target) = connect(&listener);
+        let local = stream.local_addr().unwrap();
+
+        assert!(pool.take(&target(&listener)).is_none());
+        pool.put(target(&listener), stream);
+        assert!(!pool.is_empty());
+
+        let reused = pool.take(&target(&listener)).expect("pooled connection was not reused");
+        assert_eq!(reused.local_addr().unwrap(), local);
+        assert!(pool.take(&target(&listener)).is_none());
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn expires_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = TargetPool::new(Duration::from_millis(50));
+        let (first, _first_target) = connect(&listener);
+        let (second, _second_target) = connect(&listener);
+
+        pool.put(target(&listener), first);
+        sleep(Duration::from_millis(80));
+        assert!(pool.take(&target(&listener)).is_none());
+
+        pool.put(target(&listener), second);
+        sleep(Duration::from_millis(80));
+        pool.remove_expired();
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn drops_dead_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = TargetPool::new(Duration::from_secs(10));
+        let (idle, _idle_target) = connect(&listener);
+        let (closed, closed_target) = connect(&listener);
+        let (talking, mut talking_target) = connect(&listener);
+        let idle_local = idle.local_addr().unwrap();
+
+        // most recently pooled connections are taken first, unusable ones are closed on the way
+        pool.put(target(&listener), idle);
+        pool.put(target(&listener), closed);
+        pool.put(target(&listener), talking);
+        drop(closed_target);
+        talking_target.write_all(b"unexpected").unwrap();
+        sleep(Duration::from_millis(50));
+
+        let taken = pool.take(&target(&listener)).expect("idle connection was not reused");
+        assert_eq!(taken.local_addr().unwrap(), idle_local);
+        assert!(pool.take(&target(&listener)).is_none());
+    }
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use socket2::Domain;
+use socket2::Socket;
+use socket2::Type;
+
+use super::Endpoint;
+use super::HostManager;
+use super::LoadBalancer;
+use super::Stream;
+
+/**
+    Balancer started for a test. Clients connecting to [addr] are accepted by the test itself and handed over to the
+    balancer the same way the [Poller] does it, so tests know exactly which clients the balancer got. Stopped once dropped
+*/
+pub struct TestBalancer {
+    pub balancer: LoadBalancer,
+    pub addr: SocketAddr,
+    listener: TcpListener,
+}
+
+impl TestBalancer {
+    pub fn start(balancer: LoadBalancer) -> Self {
+        TestBalancer::start_on(balancer, "127.0.0.1:0")
+    }
+
+    pub fn start_on(balancer: LoadBalancer, addr: &str) -> Self {
+        let mut test = TestBalancer::stopped_on(balancer, addr);
+        test.balancer.start();
+        test
+    }
+
+    /**
+        Balancer is not started, so its threads don't pick up connected clients until it's started by the test
+    */
+    pub fn stopped(balancer: LoadBalancer) -> Self {
+        TestBalancer::stopped_on(balancer, "127.0.0.1:0")
+    }
+
+    fn stopped_on(balancer: LoadBalancer, addr: &str) -> Self {
+        let listener = TcpListener::bind(addr).unwrap();
+        TestBalancer {
+            balancer,
+            addr: listener.local_addr().unwrap(),
+            listener,
+        }
+    }
+
+    /**
+        Connects a new client and hands it over to the balancer
+    */
+    pub fn connect(&self) -> TcpStream {
+        let client = TcpStream::connect(self.addr).unwrap();
+        self.hand_over();
+        client
+    }
+
+    /**
+        Connects a new client from given loopback address (e.g. 127.0.0.2) and hands it over to the balancer
+    */
+    pub fn connect_from(&self, source: IpAddr) -> TcpStream {
+        let socket = Socket::new(Domain::for_address(self.addr), Type::STREAM, None).unwrap();
+        socket.bind(&SocketAddr::new(source, 0).into()).unwrap();
+        socket.connect(&self.addr.into()).unwrap();
+        self.hand_over();
+        socket.into()
+    }
+
+    fn hand_over(&self) {
+        let (stream, _) = self.listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        self.balancer.add_client(Stream::Tcp(mio::net::TcpStream::from_std(stream)), None);
+    }
+
+    pub fn wait_for_no_clients(&self) {
+        wait_for("clients to be closed", || {
+            self.balancer.total_connections() == 0 && self.balancer.stats().threads.iter().all(|t| t.pending == 0)
+        });
+    }
+}
+
+impl Drop for TestBalancer {
+    fn drop(&mut self) {
+        self.balancer.stop();
+    }
+}
+
+/**
+    Returns backend listening on a free loopback port, together with its host address
+*/
+pub fn backend() -> (TcpListener, Endpoint) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = Endpoint::Tcp(listener.local_addr().unwrap());
+    (listener, host)
+}
+
+/**
+    Returns addresses of given number of hosts (127.0.0.1 from port 5000 on), for algorithms that never connect to them
+*/
+pub fn endpoints(count: u16) -> Vec<Endpoint> {
+    (0..count).map(|i| Endpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], 5000 + i)))).collect()
+}
+
+/**
+    Returns address of a client, for algorithms that never connect to it
+*/
+pub fn client_addr() -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 1], 40000))
+}
+
+pub fn hosts(hosts: &[&Endpoint]) -> HostManager {
+    HostManager::from_hosts(hosts.iter().map(|h| (*h).clone()).collect())
+}
+
+/**
+    Waits until condition holds, panics if it doesn't within a few seconds
+*/
+pub fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(started.elapsed() < Duration::from_secs(5), "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/**
+    Starts backend on a free loopback port that sends everything back to every connection, returns its host address
+*/
+pub fn echo_backend() -> Endpoint {
+    let (listener, host) = backend();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                io::copy(&mut reader, &mut stream).unwrap_or(0);
+            });
+        }
+    });
+    host
+}
+
+/**
+    Sends message through the connection and checks it's sent back
+*/
+pub fn assert_echoed(mut stream: &TcpStream, message: &[u8]) {
+    stream.write_all(message).unwrap();
+    let mut reply = vec![0; message.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, message);
+}
+
+/**
+    Checks that the balancer closed the connection (or reset it)
+*/
+pub fn assert_closed(mut stream: &TcpStream) {
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let result = stream.read(&mut [0; 16]);
+    assert!(matches!(result, Ok(0) | Err(_)), "connection was not closed");
+}
+
+/**
+    Writes file with given contents to the temporary directory, named after the test so tests don't share files
+*/
+pub fn temp_file(name: &str, contents: &str) -> String {
+    let path = temp_path(name);
+    std::fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+/**
+    Returns path in the temporary directory named after the test (e.g. for Unix domain sockets), nothing is created
+*/
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("load-balancer-rust-{}-{}", std::process::id(), name))
+}
+
+/**
+    Returns address of a loopback port nothing is listening on, so connections to it are refused
+*/
+pub fn closed_port() -> Endpoint {
+    let (_, host) = backend();
+    host
+}
@@ -0,0 +1,194 @@
+use std::io::prelude::*;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::ServerConfig;
+use rustls::ServerConnection;
+
+use super::Stream;
+
+/**
+    Loads certificate chain and private key (both in PEM format) used to terminate TLS connections from clients
+*/
+pub fn load_server_config(certfile: &str, keyfile: &str) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(certfile)
+        .and_then(|c| c.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid certificate file '{}': {}", certfile, e)))?;
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("no certificates found in '{}'", certfile)));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(keyfile).map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid key file '{}': {}", keyfile, e)))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|b| b.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(Arc::new(config))
+}
+
+/**
+    Reads and writes plaintext through the TLS session, behaving like the underlying non-blocking stream:
+    [WouldBlock] is returned whenever the session can't make progress without more socket readiness
+*/
+pub struct TlsIo<'a> {
+    pub session: &'a mut ServerConnection,
+    pub stream: &'a Stream,
+}
+
+impl TlsIo<'_> {
+    /**
+        Writes buffered TLS records (handshake messages, encrypted data, alerts) to the stream until it would block
+    */
+    pub fn flush_tls(&mut self) -> Result<()> {
+        let mut stream = self.stream;
+        while self.session.wants_write() {
+            match self.session.write_tls(&mut stream) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+        Processes TLS records that were already read from the stream by someone else (e.g. after the PROXY protocol header)
+    */
+    pub fn receive(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if self.session.read_tls(&mut data)? == 0 {
+                break;
+            }
+
+            if let Err(e) = self.session.process_new_packets() {
+                self.flush_tls().unwrap_or(());
+                return Err(Error::new(ErrorKind::InvalidData, e));
+            }
+        }
+
+        // processing records may produce handshake responses
+        self.flush_tls()
+    }
+}
+
+impl Read for TlsIo<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut stream = self.stream;
+        loop {
+            // return any plaintext that was already decrypted
+            match self.session.reader().read(buf) {
+                Ok(r) => return Ok(r),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            // otherwise read more records from the stream
+            if self.session.read_tls(&mut stream)? == 0 {
+                return Ok(0);
+            }
+
+            if let Err(e) = self.session.process_new_packets() {
+                // try to let the client know why the connection is closing
+                self.flush_tls().unwrap_or(());
+                return Err(Error::new(ErrorKind::InvalidData, e));
+            }
+
+            // processing records may produce handshake responses
+            self.flush_tls()?;
+        }
+    }
+}
+
+impl Write for TlsIo<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // don't accept more plaintext until previous records were written out
+        self.flush_tls()?;
+        if self.session.wants_write() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let written = self.session.writer().write(buf)?;
+        self.flush_tls()?;
+
+        if written == 0 && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_tls()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::net::TcpStream;
+
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use super::*;
+    use crate::balancer::testing::{echo_backend, hosts, TestBalancer};
+    use crate::balancer::{Algorithm, LoadBalancer};
+
+    const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/balancer/testdata/localhost.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/balancer/testdata/localhost.key");
+
+    /**
+        Starts TLS session with the balancer, trusting only the self-signed test certificate
+    */
+    fn tls_client(stream: TcpStream) -> StreamOwned<ClientConnection, TcpStream> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(CERT).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let session = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        StreamOwned::new(session, stream)
+    }
+
+    #[test]
+    fn loads_certificate_and_key() {
+        assert!(load_server_config(CERT, KEY).is_ok());
+        assert!(load_server_config(KEY, KEY).is_err());
+        assert!(load_server_config(CERT, CERT).is_err());
+        assert!(load_server_config("missing.pem", KEY).is_err());
+    }
+
+    #[test]
+    fn terminates_tls_of_clients() {
+        let host = echo_backend();
+        let balancer = LoadBalancer::builder().threads(2).tls_config(Some(load_server_config(CERT, KEY).unwrap()));
+        let balancer = TestBalancer::start(balancer.build(hosts(&[&host]), Algorithm::RoundRobin.factory(1)));
+
+        // handshake is done by the first write, backend gets the plaintext and echoes it back
+        let mut client = tls_client(balancer.connect());
+        client.write_all(b"hello over tls").unwrap();
+        let mut reply = [0; 14];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"hello over tls");
+
+        let payload: Vec<u8> = (0..256 * 1024).map(|i: u32| (i % 251) as u8).collect();
+        client.write_all(&payload).unwrap();
+        let mut echoed = vec![0; payload.len()];
+        client.read_exact(&mut echoed).unwrap();
+        assert!(echoed == payload, "echoed data doesn't match the payload");
+    }
+}
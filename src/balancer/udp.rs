@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use mio::net::UdpSocket;
+use mio::{Events, Interest, Poll, Token};
+
+use super::AlgorithmFactory;
+use super::BalancingAlgorithm;
+use super::Endpoint;
+use super::HostManager;
+use super::OutlierDetection;
+
+// maximum size of a UDP datagram
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+// this is used as the interval between checks for expired sessions
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+    Mapping of a single client address to the host it was balanced to
+*/
+struct Session {
+    /**
+        Socket connected to the host, replies from the host are received on it
+    */
+    socket: UdpSocket,
+    token: Token,
+    target: SocketAddr,
+    /**
+        Index of the listener the client sent its datagrams to, replies are sent from the same one
+    */
+    listener: usize,
+    last_activity: Instant,
+    /**
+        Whether host has replied yet, first reply is reported as success to the balancing algorithm
+    */
+    replied: bool,
+}
+
+/**
+    Balances UDP datagrams between hosts. Every client address is mapped to a host picked by the balancing algorithm and
+    datagrams are forwarded both ways until the mapping is idle for [session_timeout].
+
+    Datagrams are forwarded as they are, ordering and reliability are left to the application. Datagrams that can't be
+    forwarded right away (e.g. full socket buffers) are dropped. Only hosts of the default pool are used and Unix domain
+    socket hosts are skipped, as they can't receive UDP datagrams.
+*/
+pub struct UdpProxy {
+    algorithm: Box<dyn BalancingAlgorithm>,
+    hostfile: String,
+    session_timeout: Duration,
+    sessions: HashMap<SocketAddr, Session>,
+    /**
+        Client addresses of sessions, keyed by the token of their socket
+    */
+    tokens: HashMap<Token, SocketAddr>,
+    next_token: usize,
+    should_cancel: Arc<AtomicBool>,
+    should_reload: Arc<AtomicBool>,
+}
+
+impl UdpProxy {
+    pub fn new(host_manager: HostManager, factory: AlgorithmFactory, outlier_detection: OutlierDetection, hostfile: &str, session_timeout: Duration) -> Self {
+        let should_cancel = Arc::new(AtomicBool::new(false));
+        let should_reload = Arc::new(AtomicBool::new(false));
+
+        // prepare the ctrl+c handler for graceful stop (a program embedding the proxy may already have its own)
+        let cancel = Arc::clone(&should_cancel);
+        if let Err(e) = ctrlc::set_handler(move || {
+            cancel.store(true, Ordering::Relaxed);
+        }) {
+            warn!("[UDP] Failed to set Ctrl+C handler, listening can only be stopped with the stop handle! {}", e);
+        }
+
+        // prepare the SIGHUP handler for reloading the host file
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&should_reload)).expect("Failed to set SIGHUP handler!");
+
+        let draining = host_manager.get_draining_hosts();
+        let mut algorithm = factory(host_manager);
+        algorithm.set_outlier_detection(outlier_detection);
+        UdpProxy::apply_draining(&mut *algorithm, &draining);
+
+        UdpProxy {
+            algorithm,
+            hostfile: hostfile.to_string(),
+            session_timeout,
+            sessions: HashMap::new(),
+            tokens: HashMap::new(),
+            next_token: 0,
+            should_cancel,
+            should_reload,
+        }
+    }
+
+    /**
+        Returns the flag that stops listening once set to [true], the same way Ctrl+C does
+    */
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.should_cancel)
+    }
+
+    fn reload_hosts(&mut self) {
+        info!("[UDP] Reloading host file '{}'", self.hostfile);
+
+        let host_manager = HostManager::new(&self.hostfile);
+        if host_manager.hosts.is_empty() {
+            warn!("[UDP] Reloaded host file has no valid hosts, keeping the old hosts");
+            return;
+        }
+
+        // existing sessions keep their hosts until they expire (even when their host is draining now)
+        let draining = host_manager.get_draining_hosts();
+        self.algorithm.update_hosts(host_manager);
+        UdpProxy::apply_draining(&mut *self.algorithm, &draining);
+    }
+
+    fn apply_draining(algorithm: &mut dyn BalancingAlgorithm, draining: &[Endpoint]) {
+        for host in algorithm.get_hosts() {
+            algorithm.set_draining(&host, draining.contains(&host));
+        }
+    }
+
+    pub fn start_listening(&mut self, addrs: &[SocketAddr]) -> Result<()> {
+        let mut poll = Poll::new()?;
+        let mut events = Events::with_capacity(512);
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+
+        // bind all listeners, every listener is registered with token equal to its index, sessions use the tokens after them
+        let mut listeners: Vec<UdpSocket> = vec![];
+        for (i, addr) in addrs.iter().enumerate() {
+            let mut listener = UdpSocket::bind(*addr).map_err(|e| Error::new(e.kind(), format!("{} ({})", e, addr)))?;
+            poll.registry().register(&mut listener, Token(i), Interest::READABLE)?;
+            listeners.push(listener);
+        }
+        self.next_token = listeners.len();
+
+        for addr in addrs {
+            info!("[UDP] Started listening on {}", addr);
+        }
+
+        let mut next_expiry_check = Instant::now() + EXPIRY_CHECK_INTERVAL;
+        loop {
+            if self.should_cancel.load(Ordering::Relaxed) {
+                info!("[UDP] Listening stopped");
+                break;
+            }
+
+            if self.should_reload.swap(false, Ordering::Relaxed) {
+                self.reload_hosts();
+            }
+
+            if Instant::now() >= next_expiry_check {
+                next_expiry_check = Instant::now() + EXPIRY_CHECK_INTERVAL;
+                self.remove_expired(&poll);
+            }
+
+            // poll for events here (with timeout to check of [should_cancel])
+            match poll.poll(&mut events, Some(Duration::from_millis(5))) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("[UDP] Failed to poll for events! {}", e);
+                    break;
+                }
+            };
+
+            for event in events.iter() {
+                let token = event.token();
+                if token.0 < listeners.len() {
+                    self.receive_from_clients(&poll, &listeners, token.0, &mut buffer);
+                } else {
+                    self.receive_from_target(&poll, &listeners, token, &mut buffer);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+        Forwards all datagrams waiting on the listener to hosts of their clients, new clients get a host assigned
+    */
+    fn receive_from_clients(&mut self, poll: &Poll, listeners: &[UdpSocket], listener: usize, buffer: &mut [u8]) {
+        // events are edge-triggered, so we have to receive until there is nothing left
+        loop {
+            let (size, client) = match listeners[listener].recv_from(buffer) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("[UDP] Failed to receive datagram! {}", e);
+                    return;
+                }
+            };
+
+            if !self.sessions.contains_key(&client) {
+                if let Err(e) = self.create_session(poll, client, listener) {
+                    warn!("[UDP] Failed to create session for {} -> {}", client, e);
+                    continue;
+                }
+            }
+
+            let session = self.sessions.get_mut(&client).unwrap();
+            session.last_activity = Instant::now();
+            match session.socket.send(&buffer[..size]) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => debug!("[UDP] Dropped datagram from {}, socket buffer is full", client),
+                Err(e) => {
+                    // host is unreachable, client gets a new host with its next datagram
+                    warn!("[UDP] Failed to forward datagram from {} to {} -> {}", client, session.target, e);
+                    let target = Endpoint::Tcp(session.target);
+                    self.algorithm.report_error(&target);
+                    self.remove_session(poll, client);
+                }
+            }
+        }
+    }
+
+    /**
+        Forwards all datagrams waiting on the session socket back to its client
+    */
+    fn receive_from_target(&mut self, poll: &Poll, listeners: &[UdpSocket], token: Token, buffer: &mut [u8]) {
+        let client = match self.tokens.get(&token) {
+            Some(c) => *c,
+            None => return,
+        };
+
+        loop {
+            let session = match self.sessions.get_mut(&client) {
+                Some(s) => s,
+                None => return,
+            };
+
+            let size = match session.socket.recv(buffer) {
+                Ok(s) => s,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // connected UDP sockets receive errors when host is not listening (ICMP port unreachable)
+                    warn!("[UDP] Host {} of {} is unreachable -> {}", session.target, client, e);
+                    let target = Endpoint::Tcp(session.target);
+                    self.algorithm.report_error(&target);
+                    self.remove_session(poll, client);
+                    return;
+                }
+            };
+
+            session.last_activity = Instant::now();
+            if !session.replied {
+                session.replied = true;
+                self.algorithm.report_success(&Endpoint::Tcp(session.target));
+            }
+
+            match listeners[session.listener].send_to(&buffer[..size], client) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => debug!("[UDP] Dropped datagram to {}, socket buffer is full", client),
+                Err(e) => warn!("[UDP] Failed to forward datagram to {} -> {}", client, e),
+            }
+        }
+    }
+
+    fn create_session(&mut self, poll: &Poll, client: SocketAddr, listener: usize) -> Result<()> {
+        // datagrams are cheap, so the client keeps trying the least recently failed host when every host is on cooldown
+        let host = self.algorithm.get_next_host(client).or_else(|| self.algorithm.get_fallback_host());
+        let target = match host {
+            None => return Err(Error::new(ErrorKind::NotFound, "there are no hosts")),
+            Some(Endpoint::Tcp(addr)) => addr,
+            Some(unix) => {
+                // host stays on cooldown, so the next datagram picks another host
+                self.algorithm.report_error(&unix);
+                return Err(Error::new(ErrorKind::Unsupported, format!("host {} is not a UDP host", unix)));
+            }
+        };
+
+        // every session has its own socket, so replies can be told apart by the socket they arrive on
+        let local: SocketAddr = if target.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let mut socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        poll.registry().register(&mut socket, token, Interest::READABLE)?;
+
+        debug!("[UDP] Mapped {} to host {}", client, target);
+        self.algorithm.report_connected(&Endpoint::Tcp(target));
+        self.tokens.insert(token, client);
+        self.sessions.insert(
+            client,
+            Session {
+                socket,
+                token,
+                target,
+                listener,
+                last_activity: Instant::now(),
+                replied: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn remove_session(&mut self, poll: &Poll, client: SocketAddr) {
+        if let Some(mut session) = self.sessions.remove(&client) {
+            poll.registry().deregister(&mut session.socket).unwrap_or(());
+            self.tokens.remove(&session.token);
+            self.algorithm.report_disconnected(&Endpoint::Tcp(session.target));
+        }
+    }
+
+    fn remove_expired(&mut self, poll: &Poll) {
+        let timeout = self.session_timeout;
+        let expired: Vec<SocketAddr> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.last_activity.elapsed() > timeout)
+            .map(|(c, _)| *c)
+            .collect();
+
+        for client in expired {
+            debug!("[UDP] Session of {} expired", client);
+            self.remove_session(poll, client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::balancer::testing::hosts;
+    use crate::balancer::Algorithm;
+
+    /**
+        Starts backend that replies to every datagram with its name followed by the datagram
+    */
+    fn udp_backend(name: &'static str) -> Endpoint {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let host = Endpoint::Tcp(socket.local_addr().unwrap());
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while let Ok((size, from)) = socket.recv_from(&mut buffer) {
+                let reply = [name.as_bytes(), &buffer[..size]].concat();
+                socket.send_to(&reply, from).unwrap();
+            }
+        });
+        host
+    }
+
+    /**
+        Sends datagram through the proxy and returns the reply, datagrams are sent again until the proxy is listening
+    */
+    fn round_trip(client: &UdpSocket, proxy: SocketAddr, message: &[u8]) -> Vec<u8> {
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut buffer = [0; 1024];
+        for _ in 0..50 {
+            client.send_to(message, proxy).unwrap();
+            if let Ok((size, from)) = client.recv_from(&mut buffer) {
+                assert_eq!(from, proxy);
+                return buffer[..size].to_vec();
+            }
+        }
+        panic!("no reply from the proxy");
+    }
+
+    #[test]
+    fn forwards_datagrams_both_ways_and_keeps_sessions() {
+        let first = udp_backend("first:");
+        let second = udp_backend("second:");
+        let proxy_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let (handle_sender, handle) = mpsc::channel();
+        let proxy = thread::spawn(move || {
+            let factory = Algorithm::RoundRobin.factory(1);
+            let mut proxy = UdpProxy::new(hosts(&[&first, &second]), factory, OutlierDetection::default(), "", Duration::from_secs(30));
+            handle_sender.send(proxy.stop_handle()).unwrap();
+            proxy.start_listening(&[proxy_addr]).unwrap();
+        });
+        let stop = handle.recv().unwrap();
+
+        // every client keeps the host it was mapped to
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(round_trip(&a, proxy_addr, b"ping"), b"first:ping");
+        assert_eq!(round_trip(&b, proxy_addr, b"ping"), b"second:ping");
+        assert_eq!(round_trip(&a, proxy_addr, b"again"), b"first:again");
+        assert_eq!(round_trip(&b, proxy_addr, b"again"), b"second:again");
+
+        stop.store(true, Ordering::Relaxed);
+        proxy.join().unwrap();
+    }
+}
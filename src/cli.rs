@@ -0,0 +1,156 @@
+use std::str::FromStr;
+
+use log::LevelFilter;
+
+use load_balancer_rust::parse_listening_address;
+use load_balancer_rust::Algorithm;
+use load_balancer_rust::Endpoint;
+
+pub const USAGE: &str = "Usage: load-balancer-rust [OPTIONS] [ADDRESS]...
+
+Addresses are ports (e.g. 7777), addresses with port (e.g. 127.0.0.1:7777, [::]:7777) or Unix socket paths (e.g. unix:/run/lb.sock).
+Address can be followed by '@<pool>' (e.g. 443@secure), so its clients use the named pool of the host file instead of the default one
+
+Options:
+  --listen=<address>      address to listen on, can be given multiple times (same as positional addresses)
+  --algorithm=<name>      balancing algorithm (default: round_robin)
+  --threads=<n>           number of worker threads, overrides 'threads' from the config file
+  --hosts-file=<path>     host file (default: hosts), 'allow' and 'deny' files are read from its directory
+  --config=<path>         config file (default: 'config' in the directory of the host file)
+  --log-level=<level>     one of: off, error, warn, info, debug, trace (overrides RUST_LOG and 'debug' from the config file)
+  --debug                 same as --log-level=debug
+  -h, --help              print this help
+
+Options taking a value can also be given as '--option <value>'";
+
+/**
+    Options given on the command line, they take precedence over the config file
+*/
+pub struct Args {
+    /**
+        Listening addresses, together with the pool their clients use ([None] for the default pool)
+    */
+    pub addrs: Vec<(Endpoint, Option<String>)>,
+    pub algorithm: Algorithm,
+    pub hosts_file: String,
+    /**
+        Config file path, [None] if the default one next to the host file is used
+    */
+    pub config_file: Option<String>,
+    pub threads: Option<u16>,
+    /**
+        Log level picked with "--log-level" or "--debug", [None] if it depends on RUST_LOG or the config file
+    */
+    pub log_level: Option<LevelFilter>,
+    pub help: bool,
+}
+
+impl Args {
+    /**
+        Parses arguments (without the program name), error describes the first invalid argument
+    */
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args {
+            addrs: vec![],
+            algorithm: Algorithm::RoundRobin,
+            hosts_file: "hosts".to_string(),
+            config_file: None,
+            threads: None,
+            log_level: None,
+            help: false,
+        };
+        let mut debug = false;
+
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                parsed.help = true;
+                continue;
+            }
+
+            let option = match arg.strip_prefix("--") {
+                Some(o) => o,
+                None => {
+                    parsed.addrs.push(Args::parse_address(&arg)?);
+                    continue;
+                }
+            };
+
+            // flags take no value, so they are matched before the value is looked up
+            if option == "debug" {
+                debug = true;
+                continue;
+            }
+
+            // value is either part of the same argument ("--threads=4") or the next argument ("--threads 4")
+            let (name, value) = match option.split_once('=') {
+                Some((n, v)) => (n, v.to_string()),
+                None if !Args::takes_value(option) => return Err(format!("Unknown option '--{}'", option)),
+                None => match args.next() {
+                    Some(v) => (option, v),
+                    None => return Err(format!("Missing value for option '--{}'", option)),
+                },
+            };
+
+            match name {
+                "listen" => parsed.addrs.push(Args::parse_address(&value)?),
+                "algorithm" => {
+                    parsed.algorithm = value.parse().map_err(|_| {
+                        let names: Vec<String> = Algorithm::ALL.iter().map(|a| a.to_string()).collect();
+                        format!("Invalid balancing algorithm '{}'! Expected one of: {}", value, names.join(", "))
+                    })?
+                }
+                "threads" => {
+                    parsed.threads = match value.parse::<u16>() {
+                        Ok(t) if t >= 1 => Some(t),
+                        _ => return Err(format!("Invalid number of threads '{}'! Expected a number between 1 and {}", value, u16::MAX)),
+                    }
+                }
+                "hosts-file" if value.is_empty() => return Err("Host file path can't be empty".to_string()),
+                "hosts-file" => parsed.hosts_file = value,
+                "config" if value.is_empty() => return Err("Config file path can't be empty".to_string()),
+                "config" => parsed.config_file = Some(value),
+                "log-level" => {
+                    parsed.log_level = Some(
+                        LevelFilter::from_str(&value)
+                            .map_err(|_| format!("Invalid log level '{}'! Expected one of: off, error, warn, info, debug, trace", value))?,
+                    )
+                }
+                _ => return Err(format!("Unknown option '--{}'", name)),
+            }
+        }
+
+        if debug {
+            if parsed.log_level.is_some() {
+                return Err("Options '--debug' and '--log-level' can't be used together".to_string());
+            }
+            parsed.log_level = Some(LevelFilter::Debug);
+        }
+
+        if parsed.addrs.is_empty() && !parsed.help {
+            return Err("No listening address provided!".to_string());
+        }
+
+        Ok(parsed)
+    }
+
+    fn takes_value(option: &str) -> bool {
+        matches!(option, "listen" | "algorithm" | "threads" | "hosts-file" | "config" | "log-level")
+    }
+
+    fn parse_address(address: &str) -> Result<(Endpoint, Option<String>), String> {
+        let (address, pool) = match address.rsplit_once('@') {
+            Some((_, p)) if p.trim().is_empty() => return Err(format!("Missing pool name in listening address '{}'", address)),
+            Some((a, p)) => (a, Some(p.trim().to_lowercase())),
+            None => (address, None),
+        };
+
+        let endpoint = parse_listening_address(address).ok_or_else(|| {
+            format!(
+                "Invalid listening address '{}'! Expected ports (e.g. 7777), addresses with port (e.g. 127.0.0.1:7777) or Unix socket paths (e.g. unix:/run/lb.sock)",
+                address
+            )
+        })?;
+
+        Ok((endpoint, pool))
+    }
+}
@@ -0,0 +1,39 @@
+/*!
+    Load balancer for TCP (and UDP) connections, usable as a library by programs that want to embed it.
+
+    Hosts are given with a [HostManager] (parsed from a host file or built with [HostManager::from_hosts]), the [LoadBalancer]
+    is created with [LoadBalancer::builder] and balances clients accepted by the [Poller] until it's stopped
+    (see [Poller::stop_handle]). Balancing algorithms are picked with [Algorithm] or implemented with [BalancingAlgorithm]
+*/
+use std::net::SocketAddr;
+
+mod balancer;
+pub use balancer::algorithms;
+#[cfg(feature = "geoip")]
+pub use balancer::GeoIp;
+pub use balancer::{
+    load_server_config, AccessList, AccessLog, Algorithm, AlgorithmFactory, AllHostsDown, BalancerStats, BalancingAlgorithm, Config, ConnectionLimitAction,
+    Endpoint, HealthCheck, HostManager, HostRemoval, HostStats, ListenerOptions, LoadBalancer, LoadBalancerBuilder, OutlierDetection, PendingOverflow, Poller,
+    Protocol, RateLimiter, Routing, SocketOptions, StatsHandle, StickKey, ThreadStats, ThroughputStats, UdpProxy,
+};
+
+pub fn parse_listening_address(listening_address: &str) -> Option<Endpoint> {
+    // Unix domain socket path (e.g. "unix:/run/lb.sock")
+    if let Some(endpoint) = Endpoint::parse_unix(listening_address) {
+        return Some(endpoint);
+    }
+
+    // if only port is given, listen on all interfaces
+    if let Ok(port) = listening_address.parse::<u16>() {
+        if port == 0 {
+            return None;
+        }
+
+        return Some(Endpoint::Tcp(SocketAddr::from(([0, 0, 0, 0], port))));
+    }
+
+    match listening_address.parse::<SocketAddr>() {
+        Ok(a) if a.port() > 0 => Some(Endpoint::Tcp(a)),
+        _ => None,
+    }
+}
@@ -1,48 +1,130 @@
 use std::io::Result;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::process::exit;
 
-mod balancer;
-use balancer::Poller;
-use balancer::RoundRobin;
-use balancer::{HostManager, LoadBalancer};
+use log::error;
+use log::info;
+use log::LevelFilter;
+
+mod cli;
+use cli::Args;
+use load_balancer_rust::Poller;
+use load_balancer_rust::{load_server_config, AccessList, Config, HostManager, LoadBalancer, Protocol, UdpProxy};
+
 fn main() -> Result<()> {
-    // PARSE HOSTS
-    let host_manager = HostManager::new("hosts");
-    if host_manager.hosts.len() == 0 {
+    // PARSE ARGUMENTS (errors are logged once logging is set up)
+    let args = Args::parse(std::env::args().skip(1));
+    if let Ok(Args { help: true, .. }) = args {
+        println!("{}", cli::USAGE);
         return Ok(());
     }
 
-    // INITIALIZE
-    let debug_mode = true;
-    let round_robin = RoundRobin::new(host_manager);
-    let balancer = LoadBalancer::new(round_robin, 4, debug_mode);
-    let mut poller = Poller::new(balancer);
-
-    // PARSE PORT
-    let port = get_port().unwrap_or_else(|| {
-        println!("Invalid listening port provided!");
-        exit(1);
-    });
+    // LOGGING (level can be picked with --log-level or RUST_LOG, otherwise it depends on the [debug] config option).
+    // logger itself lets everything through, so the level can be raised at runtime (except above the one from RUST_LOG)
+    let cli_log_level = args.as_ref().ok().and_then(|a| a.log_level);
+    let log_level_set = cli_log_level.is_some() || std::env::var_os("RUST_LOG").is_some();
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace"));
+    if cli_log_level.is_some() {
+        logger.filter_level(LevelFilter::Trace);
+    }
+    logger.format_target(false).init();
+    if !log_level_set || cli_log_level.is_some() {
+        log::set_max_level(cli_log_level.unwrap_or(LevelFilter::Debug));
+    }
 
-    // START
-    poller.start_listening(port).unwrap_or_else(|e| {
-        println!("{}", e.to_string());
-        exit(2);
+    let args = args.unwrap_or_else(|e| {
+        error!("{}", e);
+        error!("Run with --help to see all options");
+        exit(1);
     });
 
-    Ok(())
-}
+    // PARSE HOSTS (access lists and config are read from the same directory by default)
+    let hostfile = args.hosts_file.as_str();
+    let hostdir = Path::new(hostfile).parent().unwrap_or_else(|| Path::new(""));
+    let host_manager = HostManager::new(hostfile);
+    if host_manager.hosts.is_empty() {
+        // missing or unreadable host file was already reported while parsing
+        if Path::new(hostfile).is_file() {
+            error!(
+                "[Parser] Host file '{}' has no valid hosts in the default pool! At least one is required",
+                hostfile
+            );
+        }
+        exit(4);
+    }
 
-fn get_port() -> Option<i32> {
-    let listening_port = std::env::args().nth(1)?;
-    let port: i32 = match listening_port.parse() {
-        Ok(p) => p,
-        Err(_) => return None,
+    // PARSE CONFIG
+    let configfile = match &args.config_file {
+        // default config file is optional, but an explicitly given one has to exist
+        Some(c) if !Path::new(c).is_file() => {
+            error!("[Config] Config file '{}' does not exist", c);
+            exit(1);
+        }
+        Some(c) => c.clone(),
+        None => hostdir.join("config").to_string_lossy().into_owned(),
     };
+    let mut config = Config::new(&configfile);
+    if let Some(threads) = args.threads {
+        config.threads = threads;
+    }
+    if !log_level_set && !config.debug {
+        log::set_max_level(LevelFilter::Info);
+    }
 
-    if port <= 0 || port > 65535 {
-        return None;
+    // BALANCING ALGORITHM
+    info!("Balancing algorithm: {}", args.algorithm);
+    let algorithm = args.algorithm.factory(config.virtual_nodes);
+    let addrs = args.addrs;
+
+    // UDP is balanced by its own proxy on this thread
+    if config.protocol == Protocol::Udp {
+        if addrs.iter().any(|(_, pool)| pool.is_some()) {
+            error!("[UDP] Listening addresses can't pick pools for UDP, only the default pool is used");
+            exit(1);
+        }
+
+        let addrs: Vec<SocketAddr> = addrs.iter().map(|(a, _)| a.tcp_addr()).collect::<Option<_>>().unwrap_or_else(|| {
+            error!("[UDP] Unix socket listening addresses are not supported for UDP");
+            exit(1);
+        });
+
+        let mut proxy = UdpProxy::new(host_manager, algorithm, config.outlier_detection(), hostfile, config.udp_session_timeout);
+        proxy.start_listening(&addrs).unwrap_or_else(|e| {
+            error!("[UDP] Failed to start listening -> {}", e);
+            exit(2);
+        });
+
+        return Ok(());
     }
 
-    Some(port)
+    // PARSE ACCESS LISTS
+    let allowfile = hostdir.join("allow").to_string_lossy().into_owned();
+    let denyfile = hostdir.join("deny").to_string_lossy().into_owned();
+    let access_list = AccessList::new(&allowfile, &denyfile);
+
+    // LOAD TLS CERTIFICATE
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(load_server_config(cert, key).unwrap_or_else(|e| {
+            error!("[Config] Failed to load TLS certificate -> {}", e);
+            exit(3);
+        })),
+        (None, None) => None,
+        _ => {
+            error!("[Config] Both 'tls_cert' and 'tls_key' are required to enable TLS");
+            exit(3);
+        }
+    };
+
+    // INITIALIZE
+    let balancer = LoadBalancer::with_config(host_manager, algorithm, access_list, tls_config, &config);
+    let mut poller = Poller::new(balancer, hostfile, config.admin_port, config.listener_options(), config.rate_limiter());
+
+    // START
+    poller.start_listening(&addrs).unwrap_or_else(|e| {
+        error!("[Listener] Failed to start listening -> {}", e);
+        exit(2);
+    });
+
+    Ok(())
 }